@@ -0,0 +1,204 @@
+//! Minimal bolt11 encoder for holdinvoices where we only know the
+//! payment_hash. CLN can't create those through `invoice`, so we encode
+//! the invoice ourselves with a dummy signature and let `signinvoice`
+//! put the real signature on it.
+use anyhow::{anyhow, Result};
+use bitcoin::{
+    bech32::{primitives::iter::ByteIterExt, Bech32, Fe32, Fe32IterExt, Hrp},
+    hashes::{sha256::Hash as Sha256, Hash},
+};
+
+const TAG_PAYMENT_HASH: u8 = 1;
+const TAG_FEATURES: u8 = 5;
+const TAG_EXPIRY: u8 = 6;
+const TAG_DESCRIPTION: u8 = 13;
+const TAG_PAYMENT_SECRET: u8 = 16;
+const TAG_DESCRIPTION_HASH: u8 = 23;
+const TAG_MIN_FINAL_CLTV_EXPIRY: u8 = 24;
+
+/// var_onion_optin (8), payment_secret (14) and basic_mpp (17),
+/// same as what CLN puts into its own invoices
+const INVOICE_FEATURES: u64 = (1 << 8) | (1 << 14) | (1 << 17);
+const SIGNATURE_FES: usize = 104;
+const MAX_FIELD_FES: usize = 1023;
+
+pub struct UnsignedBolt11<'a> {
+    pub network: &'a str,
+    pub amount_msat: u64,
+    pub timestamp: u64,
+    pub payment_hash: &'a [u8; 32],
+    pub payment_secret: &'a [u8; 32],
+    pub description: &'a str,
+    pub deschashonly: bool,
+    pub expiry: u64,
+    pub cltv: u32,
+}
+
+impl UnsignedBolt11<'_> {
+    /// Encode the invoice with an all-zero signature
+    pub fn encode(&self) -> Result<String> {
+        let hrp = Hrp::parse(&format!(
+            "ln{}{}",
+            network_prefix(self.network)?,
+            encode_amount(self.amount_msat)
+        ))?;
+
+        let mut data = int_to_fes(self.timestamp, 7);
+        push_field(&mut data, TAG_PAYMENT_HASH, bytes_to_fes(self.payment_hash))?;
+        push_field(
+            &mut data,
+            TAG_PAYMENT_SECRET,
+            bytes_to_fes(self.payment_secret),
+        )?;
+        if self.deschashonly {
+            let desc_hash = Sha256::hash(self.description.as_bytes());
+            push_field(
+                &mut data,
+                TAG_DESCRIPTION_HASH,
+                bytes_to_fes(desc_hash.as_byte_array()),
+            )?;
+        } else {
+            push_field(
+                &mut data,
+                TAG_DESCRIPTION,
+                bytes_to_fes(self.description.as_bytes()),
+            )?;
+        }
+        push_field(&mut data, TAG_EXPIRY, int_to_fes(self.expiry, 0))?;
+        push_field(
+            &mut data,
+            TAG_MIN_FINAL_CLTV_EXPIRY,
+            int_to_fes(u64::from(self.cltv), 0),
+        )?;
+        push_field(&mut data, TAG_FEATURES, int_to_fes(INVOICE_FEATURES, 0))?;
+        data.extend(std::iter::repeat_n(Fe32::Q, SIGNATURE_FES));
+
+        Ok(data
+            .into_iter()
+            .with_checksum::<Bech32>(&hrp)
+            .chars()
+            .collect())
+    }
+}
+
+/// Read the creation timestamp from a bolt11 string without fully decoding it.
+/// The timestamp is always the first 35 bits of the data part.
+pub fn bolt11_timestamp(bolt11: &str) -> Option<u64> {
+    let (_hrp, data) = bolt11.rsplit_once('1')?;
+    if data.len() < 7 {
+        return None;
+    }
+    let mut timestamp = 0;
+    for c in data.chars().take(7) {
+        timestamp = timestamp << 5 | u64::from(Fe32::from_char(c).ok()?.to_u8());
+    }
+    Some(timestamp)
+}
+
+/// Read the payment_hash from a bolt11 string without checking its
+/// signature, so looking up one of our invoices needs no call to cln
+pub fn bolt11_payment_hash(bolt11: &str) -> Option<String> {
+    let (_hrp, data) = bolt11.rsplit_once('1')?;
+    let fes = data
+        .chars()
+        .map(|c| Fe32::from_char(c.to_ascii_lowercase()).ok())
+        .collect::<Option<Vec<Fe32>>>()?;
+    // timestamp first, signature and checksum last
+    let mut fields = fes.get(7..fes.len().checked_sub(SIGNATURE_FES + 6)?)?;
+    while fields.len() >= 3 {
+        let len = usize::from(fields[1].to_u8()) << 5 | usize::from(fields[2].to_u8());
+        let field = fields.get(3..3 + len)?;
+        if fields[0].to_u8() == TAG_PAYMENT_HASH && len == 52 {
+            return Some(hex::encode(
+                field.iter().copied().fes_to_bytes().collect::<Vec<u8>>(),
+            ));
+        }
+        fields = &fields[3 + len..];
+    }
+    None
+}
+
+fn network_prefix(network: &str) -> Result<&'static str> {
+    match network {
+        "bitcoin" => Ok("bc"),
+        "testnet" | "testnet4" => Ok("tb"),
+        "signet" => Ok("tbs"),
+        "regtest" => Ok("bcrt"),
+        _ => Err(anyhow!("unsupported network for bolt11: {}", network)),
+    }
+}
+
+fn encode_amount(amount_msat: u64) -> String {
+    // 1 BTC = 10^11 msat, so milli=10^8, micro=10^5, nano=10^2, pico=10^-1 msat
+    if amount_msat % 100_000_000 == 0 {
+        format!("{}m", amount_msat / 100_000_000)
+    } else if amount_msat % 100_000 == 0 {
+        format!("{}u", amount_msat / 100_000)
+    } else if amount_msat % 100 == 0 {
+        format!("{}n", amount_msat / 100)
+    } else {
+        format!("{}p", amount_msat * 10)
+    }
+}
+
+fn bytes_to_fes(bytes: &[u8]) -> Vec<Fe32> {
+    bytes.iter().copied().bytes_to_fes().collect()
+}
+
+/// Big-endian 5 bit words, left-padded to at least `min_len`
+fn int_to_fes(mut value: u64, min_len: usize) -> Vec<Fe32> {
+    let mut fes = Vec::new();
+    while value > 0 || fes.len() < min_len {
+        fes.push(Fe32::try_from((value & 31) as u8).unwrap());
+        value >>= 5;
+    }
+    fes.reverse();
+    fes
+}
+
+fn push_field(data: &mut Vec<Fe32>, tag: u8, field: Vec<Fe32>) -> Result<()> {
+    if field.len() > MAX_FIELD_FES {
+        return Err(anyhow!(
+            "bolt11 field {} too long: {} > {}",
+            Fe32::try_from(tag)?.to_char(),
+            field.len(),
+            MAX_FIELD_FES
+        ));
+    }
+    data.push(Fe32::try_from(tag)?);
+    data.extend(int_to_fes(field.len() as u64, 2));
+    data.extend(field);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payment_hash_roundtrip() {
+        let payment_hash = [7u8; 32];
+        let bolt11 = UnsignedBolt11 {
+            network: "regtest",
+            amount_msat: 1_001,
+            timestamp: 1_700_000_000,
+            payment_hash: &payment_hash,
+            payment_secret: &[9u8; 32],
+            description: "payment_hash roundtrip",
+            deschashonly: false,
+            expiry: 3_600,
+            cltv: 80,
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(
+            bolt11_payment_hash(&bolt11),
+            Some(hex::encode(payment_hash))
+        );
+        assert_eq!(
+            bolt11_payment_hash(&bolt11.to_uppercase()),
+            Some(hex::encode(payment_hash))
+        );
+        assert_eq!(bolt11_payment_hash("lnbcrt1qqqq"), None);
+    }
+}
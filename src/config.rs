@@ -1,11 +1,26 @@
+use std::str::FromStr;
+
 use anyhow::{anyhow, Error};
 use cln_plugin::ConfiguredPlugin;
 
 use crate::{
-    errors::config_value_error,
-    model::PluginState,
+    errors::{config_str_value_error, config_value_error},
+    model::{ChannelHealthPolicy, ExpiryPolicy, PluginState},
+    webhook::is_valid_webhook_url,
+    OPT_AUTOCLEAN_CANCELED_AGE,
+    OPT_AUTOCLEAN_CYCLE,
+    OPT_AUTOCLEAN_SETTLED_AGE,
     OPT_CANCEL_HOLD_BEFORE_HTLC_EXPIRY_BLOCKS,
     OPT_CANCEL_HOLD_BEFORE_INVOICE_EXPIRY_SECONDS,
+    OPT_CHANNEL_HEALTH_POLICY,
+    OPT_MAX_OVERPAY_MSAT,
+    OPT_MAX_OVERPAY_PERCENT,
+    OPT_ON_EXPIRY,
+    OPT_RPC_POOL_SIZE,
+    OPT_STARTUP_TIMEOUT,
+    OPT_UNHEALTHY_CANCEL_BEFORE_HTLC_EXPIRY_BLOCKS,
+    OPT_WEBHOOK_SECRET,
+    OPT_WEBHOOK_URL,
 };
 
 pub fn verify_config_options(
@@ -42,5 +57,96 @@ pub fn verify_config_options(
             cancel_hold_before_invoice_expiry_seconds
         )));
     }
+
+    if let Some(url) = plugin.option(&OPT_WEBHOOK_URL)? {
+        if !is_valid_webhook_url(&url) {
+            return Err(anyhow!(config_str_value_error(
+                OPT_WEBHOOK_URL.name,
+                &url,
+                "must be an http or https url"
+            )));
+        }
+        if plugin.option(&OPT_WEBHOOK_SECRET)?.is_none() {
+            return Err(anyhow!(config_str_value_error(
+                OPT_WEBHOOK_URL.name,
+                &url,
+                "requires holdinvoice-webhook-secret to be set"
+            )));
+        }
+    }
+    if plugin
+        .option(&OPT_WEBHOOK_SECRET)?
+        .is_some_and(|s| s.is_empty())
+    {
+        return Err(anyhow!(config_str_value_error(
+            OPT_WEBHOOK_SECRET.name,
+            "",
+            "must not be empty"
+        )));
+    }
+
+    for opt in [OPT_MAX_OVERPAY_MSAT, OPT_MAX_OVERPAY_PERCENT] {
+        if let Some(v) = plugin.option(&opt)? {
+            if v < 0 {
+                return Err(anyhow!(config_value_error(opt.name, v)));
+            }
+        }
+    }
+
+    let autoclean_cycle = plugin.option(&OPT_AUTOCLEAN_CYCLE)?;
+    if autoclean_cycle < 0 {
+        return Err(anyhow!(config_value_error(
+            OPT_AUTOCLEAN_CYCLE.name,
+            autoclean_cycle
+        )));
+    }
+    for opt in [OPT_AUTOCLEAN_SETTLED_AGE, OPT_AUTOCLEAN_CANCELED_AGE] {
+        if let Some(v) = plugin.option(&opt)? {
+            if v < 0 {
+                return Err(anyhow!(config_value_error(opt.name, v)));
+            }
+        }
+    }
+
+    let startup_timeout = plugin.option(&OPT_STARTUP_TIMEOUT)?;
+    if startup_timeout < 0 {
+        return Err(anyhow!(config_value_error(
+            OPT_STARTUP_TIMEOUT.name,
+            startup_timeout
+        )));
+    }
+
+    let rpc_pool_size = plugin.option(&OPT_RPC_POOL_SIZE)?;
+    if !(1..=1_000).contains(&rpc_pool_size) {
+        return Err(anyhow!(config_value_error(
+            OPT_RPC_POOL_SIZE.name,
+            rpc_pool_size
+        )));
+    }
+
+    let on_expiry = plugin.option(&OPT_ON_EXPIRY)?;
+    if ExpiryPolicy::from_str(&on_expiry).is_err() {
+        return Err(anyhow!(config_str_value_error(
+            OPT_ON_EXPIRY.name,
+            &on_expiry,
+            "must be settle or cancel"
+        )));
+    }
+
+    let channel_health_policy = plugin.option(&OPT_CHANNEL_HEALTH_POLICY)?;
+    if ChannelHealthPolicy::from_str(&channel_health_policy).is_err() {
+        return Err(anyhow!(config_str_value_error(
+            OPT_CHANNEL_HEALTH_POLICY.name,
+            &channel_health_policy,
+            "must be warn, cancel or tighten"
+        )));
+    }
+    let unhealthy_cancel_blocks = plugin.option(&OPT_UNHEALTHY_CANCEL_BEFORE_HTLC_EXPIRY_BLOCKS)?;
+    if !u32::try_from(unhealthy_cancel_blocks).is_ok_and(|b| b > 0) {
+        return Err(anyhow!(config_value_error(
+            OPT_UNHEALTHY_CANCEL_BEFORE_HTLC_EXPIRY_BLOCKS.name,
+            unhealthy_cancel_blocks
+        )));
+    }
     Ok(())
 }
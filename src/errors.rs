@@ -46,6 +46,27 @@ pub fn payment_hash_missing_error(pay_hash: &str) -> serde_json::Value {
     })
 }
 
+pub fn invoice_missing_error(name: &str, value: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("{} '{}' not found", name, value)
+    })
+}
+
+pub fn not_holdinvoice_error(name: &str, value: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("{} '{}' is not a holdinvoice", name, value)
+    })
+}
+
+pub fn offer_missing_error(offer_id: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("offer_id '{}' not found", offer_id)
+    })
+}
+
 pub fn invalid_integer_error(name: &str, integer: &str) -> serde_json::Value {
     json!({
         "code": -32602,
@@ -54,6 +75,13 @@ pub fn invalid_integer_error(name: &str, integer: &str) -> serde_json::Value {
     })
 }
 
+pub fn nonpositive_amount_error(name: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("{}: should be positive msat or 'any'", name)
+    })
+}
+
 pub fn too_many_params_error(actual: usize, expected: usize) -> serde_json::Value {
     json!({
        "code": -32602,
@@ -69,6 +97,81 @@ pub fn wrong_hold_state_error(holdstate: Holdstate) -> serde_json::Value {
     })
 }
 
+pub fn wait_timeout_error(pay_hash: &str) -> serde_json::Value {
+    json!({
+        "code": 904,
+        "message": format!("Timed out while waiting for payment_hash '{}'", pay_hash)
+    })
+}
+
 pub fn config_value_error(name: &str, value: i64) -> String {
     format!("'{}' is invalid for {}", value, name)
 }
+
+pub fn config_str_value_error(name: &str, value: &str, reason: &str) -> String {
+    format!("'{}' is invalid for {}: {}", value, name, reason)
+}
+
+pub fn invalid_holdstate_error(input: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("state: should be one of OPEN, ACCEPTED, SETTLED or CANCELED: \
+        invalid token '{}'", input)
+    })
+}
+
+pub fn invalid_on_expiry_error(input: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("on_expiry: should be settle or cancel: invalid token '{}'", input)
+    })
+}
+
+pub fn invalid_metadata_error(reason: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("metadata: {}", reason)
+    })
+}
+
+pub fn hash_only_unsupported_error(param: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("{}: not supported for holdinvoices created with payment_hash", param)
+    })
+}
+
+pub fn duplicate_payment_hash_error(pay_hash: &str) -> serde_json::Value {
+    json!({
+        "code": 900,
+        "message": format!("Duplicate payment_hash '{}'", pay_hash)
+    })
+}
+
+pub fn duplicate_label_error(label: &str) -> serde_json::Value {
+    json!({
+        "code": 900,
+        "message": format!("Duplicate label '{}'", label)
+    })
+}
+
+pub fn preimage_mismatch_error(preimage: &str, pay_hash: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("preimage '{}' does not match payment_hash '{}'", preimage, pay_hash)
+    })
+}
+
+pub fn invalid_webhook_url_error(url: &str) -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": format!("webhook_url: should be an http or https url: invalid token '{}'", url)
+    })
+}
+
+pub fn webhook_secret_missing_error() -> serde_json::Value {
+    json!({
+        "code": -32602,
+        "message": "webhook_url: requires holdinvoice-webhook-secret to be set"
+    })
+}
@@ -0,0 +1,148 @@
+//! Just enough of cln's rpc socket and datastore for unit tests
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixListener,
+};
+
+use crate::{metrics::HoldMetrics, pool::RpcPool};
+
+#[derive(Debug, Default)]
+pub struct FakeDatastore {
+    pub entries: BTreeMap<Vec<String>, (String, u64)>,
+    /// `datastore` calls fail with this error code
+    pub fail_writes: Option<i64>,
+    /// connections are closed instead of answering `datastore` calls
+    pub drop_writes: bool,
+    /// `datastore` calls for keys with this payment_hash take this long
+    pub slow_writes: Option<(String, Duration)>,
+}
+
+impl FakeDatastore {
+    fn delay(&self, method: &str, params: &Value) -> Option<Duration> {
+        let (pay_hash, delay) = self.slow_writes.as_ref()?;
+        let is_slow = method == "datastore"
+            && params["key"]
+                .as_array()
+                .is_some_and(|k| k.iter().any(|p| p == pay_hash.as_str()));
+        is_slow.then_some(*delay)
+    }
+
+    fn handle(&mut self, method: &str, params: &Value) -> Option<Result<Value, Value>> {
+        let key: Vec<String> = serde_json::from_value(params["key"].clone()).unwrap_or_default();
+        Some(match method {
+            "listdatastore" => {
+                // like cln only direct children have values, deeper keys are summarized
+                let mut datastore = Vec::new();
+                let mut summarized = HashSet::new();
+                for (k, (string, generation)) in self.entries.iter() {
+                    if !k.starts_with(&key) {
+                        continue;
+                    }
+                    if k.len() <= key.len() + 1 {
+                        datastore
+                            .push(json!({"key": k, "generation": generation, "string": string}));
+                    } else if summarized.insert(k[..=key.len()].to_vec()) {
+                        datastore.push(json!({"key": k[..=key.len()]}));
+                    }
+                }
+                Ok(json!({ "datastore": datastore }))
+            }
+            "datastore" => {
+                if self.drop_writes {
+                    return None;
+                }
+                if let Some(code) = self.fail_writes {
+                    return Some(Err(json!({"code": code, "message": "injected failure"})));
+                }
+                let existing = self.entries.get(&key).map(|(_, g)| *g);
+                let generation = match (params["mode"].as_str(), existing) {
+                    (Some("must-create"), Some(_)) => {
+                        return Some(Err(json!({"code": 1202, "message": "already exists"})))
+                    }
+                    (Some("must-replace"), None) => {
+                        return Some(Err(json!({"code": 1203, "message": "does not exist"})))
+                    }
+                    (_, Some(g)) => {
+                        if params["generation"].as_u64().is_some_and(|want| want != g) {
+                            return Some(Err(json!({"code": 1204, "message": "wrong generation"})));
+                        }
+                        g + 1
+                    }
+                    (_, None) => 0,
+                };
+                let string = params["string"].as_str().unwrap_or_default().to_owned();
+                self.entries
+                    .insert(key.clone(), (string.clone(), generation));
+                Ok(json!({"key": key, "generation": generation, "string": string}))
+            }
+            "deldatastore" => match self.entries.remove(&key) {
+                Some((string, generation)) => {
+                    Ok(json!({"key": key, "generation": generation, "string": string}))
+                }
+                None => Err(json!({"code": 1200, "message": "does not exist"})),
+            },
+            _ => Err(json!({"code": -32601, "message": format!("unknown method {}", method)})),
+        })
+    }
+}
+
+/// Serves `store` on a fresh unix socket and returns a pool connected to it
+pub fn serve(store: Arc<Mutex<FakeDatastore>>) -> Arc<RpcPool> {
+    let path: PathBuf = std::env::temp_dir().join(format!(
+        "holdinvoice-test-{}.sock",
+        hex::encode(rand::random::<[u8; 8]>())
+    ));
+    let listener = UnixListener::bind(&path).unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let store = store.clone();
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                loop {
+                    match stream.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                    }
+                    // requests are separated by an empty line
+                    while let Some(pos) = buf.windows(2).position(|w| w == b"\n\n") {
+                        let request: Value = serde_json::from_slice(&buf[..pos]).unwrap();
+                        buf.drain(..pos + 2);
+                        let method = request["method"].as_str().unwrap();
+                        let delay = store.lock().delay(method, &request["params"]);
+                        if let Some(delay) = delay {
+                            tokio::time::sleep(delay).await;
+                        }
+                        let result = store.lock().handle(method, &request["params"]);
+                        let response = match result {
+                            Some(Ok(r)) => {
+                                json!({"jsonrpc": "2.0", "id": request["id"], "result": r})
+                            }
+                            Some(Err(e)) => {
+                                json!({"jsonrpc": "2.0", "id": request["id"], "error": e})
+                            }
+                            None => return,
+                        };
+                        if stream
+                            .write_all(format!("{}\n\n", response).as_bytes())
+                            .await
+                            .is_err()
+                        {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    Arc::new(RpcPool::new(path, 2, Arc::new(HoldMetrics::default())))
+}
@@ -1,33 +1,105 @@
-use std::{str::FromStr, time::Duration};
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::{anyhow, Error};
+use bitcoin::hashes::{sha256::Hash as Sha256, Hash};
 use cln_plugin::Plugin;
 use cln_rpc::{
     model::{
-        requests::{ListinvoicesRequest, ListpeerchannelsRequest},
-        responses::ListinvoicesInvoicesStatus,
+        requests::{
+            InvoiceRequest,
+            ListinvoicesIndex,
+            ListinvoicesRequest,
+            ListoffersRequest,
+            ListpeerchannelsRequest,
+            SigninvoiceRequest,
+        },
+        responses::{ListinvoicesInvoices, ListinvoicesInvoicesStatus},
     },
-    primitives::ChannelState,
-    ClnRpc,
+    primitives::{AmountOrAny, ChannelState, Secret},
 };
 use log::{debug, warn};
 use serde_json::json;
-use tokio::{time, time::Instant};
+use tokio::{sync::broadcast::error::RecvError, time, time::Instant};
 
 use crate::{
+    bolt11::{bolt11_timestamp, UnsignedBolt11},
     errors::*,
-    model::{HoldLookupResponse, HoldStateResponse, PluginState},
-    rpc::{datastore_new_state, datastore_update_state_forced, listdatastore_state},
-    util::{build_invoice_request, make_rpc_path, parse_payment_hash},
+    metrics::HoldMetrics,
+    model::{
+        HashOnlyInvoice,
+        HoldBulkResponse,
+        HoldBulkResult,
+        HoldEventInvoice,
+        HoldEventType,
+        HoldHistoryResponse,
+        HoldInitiator,
+        HoldInvoiceListEntry,
+        HoldInvoiceListFilter,
+        HoldInvoiceListResponse,
+        HoldInvoiceOptions,
+        HoldInvoiceResponse,
+        HoldLookupResponse,
+        HoldOfferResponse,
+        HoldStateReason,
+        HoldStateResponse,
+        InvoiceSelector,
+        PluginState,
+        HOLD_DEFAULT_INVOICE_EXPIRY,
+        HOLD_LIST_HASH_ONLY_START,
+        HOLD_LIST_PAGE_SIZE,
+    },
+    pool::PooledRpc,
+    rpc::{
+        datastore_append_history,
+        datastore_hold_offer,
+        datastore_invoice_options,
+        datastore_new_invoice,
+        datastore_new_label,
+        datastore_preimage,
+        listdatastore_history,
+        listdatastore_invoice,
+        listdatastore_label,
+        listdatastore_metadata,
+        listdatastore_on_expiry,
+        listinvoices_payment_hash,
+    },
+    tasks::autoclean_pass,
+    util::{
+        amount_held_msat,
+        build_invoice_request,
+        event_invoice,
+        global_on_expiry,
+        new_transition,
+        parse_bulk_args,
+        parse_invoice_selector,
+        parse_list_filter,
+        parse_max_overpay,
+        parse_metadata,
+        parse_min_amount,
+        parse_no_args,
+        parse_offer_id,
+        parse_on_expiry,
+        parse_optional_hash,
+        parse_safety_margins,
+        parse_settle_args,
+        parse_wait_args,
+        parse_webhook_url,
+        resolve_safety_margins,
+        send_state_event,
+    },
     Holdstate,
 };
 
 pub async fn hold_invoice(
     plugin: Plugin<PluginState>,
     args: serde_json::Value,
+    initiator: HoldInitiator,
 ) -> Result<serde_json::Value, Error> {
-    let rpc_path = make_rpc_path(plugin.clone());
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
+    let mut rpc = plugin.state().rpc_pool.get().await?;
 
     let valid_arg_keys = [
         "amount_msat",
@@ -39,6 +111,15 @@ pub async fn hold_invoice(
         "cltv",
         "deschashonly",
         "exposeprivatechannels",
+        "payment_hash",
+        "webhook_url",
+        "min_amount_msat",
+        "max_overpay_msat",
+        "max_overpay_percent",
+        "cancel_before_htlc_expiry",
+        "cancel_before_invoice_expiry",
+        "on_expiry",
+        "metadata",
     ];
 
     let mut new_args = serde_json::Value::Object(Default::default());
@@ -61,63 +142,417 @@ pub async fn hold_invoice(
         _ => return Ok(invalid_input_error(&args.to_string())),
     };
 
-    let inv_req = match build_invoice_request(&new_args, &plugin) {
+    let safety_margins = match parse_safety_margins(&new_args) {
+        Ok(s) => s,
+        Err(e) => return Ok(e),
+    };
+    let (cancel_before_htlc_expiry, cancel_before_invoice_expiry) =
+        resolve_safety_margins(&plugin, safety_margins)?;
+    let inv_req = match build_invoice_request(
+        &new_args,
+        cancel_before_htlc_expiry,
+        cancel_before_invoice_expiry,
+    ) {
         Ok(i) => i,
         Err(e) => return Ok(e),
     };
 
+    let webhook_url = match parse_webhook_url(&new_args, &plugin) {
+        Ok(w) => w,
+        Err(e) => return Ok(e),
+    };
+    let min_amount_msat = match parse_min_amount(&new_args, &inv_req.amount_msat) {
+        Ok(m) => m,
+        Err(e) => return Ok(e),
+    };
+    let max_overpay = match parse_max_overpay(&new_args) {
+        Ok(m) => m,
+        Err(e) => return Ok(e),
+    };
+    let on_expiry = match parse_on_expiry(&new_args, &plugin) {
+        Ok(o) => o,
+        Err(e) => return Ok(e),
+    };
+    let metadata = match parse_metadata(&new_args) {
+        Ok(m) => m,
+        Err(e) => return Ok(e),
+    };
+    let options = HoldInvoiceOptions {
+        webhook_url,
+        min_amount_msat,
+        max_overpay,
+        safety_margins,
+        on_expiry: Some(on_expiry),
+        metadata,
+    };
+
+    match parse_optional_hash(&new_args, "payment_hash") {
+        Ok(Some(pay_hash)) => {
+            return hold_invoice_hash_only(
+                &plugin, &mut rpc, inv_req, pay_hash, &options, initiator,
+            )
+            .await
+        }
+        Ok(None) => (),
+        Err(e) => return Ok(e),
+    }
+
+    // cln only knows the labels of its own invoices
+    if listdatastore_label(&mut rpc, inv_req.label.clone())
+        .await?
+        .is_some()
+    {
+        return Ok(duplicate_label_error(&inv_req.label));
+    }
     let invoice = rpc.call_typed(&inv_req).await?;
 
-    datastore_new_state(
+    plugin
+        .state()
+        .holdstates
+        .create(&mut rpc, &invoice.payment_hash.to_string(), Holdstate::Open)
+        .await?;
+    datastore_invoice_options(&mut rpc, invoice.payment_hash.to_string(), &options).await?;
+    datastore_append_history(
         &mut rpc,
         invoice.payment_hash.to_string(),
-        Holdstate::Open.to_string(),
+        &new_transition(
+            &plugin,
+            None,
+            Holdstate::Open,
+            HoldStateReason::Created,
+            initiator,
+            0,
+        ),
     )
     .await?;
+    send_state_event(
+        &plugin,
+        &mut rpc,
+        &invoice.payment_hash.to_string(),
+        Holdstate::Open,
+        HoldStateReason::Created,
+        HoldEventInvoice {
+            label: Some(inv_req.label),
+            amount_msat: match inv_req.amount_msat {
+                AmountOrAny::Amount(a) => Some(a.msat()),
+                AmountOrAny::Any => None,
+            },
+            metadata: options.metadata,
+        },
+    )
+    .await;
     Ok(json!(invoice))
 }
 
+async fn hold_invoice_hash_only(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    inv_req: InvoiceRequest,
+    pay_hash: String,
+    options: &HoldInvoiceOptions,
+    initiator: HoldInitiator,
+) -> Result<serde_json::Value, Error> {
+    if inv_req.preimage.is_some() {
+        return Ok(hash_only_unsupported_error("preimage"));
+    }
+    if inv_req.fallbacks.is_some() {
+        return Ok(hash_only_unsupported_error("fallbacks"));
+    }
+    if inv_req.exposeprivatechannels.is_some() {
+        return Ok(hash_only_unsupported_error("exposeprivatechannels"));
+    }
+    let amount_msat = match inv_req.amount_msat {
+        AmountOrAny::Amount(a) => a.msat(),
+        AmountOrAny::Any => return Ok(hash_only_unsupported_error("amount_msat")),
+    };
+
+    let existing = rpc
+        .call_typed(&ListinvoicesRequest {
+            index: None,
+            invstring: None,
+            label: None,
+            limit: None,
+            offer_id: None,
+            payment_hash: Some(pay_hash.clone()),
+            start: None,
+        })
+        .await?
+        .invoices;
+    if !existing.is_empty()
+        || plugin
+            .state()
+            .holdstates
+            .get(rpc, &pay_hash)
+            .await?
+            .is_some()
+    {
+        return Ok(duplicate_payment_hash_error(&pay_hash));
+    }
+    let existing = rpc
+        .call_typed(&ListinvoicesRequest {
+            index: None,
+            invstring: None,
+            label: Some(inv_req.label.clone()),
+            limit: None,
+            offer_id: None,
+            payment_hash: None,
+            start: None,
+        })
+        .await?
+        .invoices;
+    if !existing.is_empty()
+        || listdatastore_label(rpc, inv_req.label.clone())
+            .await?
+            .is_some()
+    {
+        return Ok(duplicate_label_error(&inv_req.label));
+    }
+
+    let payment_hash = Sha256::from_str(&pay_hash)?;
+    let payment_secret: [u8; 32] = rand::random();
+    let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let expires_at = created_at + inv_req.expiry.unwrap_or(HOLD_DEFAULT_INVOICE_EXPIRY);
+
+    let unsigned = match (UnsignedBolt11 {
+        network: &plugin.configuration().network,
+        amount_msat,
+        timestamp: created_at,
+        payment_hash: payment_hash.as_byte_array(),
+        payment_secret: &payment_secret,
+        description: &inv_req.description,
+        deschashonly: inv_req.deschashonly.unwrap_or(false),
+        expiry: expires_at - created_at,
+        cltv: inv_req.cltv.unwrap(),
+    })
+    .encode()
+    {
+        Ok(b) => b,
+        Err(e) => return Ok(invalid_input_error(&e.to_string())),
+    };
+    let bolt11 = rpc
+        .call_typed(&SigninvoiceRequest {
+            invstring: unsigned,
+        })
+        .await?
+        .bolt11;
+
+    let hash_only = HashOnlyInvoice {
+        bolt11,
+        label: inv_req.label,
+        description: inv_req.description,
+        amount_msat,
+        payment_secret: Secret::try_from(payment_secret.to_vec())?,
+        created_at,
+        expires_at,
+        cltv: inv_req.cltv.unwrap(),
+    };
+    // claims the label, another hash-only holdinvoice might have been faster
+    match datastore_new_label(rpc, hash_only.label.clone(), pay_hash.clone()).await {
+        Err(e) if e.code == Some(1202) => return Ok(duplicate_label_error(&hash_only.label)),
+        r => r?,
+    };
+    datastore_new_invoice(rpc, pay_hash.clone(), &hash_only).await?;
+    plugin
+        .state()
+        .holdstates
+        .create(rpc, &pay_hash, Holdstate::Open)
+        .await?;
+    datastore_invoice_options(rpc, pay_hash.clone(), options).await?;
+    datastore_append_history(
+        rpc,
+        pay_hash.clone(),
+        &new_transition(
+            plugin,
+            None,
+            Holdstate::Open,
+            HoldStateReason::Created,
+            initiator,
+            0,
+        ),
+    )
+    .await?;
+    send_state_event(
+        plugin,
+        rpc,
+        &pay_hash,
+        Holdstate::Open,
+        HoldStateReason::Created,
+        HoldEventInvoice {
+            label: Some(hash_only.label.clone()),
+            amount_msat: Some(amount_msat),
+            metadata: options.metadata.clone(),
+        },
+    )
+    .await;
+
+    Ok(json!(HoldInvoiceResponse {
+        bolt11: hash_only.bolt11,
+        payment_hash,
+        payment_secret: hash_only.payment_secret,
+        expires_at,
+        warning_capacity: None,
+        warning_offline: None,
+        warning_deadends: None,
+        warning_private_unused: None,
+        warning_mpp: None,
+        created_index: None,
+    }))
+}
+
+pub async fn hold_offer(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let mut rpc = plugin.state().rpc_pool.get().await?;
+
+    let offer_id = match parse_offer_id(args) {
+        Ok(o) => o,
+        Err(e) => return Ok(e),
+    };
+    let offer_hash = match Sha256::from_str(&offer_id) {
+        Ok(h) => h,
+        Err(_) => return Ok(invalid_hash_error("offer_id", &offer_id)),
+    };
+
+    let offer = match rpc
+        .call_typed(&ListoffersRequest {
+            active_only: None,
+            offer_id: Some(offer_hash),
+        })
+        .await?
+        .offers
+        .into_iter()
+        .next()
+    {
+        Some(o) => o,
+        None => return Ok(offer_missing_error(&offer_id)),
+    };
+    datastore_hold_offer(&mut rpc, offer_id, offer.bolt12.clone()).await?;
+    plugin
+        .state()
+        .hold_offers
+        .insert(offer.offer_id.to_string());
+
+    Ok(json!(HoldOfferResponse {
+        offer_id: offer.offer_id,
+        bolt12: offer.bolt12,
+    }))
+}
+
 pub async fn hold_invoice_settle(
     plugin: Plugin<PluginState>,
     args: serde_json::Value,
+    initiator: HoldInitiator,
 ) -> Result<serde_json::Value, Error> {
-    loop {
-        if *plugin.state().startup_lock.lock() {
-            time::sleep(Duration::from_secs(1)).await;
-        } else {
-            break;
-        }
-    }
-    let rpc_path = make_rpc_path(plugin.clone());
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
+    plugin.state().readiness.wait().await;
+    let mut rpc = plugin.state().rpc_pool.get().await?;
 
-    let pay_hash = match parse_payment_hash(args) {
+    let (selector, preimage) = match parse_settle_args(args) {
+        Ok(ph) => ph,
+        Err(e) => return Ok(e),
+    };
+    let pay_hash = match resolve_hold_invoice(&plugin, &mut rpc, selector).await? {
         Ok(ph) => ph,
         Err(e) => return Ok(e),
     };
 
-    let data = match listdatastore_state(&mut rpc, pay_hash.clone()).await {
-        Ok(d) => d,
-        Err(_) => return Ok(payment_hash_missing_error(&pay_hash)),
+    settle_one(&plugin, &mut rpc, pay_hash, preimage, initiator).await
+}
+
+/// Resolves `label` and `bolt11` to the payment_hash of a holdinvoice, an
+/// unknown payment_hash is reported by the caller as before
+async fn resolve_hold_invoice(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    selector: InvoiceSelector,
+) -> Result<Result<String, serde_json::Value>, Error> {
+    if let InvoiceSelector::PaymentHash(pay_hash) = selector {
+        return Ok(Ok(pay_hash));
+    }
+    let pay_hash = match listinvoices_payment_hash(rpc, &selector).await? {
+        Some(ph) => ph,
+        None => {
+            return Ok(Err(invoice_missing_error(
+                selector.name(),
+                selector.value(),
+            )))
+        }
     };
+    if plugin
+        .state()
+        .holdstates
+        .get(rpc, &pay_hash)
+        .await?
+        .is_none()
+    {
+        return Ok(Err(not_holdinvoice_error(
+            selector.name(),
+            selector.value(),
+        )));
+    }
+    Ok(Ok(pay_hash))
+}
 
-    let holdstate = Holdstate::from_str(&data.string.unwrap())?;
+async fn settle_one(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    preimage: Option<String>,
+    initiator: HoldInitiator,
+) -> Result<serde_json::Value, Error> {
+    let holdstate = match plugin.state().holdstates.get(rpc, &pay_hash).await? {
+        Some(s) => s.state,
+        None => return Ok(payment_hash_missing_error(&pay_hash)),
+    };
 
     if holdstate.is_valid_transition(&Holdstate::Settled) {
-        let result = datastore_update_state_forced(
-            &mut rpc,
-            pay_hash.clone(),
-            Holdstate::Settled.to_string(),
-        )
-        .await;
+        let is_hash_only = listdatastore_invoice(rpc, pay_hash.clone())
+            .await?
+            .is_some();
+        if let Some(preimage) = preimage {
+            if Sha256::hash(&hex::decode(&preimage)?) != Sha256::from_str(&pay_hash)? {
+                return Ok(preimage_mismatch_error(&preimage, &pay_hash));
+            }
+            if is_hash_only {
+                datastore_preimage(rpc, pay_hash.clone(), preimage).await?;
+            }
+        } else if is_hash_only {
+            return Ok(missing_parameter_error("preimage"));
+        }
+
+        let result = plugin
+            .state()
+            .holdstates
+            .update_forced(rpc, &pay_hash, Holdstate::Settled)
+            .await;
         match result {
             Ok(_r) => {
-                let mut holdinvoices = plugin.state().holdinvoices.lock().await;
-                if let Some(invoice) = holdinvoices.get_mut(&pay_hash) {
-                    for (_, htlc) in invoice.htlc_data.iter_mut() {
-                        *htlc.loop_mutex.lock().await = true;
-                    }
-                } else {
+                if holdstate != Holdstate::Settled {
+                    datastore_append_history(
+                        rpc,
+                        pay_hash.clone(),
+                        &new_transition(
+                            plugin,
+                            Some(holdstate),
+                            Holdstate::Settled,
+                            HoldStateReason::SettleRequested,
+                            initiator,
+                            amount_held_msat(plugin, &pay_hash),
+                        ),
+                    )
+                    .await?;
+                    let invoice = event_invoice(plugin, rpc, &pay_hash).await?;
+                    send_state_event(
+                        plugin,
+                        rpc,
+                        &pay_hash,
+                        Holdstate::Settled,
+                        HoldStateReason::SettleRequested,
+                        invoice,
+                    )
+                    .await;
+                }
+                if !plugin.state().holdinvoices.wake(&pay_hash) {
                     warn!(
                         "payment_hash: '{}' DROPPED INVOICE from internal state!",
                         pay_hash
@@ -133,13 +568,14 @@ pub async fn hold_invoice_settle(
                 }))
             }
             Err(e) => {
+                HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
                 debug!(
                     "Unexpected result {} to method call datastore_update_state_forced",
-                    e.to_string()
+                    e
                 );
                 Err(anyhow!(
                     "Unexpected result {} to method call datastore_update_state_forced",
-                    e.to_string()
+                    e
                 ))
             }
         }
@@ -151,120 +587,287 @@ pub async fn hold_invoice_settle(
 pub async fn hold_invoice_cancel(
     plugin: Plugin<PluginState>,
     args: serde_json::Value,
+    initiator: HoldInitiator,
 ) -> Result<serde_json::Value, Error> {
-    loop {
-        if *plugin.state().startup_lock.lock() {
-            time::sleep(Duration::from_secs(1)).await;
-        } else {
-            break;
-        }
-    }
-    let rpc_path = make_rpc_path(plugin.clone());
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
+    plugin.state().readiness.wait().await;
+    let mut rpc = plugin.state().rpc_pool.get().await?;
 
-    let pay_hash = match parse_payment_hash(args) {
+    let selector = match parse_invoice_selector(args) {
+        Ok(s) => s,
+        Err(e) => return Ok(e),
+    };
+    let pay_hash = match resolve_hold_invoice(&plugin, &mut rpc, selector).await? {
         Ok(ph) => ph,
         Err(e) => return Ok(e),
     };
 
-    let data = match listdatastore_state(&mut rpc, pay_hash.clone()).await {
-        Ok(d) => d,
-        Err(_) => return Ok(payment_hash_missing_error(&pay_hash)),
-    };
+    cancel_one(&plugin, &mut rpc, pay_hash, initiator).await
+}
 
-    let holdstate = Holdstate::from_str(&data.string.unwrap())?;
+async fn cancel_one(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    initiator: HoldInitiator,
+) -> Result<serde_json::Value, Error> {
+    let holdstate = match plugin.state().holdstates.get(rpc, &pay_hash).await? {
+        Some(s) => s.state,
+        None => return Ok(payment_hash_missing_error(&pay_hash)),
+    };
 
     if holdstate.is_valid_transition(&Holdstate::Canceled) {
-        let result = datastore_update_state_forced(
-            &mut rpc,
-            pay_hash.clone(),
-            Holdstate::Canceled.to_string(),
-        )
-        .await;
+        let result = plugin
+            .state()
+            .holdstates
+            .update_forced(rpc, &pay_hash, Holdstate::Canceled)
+            .await;
         match result {
             Ok(_r) => {
-                let mut holdinvoices = plugin.state().holdinvoices.lock().await;
-                if let Some(invoice) = holdinvoices.get_mut(&pay_hash) {
-                    for (_, htlc) in invoice.htlc_data.iter_mut() {
-                        *htlc.loop_mutex.lock().await = true;
-                    }
+                if holdstate != Holdstate::Canceled {
+                    datastore_append_history(
+                        rpc,
+                        pay_hash.clone(),
+                        &new_transition(
+                            plugin,
+                            Some(holdstate),
+                            Holdstate::Canceled,
+                            HoldStateReason::CancelRequested,
+                            initiator,
+                            amount_held_msat(plugin, &pay_hash),
+                        ),
+                    )
+                    .await?;
+                    let invoice = event_invoice(plugin, rpc, &pay_hash).await?;
+                    send_state_event(
+                        plugin,
+                        rpc,
+                        &pay_hash,
+                        Holdstate::Canceled,
+                        HoldStateReason::CancelRequested,
+                        invoice,
+                    )
+                    .await;
                 }
+                plugin.state().holdinvoices.wake(&pay_hash);
 
                 Ok(json!(HoldStateResponse {
                     state: Holdstate::Canceled,
                 }))
             }
-            Err(e) => Err(anyhow!(
-                "Unexpected result {} to method call datastore_update_state_forced",
-                e.to_string()
-            )),
+            Err(e) => {
+                HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+                Err(anyhow!(
+                    "Unexpected result {} to method call datastore_update_state_forced",
+                    e
+                ))
+            }
         }
     } else {
         Ok(wrong_hold_state_error(holdstate))
     }
 }
 
-pub async fn hold_invoice_lookup(
+pub async fn hold_invoice_settle_many(
     plugin: Plugin<PluginState>,
     args: serde_json::Value,
+    initiator: HoldInitiator,
 ) -> Result<serde_json::Value, Error> {
-    loop {
-        if *plugin.state().startup_lock.lock() {
-            time::sleep(Duration::from_secs(1)).await;
-        } else {
-            break;
+    hold_invoice_bulk(plugin, args, Holdstate::Settled, initiator).await
+}
+
+pub async fn hold_invoice_cancel_many(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+    initiator: HoldInitiator,
+) -> Result<serde_json::Value, Error> {
+    hold_invoice_bulk(plugin, args, Holdstate::Canceled, initiator).await
+}
+
+async fn hold_invoice_bulk(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+    target: Holdstate,
+    initiator: HoldInitiator,
+) -> Result<serde_json::Value, Error> {
+    plugin.state().readiness.wait().await;
+
+    let (payment_hashes, label_prefix) = match parse_bulk_args(args) {
+        Ok(a) => a,
+        Err(e) => return Ok(e),
+    };
+    let payment_hashes = if let Some(prefix) = label_prefix {
+        let list = hold_invoice_list(plugin.clone(), json!({"label_prefix": prefix})).await?;
+        if list.get("code").is_some() {
+            return Ok(list);
         }
+        serde_json::from_value::<HoldInvoiceListResponse>(list)?
+            .holdinvoices
+            .into_iter()
+            .map(|h| h.payment_hash.to_string())
+            .collect()
+    } else {
+        payment_hashes
+    };
+
+    let mut rpc = plugin.state().rpc_pool.get().await?;
+
+    let mut results = Vec::with_capacity(payment_hashes.len());
+    for pay_hash in payment_hashes {
+        let result = match target {
+            Holdstate::Settled => {
+                settle_one(&plugin, &mut rpc, pay_hash.clone(), None, initiator).await
+            }
+            _ => cancel_one(&plugin, &mut rpc, pay_hash.clone(), initiator).await,
+        };
+        results.push(match result {
+            Ok(r) => match serde_json::from_value::<HoldStateResponse>(r.clone()) {
+                Ok(hs) => HoldBulkResult {
+                    payment_hash: pay_hash,
+                    state: Some(hs.state),
+                    error: None,
+                },
+                Err(_) => HoldBulkResult {
+                    payment_hash: pay_hash,
+                    state: None,
+                    error: Some(r),
+                },
+            },
+            Err(e) => HoldBulkResult {
+                payment_hash: pay_hash,
+                state: None,
+                error: Some(json!({"code": -1, "message": e.to_string()})),
+            },
+        });
     }
-    let rpc_path = make_rpc_path(plugin.clone());
-    let mut rpc = ClnRpc::new(&rpc_path).await?;
 
-    let pay_hash = match parse_payment_hash(args) {
+    Ok(json!(HoldBulkResponse { results }))
+}
+
+pub async fn hold_invoice_lookup(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+    initiator: HoldInitiator,
+) -> Result<serde_json::Value, Error> {
+    plugin.state().readiness.wait().await;
+    let mut rpc = plugin.state().rpc_pool.get().await?;
+
+    let selector = match parse_invoice_selector(args) {
+        Ok(s) => s,
+        Err(e) => return Ok(e),
+    };
+    let pay_hash = match resolve_hold_invoice(&plugin, &mut rpc, selector).await? {
         Ok(ph) => ph,
         Err(e) => return Ok(e),
     };
 
-    let data = match listdatastore_state(&mut rpc, pay_hash.clone()).await {
-        Ok(d) => d,
-        Err(_) => return Ok(payment_hash_missing_error(&pay_hash)),
+    let holdstate = match plugin.state().holdstates.get(&mut rpc, &pay_hash).await? {
+        Some(s) => s.state,
+        None => return Ok(payment_hash_missing_error(&pay_hash)),
     };
 
-    let holdstate = Holdstate::from_str(&data.string.unwrap())?;
+    let invoice = rpc
+        .call_typed(&ListinvoicesRequest {
+            index: None,
+            invstring: None,
+            label: None,
+            limit: None,
+            offer_id: None,
+            payment_hash: Some(pay_hash.clone()),
+            start: None,
+        })
+        .await?
+        .invoices
+        .into_iter()
+        .next();
+    // only set for invoices of hold offers
+    let offer_id = invoice.as_ref().and_then(|i| i.local_offer_id);
+    let payer_note = invoice.as_ref().and_then(|i| i.invreq_payer_note.clone());
+    // holdinvoices with htlcs already have their settings in memory
+    let in_memory = plugin.state().holdinvoices.get(&pay_hash).map(|h| {
+        let h = h.lock();
+        (h.on_expiry, h.metadata.clone())
+    });
+    let (on_expiry, metadata) = match in_memory {
+        Some(m) => m,
+        None => (
+            match listdatastore_on_expiry(&mut rpc, pay_hash.clone()).await? {
+                Some(p) => p,
+                None => global_on_expiry(&plugin)?,
+            },
+            listdatastore_metadata(&mut rpc, pay_hash.clone()).await?,
+        ),
+    };
+    let last_transition = listdatastore_history(&mut rpc, pay_hash.clone())
+        .await?
+        .pop();
 
     let mut htlc_expiry = None;
+    let mut amount_accepted_msat = None;
     match holdstate {
         Holdstate::Open => {
-            let invoices = rpc
-                .call_typed(&ListinvoicesRequest {
-                    index: None,
-                    invstring: None,
-                    label: None,
-                    limit: None,
-                    offer_id: None,
-                    payment_hash: Some(pay_hash.clone()),
-                    start: None,
-                })
-                .await?
-                .invoices;
-            if let Some(inv) = invoices.first() {
-                if inv.status == ListinvoicesInvoicesStatus::EXPIRED {
-                    datastore_update_state_forced(
-                        &mut rpc,
-                        pay_hash.clone(),
-                        Holdstate::Canceled.to_string(),
-                    )
-                    .await?;
-                    return Ok(json!(HoldLookupResponse {
-                        state: Holdstate::Canceled,
-                        htlc_expiry
-                    }));
-                }
+            let (is_expired, label, amount_msat) = if let Some(inv) = &invoice {
+                (
+                    inv.status == ListinvoicesInvoicesStatus::EXPIRED,
+                    inv.label.clone(),
+                    inv.amount_msat.map(|a| a.msat()),
+                )
+            } else if let Some(hash_only) =
+                listdatastore_invoice(&mut rpc, pay_hash.clone()).await?
+            {
+                (
+                    hash_only.expires_at <= SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+                    hash_only.label,
+                    Some(hash_only.amount_msat),
+                )
             } else {
                 return Ok(payment_hash_missing_error(&pay_hash));
+            };
+            if is_expired {
+                plugin
+                    .state()
+                    .holdstates
+                    .update_forced(&mut rpc, &pay_hash, Holdstate::Canceled)
+                    .await?;
+                let transition = new_transition(
+                    &plugin,
+                    Some(Holdstate::Open),
+                    Holdstate::Canceled,
+                    HoldStateReason::Expired,
+                    initiator,
+                    0,
+                );
+                datastore_append_history(&mut rpc, pay_hash.clone(), &transition).await?;
+                send_state_event(
+                    &plugin,
+                    &mut rpc,
+                    &pay_hash,
+                    Holdstate::Canceled,
+                    HoldStateReason::Expired,
+                    HoldEventInvoice {
+                        label: Some(label),
+                        amount_msat,
+                        metadata: metadata.clone(),
+                    },
+                )
+                .await;
+                return Ok(json!(HoldLookupResponse {
+                    state: Holdstate::Canceled,
+                    htlc_expiry,
+                    amount_accepted_msat: None,
+                    offer_id,
+                    payer_note,
+                    on_expiry,
+                    metadata,
+                    htlcs: Vec::new(),
+                    last_transition: Some(transition),
+                    channel_unhealthy: false,
+                }));
             }
         }
         Holdstate::Accepted => {
-            let holdinvoices = plugin.state().holdinvoices.lock().await;
-            let next_expiry = if let Some(h) = holdinvoices.get(&pay_hash) {
+            let next_expiry = if let Some(h) = plugin.state().holdinvoices.get(&pay_hash) {
+                let h = h.lock();
+                amount_accepted_msat = Some(h.amount_held_msat());
                 h.htlc_data
                     .values()
                     .map(|htlc| htlc.cltv_expiry)
@@ -276,56 +879,39 @@ pub async fn hold_invoice_lookup(
             htlc_expiry = Some(next_expiry)
         }
         Holdstate::Canceled => {
-            let now = Instant::now();
-            loop {
-                let mut all_cancelled = true;
-                let channels = rpc
-                    .call_typed(&ListpeerchannelsRequest {
-                        id: None,
-                        short_channel_id: None,
-                    })
-                    .await?
-                    .channels;
-
-                for chan in channels {
-                    if !chan.peer_connected
-                        || chan.state != ChannelState::CHANNELD_NORMAL
-                            && chan.state != ChannelState::CHANNELD_AWAITING_SPLICE
-                    {
-                        continue;
-                    }
-
-                    let htlcs = if let Some(h) = chan.htlcs {
-                        h
-                    } else {
-                        continue;
-                    };
-                    for htlc in htlcs {
-                        if htlc
-                            .payment_hash
-                            .to_string()
-                            .eq_ignore_ascii_case(&pay_hash)
-                        {
-                            all_cancelled = false;
-                        }
-                    }
-                }
-
-                if all_cancelled {
-                    break;
-                }
-
-                if now.elapsed().as_secs() > 20 {
-                    return Err(anyhow!(
-                        "holdinvoicelookup: Timed out before cancellation of all \
-                        related htlcs was finished"
-                    ));
-                }
-
-                time::sleep(Duration::from_secs(2)).await
-            }
+            wait_for_htlcs_resolved(
+                &mut rpc,
+                &pay_hash,
+                "holdinvoicelookup: Timed out before cancellation of all \
+                related htlcs was finished",
+            )
+            .await?;
         }
         Holdstate::Settled => {
+            if listdatastore_invoice(&mut rpc, pay_hash.clone())
+                .await?
+                .is_some()
+            {
+                // cln doesn't know hash-only invoices, so they never show as paid
+                wait_for_htlcs_resolved(
+                    &mut rpc,
+                    &pay_hash,
+                    "holdinvoicelookup: Timed out before settlement could be confirmed",
+                )
+                .await?;
+                return Ok(json!(HoldLookupResponse {
+                    state: holdstate,
+                    htlc_expiry,
+                    amount_accepted_msat: None,
+                    offer_id,
+                    payer_note,
+                    on_expiry,
+                    metadata,
+                    htlcs: Vec::new(),
+                    last_transition,
+                    channel_unhealthy: false,
+                }));
+            }
             let now = Instant::now();
             loop {
                 let invoices = rpc
@@ -365,8 +951,324 @@ pub async fn hold_invoice_lookup(
             }
         }
     }
+    let htlcs = match plugin.state().holdinvoices.get(&pay_hash) {
+        Some(h) => h.lock().held_htlcs(
+            *plugin.state().blockheight.lock(),
+            &plugin.state().channel_health,
+        ),
+        None => Vec::new(),
+    };
+    let channel_unhealthy = htlcs.iter().any(|h| h.channel_issue.is_some());
     Ok(json!(HoldLookupResponse {
         state: holdstate,
-        htlc_expiry
+        htlc_expiry,
+        amount_accepted_msat,
+        offer_id,
+        payer_note,
+        on_expiry,
+        metadata,
+        htlcs,
+        last_transition,
+        channel_unhealthy,
+    }))
+}
+
+pub async fn hold_invoice_history(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let mut rpc = plugin.state().rpc_pool.get().await?;
+
+    let selector = match parse_invoice_selector(args) {
+        Ok(s) => s,
+        Err(e) => return Ok(e),
+    };
+    let pay_hash = match resolve_hold_invoice(&plugin, &mut rpc, selector).await? {
+        Ok(ph) => ph,
+        Err(e) => return Ok(e),
+    };
+    if plugin
+        .state()
+        .holdstates
+        .get(&mut rpc, &pay_hash)
+        .await?
+        .is_none()
+    {
+        return Ok(payment_hash_missing_error(&pay_hash));
+    }
+
+    Ok(json!(HoldHistoryResponse {
+        history: listdatastore_history(&mut rpc, pay_hash).await?,
+    }))
+}
+
+pub async fn hold_invoice_autoclean(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    plugin.state().readiness.wait().await;
+
+    if let Err(e) = parse_no_args(args) {
+        return Ok(e);
+    }
+
+    Ok(json!(autoclean_pass(&plugin).await?))
+}
+
+/// Answers right away, also while the plugin is not ready yet
+pub async fn hold_invoice_status(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    if let Err(e) = parse_no_args(args) {
+        return Ok(e);
+    }
+
+    Ok(json!(plugin.state().readiness.status()))
+}
+
+pub async fn hold_invoice_wait(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    plugin.state().readiness.wait().await;
+
+    let (pay_hash, states, timeout) = match parse_wait_args(args) {
+        Ok(a) => a,
+        Err(e) => return Ok(e),
+    };
+    let deadline = timeout.map(|t| Instant::now() + Duration::from_secs(t));
+
+    // subscribe before reading the current state so we can't miss a change in between
+    let mut events = plugin.state().events.subscribe();
+    let mut holdstate = {
+        let mut rpc = plugin.state().rpc_pool.get().await?;
+        match plugin.state().holdstates.get(&mut rpc, &pay_hash).await? {
+            Some(s) => s.state,
+            None => return Ok(payment_hash_missing_error(&pay_hash)),
+        }
+    };
+
+    while !states.contains(&holdstate) {
+        let event = match deadline {
+            Some(d) => match time::timeout_at(d, events.recv()).await {
+                Ok(e) => e,
+                Err(_) => return Ok(wait_timeout_error(&pay_hash)),
+            },
+            None => events.recv().await,
+        };
+        match event {
+            Ok(e) => {
+                if e.event_type == HoldEventType::StateChanged && e.payment_hash == pay_hash {
+                    holdstate = e.state;
+                }
+            }
+            Err(RecvError::Lagged(_)) => {
+                // we might have missed our change, read it again
+                let mut rpc = plugin.state().rpc_pool.get().await?;
+                holdstate = match plugin.state().holdstates.get(&mut rpc, &pay_hash).await? {
+                    Some(s) => s.state,
+                    None => return Ok(payment_hash_missing_error(&pay_hash)),
+                };
+            }
+            Err(RecvError::Closed) => return Err(anyhow!("holdinvoicewait: event bus closed")),
+        }
+    }
+
+    Ok(json!(HoldStateResponse { state: holdstate }))
+}
+
+async fn wait_for_htlcs_resolved(
+    rpc: &mut PooledRpc,
+    pay_hash: &str,
+    timeout_msg: &'static str,
+) -> Result<(), Error> {
+    let now = Instant::now();
+    loop {
+        let mut all_resolved = true;
+        let channels = rpc
+            .call_typed(&ListpeerchannelsRequest {
+                id: None,
+                short_channel_id: None,
+            })
+            .await?
+            .channels;
+
+        for chan in channels {
+            if !chan.peer_connected
+                || chan.state != ChannelState::CHANNELD_NORMAL
+                    && chan.state != ChannelState::CHANNELD_AWAITING_SPLICE
+            {
+                continue;
+            }
+
+            let htlcs = if let Some(h) = chan.htlcs {
+                h
+            } else {
+                continue;
+            };
+            for htlc in htlcs {
+                if htlc.payment_hash.to_string().eq_ignore_ascii_case(pay_hash) {
+                    all_resolved = false;
+                }
+            }
+        }
+
+        if all_resolved {
+            return Ok(());
+        }
+
+        if now.elapsed().as_secs() > 20 {
+            return Err(anyhow!(timeout_msg));
+        }
+
+        time::sleep(Duration::from_secs(2)).await
+    }
+}
+
+pub async fn hold_invoice_list(
+    plugin: Plugin<PluginState>,
+    args: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    plugin.state().readiness.wait().await;
+    let mut rpc = plugin.state().rpc_pool.get().await?;
+
+    let filter = match parse_list_filter(args) {
+        Ok(f) => f,
+        Err(e) => return Ok(e),
+    };
+
+    let hold_hashes = plugin.state().holdstates.pay_hashes(&mut rpc).await?;
+
+    let mut holdinvoices = Vec::new();
+    let mut seen_hashes = HashSet::new();
+    let mut next_start = None;
+    let requested_start = filter.start.unwrap_or(0);
+    // hash-only holdinvoices have no created_index, they come after cln's
+    // invoices and `start` counts them from HOLD_LIST_HASH_ONLY_START on
+    let mut hash_only_offset = requested_start.checked_sub(HOLD_LIST_HASH_ONLY_START);
+    let mut start = requested_start;
+    'pages: while hash_only_offset.is_none() {
+        let page_start = start;
+        let invoices = rpc
+            .call_typed(&ListinvoicesRequest {
+                index: Some(ListinvoicesIndex::CREATED),
+                invstring: None,
+                label: None,
+                limit: Some(HOLD_LIST_PAGE_SIZE),
+                offer_id: None,
+                payment_hash: None,
+                start: Some(start),
+            })
+            .await?
+            .invoices;
+        if invoices.is_empty() {
+            hash_only_offset = Some(0);
+            break;
+        }
+
+        for inv in invoices {
+            if let Some(created_index) = inv.created_index {
+                start = created_index + 1;
+            }
+            let pay_hash = inv.payment_hash.to_string();
+            if !hold_hashes.contains(&pay_hash) {
+                continue;
+            }
+            seen_hashes.insert(pay_hash);
+            let created_at = inv.bolt11.as_deref().and_then(bolt11_timestamp);
+            if let Some(entry) = list_entry(&plugin, &mut rpc, &filter, inv, created_at).await? {
+                holdinvoices.push(entry);
+                if filter.is_limit_reached(holdinvoices.len()) {
+                    next_start = Some(start);
+                    break 'pages;
+                }
+            }
+        }
+        if start == page_start {
+            hash_only_offset = Some(0);
+        }
+    }
+
+    if let Some(offset) = hash_only_offset {
+        let mut hash_only_invoices = Vec::new();
+        for pay_hash in hold_hashes.difference(&seen_hashes) {
+            if let Some(h) = listdatastore_invoice(&mut rpc, pay_hash.clone()).await? {
+                hash_only_invoices.push((pay_hash.clone(), h));
+            }
+        }
+        // a stable order, so pages don't overlap
+        hash_only_invoices.sort_by(|(hash_a, a), (hash_b, b)| {
+            (a.created_at, hash_a).cmp(&(b.created_at, hash_b))
+        });
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for (index, (pay_hash, hash_only)) in hash_only_invoices
+            .into_iter()
+            .enumerate()
+            .skip(offset as usize)
+        {
+            let inv = hash_only.to_listinvoices_invoice(Sha256::from_str(&pay_hash)?, now);
+            if let Some(entry) =
+                list_entry(&plugin, &mut rpc, &filter, inv, Some(hash_only.created_at)).await?
+            {
+                holdinvoices.push(entry);
+                if filter.is_limit_reached(holdinvoices.len()) {
+                    next_start = Some(HOLD_LIST_HASH_ONLY_START + index as u64 + 1);
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(json!(HoldInvoiceListResponse {
+        holdinvoices,
+        next_start
+    }))
+}
+
+async fn list_entry(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    filter: &HoldInvoiceListFilter,
+    inv: ListinvoicesInvoices,
+    created_at: Option<u64>,
+) -> Result<Option<HoldInvoiceListEntry>, Error> {
+    if !filter.matches_invoice(&inv.label, created_at) {
+        return Ok(None);
+    }
+
+    let pay_hash = inv.payment_hash.to_string();
+    let state = match plugin.state().holdstates.get(rpc, &pay_hash).await? {
+        Some(s) => s.state,
+        None => {
+            debug!("holdinvoicelist: skipping {}: no holdstate", pay_hash);
+            return Ok(None);
+        }
+    };
+    if filter.state.is_some_and(|s| s != state) {
+        return Ok(None);
+    }
+
+    let amount_held_msat = plugin
+        .state()
+        .holdinvoices
+        .get(&pay_hash)
+        .map(|h| h.lock().amount_held_msat())
+        .unwrap_or(0);
+
+    Ok(Some(HoldInvoiceListEntry {
+        payment_hash: inv.payment_hash,
+        label: inv.label,
+        bolt11: inv.bolt11,
+        state,
+        amount_msat: inv.amount_msat.map(|a| a.msat()),
+        amount_held_msat,
+        expires_at: inv.expires_at,
+        created_index: inv.created_index,
+        created_at,
+        offer_id: inv.local_offer_id,
+        payer_note: inv.invreq_payer_note,
+        metadata: listdatastore_metadata(rpc, pay_hash).await?,
     }))
 }
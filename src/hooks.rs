@@ -1,36 +1,76 @@
 use std::{
     collections::HashMap,
-    str::FromStr,
-    sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, Error};
 use cln_plugin::Plugin;
 use cln_rpc::{
-    model::{requests::ListinvoicesRequest, responses::ListinvoicesInvoices},
-    primitives::{Amount, ShortChannelId},
+    model::{requests::ListpeerchannelsRequest, responses::ListinvoicesInvoices},
+    primitives::{ChannelState, PublicKey, ShortChannelId},
 };
 use log::{debug, info, warn};
 use serde::Deserialize;
 use serde_json::json;
-use tokio::time::{self};
+use tokio::{
+    sync::watch,
+    time::{self},
+};
 
 use crate::{
-    model::{HoldHtlc, HoldInvoice, HtlcIdentifier, PluginState},
-    rpc::{datastore_update_state, listdatastore_state},
-    util::cleanup_pluginstate_holdinvoices,
+    metrics::HoldMetrics,
+    model::{
+        is_closing,
+        ChannelHealthPolicy,
+        ExpiryPolicy,
+        HoldEventInvoice,
+        HoldEventType,
+        HoldHtlc,
+        HoldInitiator,
+        HoldInvoice,
+        HoldStateReason,
+        HtlcIdentifier,
+        PluginState,
+        SharedHoldInvoice,
+        HOLD_LOOP_RETRY_INTERVAL,
+    },
+    pool::PooledRpc,
+    rpc::{
+        datastore_append_history,
+        datastore_htlc_set,
+        listdatastore_preimage,
+        listinvoices_hold_offer,
+    },
+    util::{
+        cleanup_pluginstate_holdinvoices,
+        global_max_overpay,
+        global_on_expiry,
+        load_holdinvoice,
+        new_transition,
+        record_transition,
+        resolve_safety_margins,
+        send_htlc_event,
+        send_state_event,
+    },
     Holdstate,
-    OPT_CANCEL_HOLD_BEFORE_HTLC_EXPIRY_BLOCKS,
-    OPT_CANCEL_HOLD_BEFORE_INVOICE_EXPIRY_SECONDS,
 };
 
 const WIRE_INCORRECT_OR_UNKNOWN_PAYMENT_DETAILS: &str = "400F";
 
 #[derive(Debug, Deserialize)]
 struct HtlcHook {
+    #[serde(default)]
+    onion: Onion,
     htlc: Htlc,
     forward_to: Option<String>,
+    #[serde(default)]
+    peer_id: Option<PublicKey>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Onion {
+    payment_secret: Option<String>,
+    total_msat: Option<u64>,
 }
 
 #[allow(dead_code)]
@@ -47,6 +87,17 @@ struct Htlc {
 pub async fn htlc_handler(
     plugin: Plugin<PluginState>,
     v: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    let result = process_htlc(plugin.clone(), v).await;
+    if result.is_err() {
+        HoldMetrics::inc(&plugin.state().metrics.rpc_errors);
+    }
+    result
+}
+
+async fn process_htlc(
+    plugin: Plugin<PluginState>,
+    v: serde_json::Value,
 ) -> Result<serde_json::Value, Error> {
     let htlc_hook = match serde_json::from_value::<HtlcHook>(v) {
         Ok(args) => args,
@@ -58,117 +109,119 @@ pub async fn htlc_handler(
     if htlc_hook.forward_to.is_some() {
         return Ok(json!({"result": "continue"}));
     }
+    let hook_timer = plugin.state().metrics.hook_timer();
 
     debug!(
         "payment_hash: `{}`. htlc_hook started!",
         htlc_hook.htlc.payment_hash
     );
-    // let rpc_path = make_rpc_path(plugin.clone());
-    // let mut rpc = ClnRpc::new(&rpc_path)
-    //     .await
-    //     .context("rpc connection full")?;
+    // htlcs replayed after a restart wait until the accepted htlc sets are restored
+    drop(plugin.state().restore_lock.read().await);
 
-    let is_new_invoice;
-
-    let invoice;
-    let global_htlc_ident;
-    let hold_state;
+    let global_htlc_ident = HtlcIdentifier {
+        htlc_id: htlc_hook.htlc.id,
+        scid: htlc_hook.htlc.short_channel_id,
+    };
+    let replayed = plugin.state().readiness.replayed(&global_htlc_ident);
 
-    {
-        let mut holdinvoices = plugin.state().holdinvoices.lock().await;
-        let generation;
-        if let Some(holdinvoice) = holdinvoices.get_mut(&htlc_hook.htlc.payment_hash) {
-            is_new_invoice = false;
+    let added = loop {
+        let new_holdinvoice = if plugin
+            .state()
+            .holdinvoices
+            .contains(&htlc_hook.htlc.payment_hash)
+        {
             debug!(
                 "payment_hash: `{}`. Htlc is for a known holdinvoice! Processing...",
                 htlc_hook.htlc.payment_hash
             );
-
-            hold_state = holdinvoice.hold_state;
-            invoice = holdinvoice.invoice.clone();
-            generation = holdinvoice.generation;
+            None
         } else {
-            is_new_invoice = true;
             debug!(
                 "payment_hash: `{}`. New htlc, checking if it's our invoice...",
                 htlc_hook.htlc.payment_hash
             );
-            let mut rpc = plugin.state().rpc.lock().await;
+            let mut rpc = plugin.state().rpc_pool.get().await?;
 
-            match listdatastore_state(&mut rpc, htlc_hook.htlc.payment_hash.clone()).await {
-                Ok(dbstate) => {
+            match plugin
+                .state()
+                .holdstates
+                .get(&mut rpc, &htlc_hook.htlc.payment_hash)
+                .await?
+            {
+                Some(state) => {
                     debug!(
                         "payment_hash: `{}`. Htlc is for a holdinvoice! Processing...",
                         htlc_hook.htlc.payment_hash
                     );
-                    hold_state = Holdstate::from_str(&dbstate.string.unwrap())?;
-                    generation = dbstate.generation.unwrap_or(0);
-
-                    invoice = rpc
-                        .call_typed(&ListinvoicesRequest {
-                            index: None,
-                            invstring: None,
-                            label: None,
-                            limit: None,
-                            offer_id: None,
-                            payment_hash: Some(htlc_hook.htlc.payment_hash.clone()),
-                            start: None,
-                        })
-                        .await?
-                        .invoices
-                        .first()
-                        .ok_or(anyhow!(
-                            "payment_hash: `{}`. holdinvoice not found!",
-                            htlc_hook.htlc.payment_hash
-                        ))?
-                        .clone();
+                    Some(
+                        load_holdinvoice(&plugin, &mut rpc, &htlc_hook.htlc.payment_hash, state)
+                            .await?,
+                    )
                 }
-                Err(_e) => {
-                    debug!(
-                        "payment_hash: `{}`. Not a holdinvoice! Continue...",
-                        htlc_hook.htlc.payment_hash
-                    );
-                    return Ok(json!({"result": "continue"}));
+                None => {
+                    match listinvoices_hold_offer(
+                        &mut rpc,
+                        &plugin.state().hold_offers,
+                        htlc_hook.htlc.payment_hash.clone(),
+                    )
+                    .await?
+                    {
+                        Some(inv) => {
+                            debug!(
+                                "payment_hash: `{}`. Htlc is for an invoice of a hold offer! \
+                            Processing...",
+                                htlc_hook.htlc.payment_hash
+                            );
+                            Some(
+                                new_hold_offer_invoice(
+                                    &plugin,
+                                    &mut rpc,
+                                    &htlc_hook.htlc.payment_hash,
+                                    inv,
+                                )
+                                .await?,
+                            )
+                        }
+                        None => {
+                            debug!(
+                                "payment_hash: `{}`. Not a holdinvoice! Continue...",
+                                htlc_hook.htlc.payment_hash
+                            );
+                            return Ok(json!({"result": "continue"}));
+                        }
+                    }
                 }
-            };
-        }
-
-        global_htlc_ident = HtlcIdentifier {
-            htlc_id: htlc_hook.htlc.id,
-            scid: htlc_hook.htlc.short_channel_id,
+            }
         };
 
-        if is_new_invoice {
-            let mut htlc_data = HashMap::new();
-            htlc_data.insert(
-                global_htlc_ident,
-                HoldHtlc {
-                    amount_msat: htlc_hook.htlc.amount_msat,
-                    cltv_expiry: htlc_hook.htlc.cltv_expiry,
-                    loop_mutex: Arc::new(tokio::sync::Mutex::new(true)),
-                },
-            );
-            holdinvoices.insert(
-                htlc_hook.htlc.payment_hash.clone(),
-                HoldInvoice {
-                    hold_state,
-                    generation,
-                    htlc_data,
-                    invoice: invoice.clone(),
-                },
-            );
-        } else {
-            let holdinvoice = holdinvoices.get_mut(&htlc_hook.htlc.payment_hash).unwrap();
-            holdinvoice.htlc_data.insert(
-                global_htlc_ident,
-                HoldHtlc {
-                    amount_msat: htlc_hook.htlc.amount_msat,
-                    cltv_expiry: htlc_hook.htlc.cltv_expiry,
-                    loop_mutex: Arc::new(tokio::sync::Mutex::new(true)),
-                },
-            );
+        match plugin.state().holdinvoices.update_or_insert(
+            &htlc_hook.htlc.payment_hash,
+            new_holdinvoice,
+            |holdinvoice| {
+                add_htlc(
+                    &plugin,
+                    &htlc_hook,
+                    global_htlc_ident,
+                    replayed,
+                    holdinvoice,
+                )
+            },
+        ) {
+            Some(added) => break added?,
+            // the last htlc of the holdinvoice was just resolved, load it again
+            None => continue,
         }
-    }
+    };
+    let (hold_state, invoice, wakeup) = match added {
+        Some(a) => a,
+        None => {
+            return Ok(json!({"result": "fail",
+            "failure_message": get_failure_message(
+                *plugin.state().blockheight.lock(),
+                htlc_hook.htlc.amount_msat)
+            }));
+        }
+    };
 
     if let Holdstate::Canceled = hold_state {
         info!(
@@ -177,13 +230,7 @@ pub async fn htlc_handler(
                         Rejecting htlc...",
             htlc_hook.htlc.payment_hash
         );
-        let mut holdinvoices = plugin.state().holdinvoices.lock().await;
-        cleanup_pluginstate_holdinvoices(
-            &mut holdinvoices,
-            &htlc_hook.htlc.payment_hash,
-            &global_htlc_ident,
-        )
-        .await;
+        cleanup_pluginstate_holdinvoices(&plugin, &htlc_hook.htlc.payment_hash, &global_htlc_ident);
 
         return Ok(json!({"result": "fail",
         "failure_message": get_failure_message(
@@ -200,6 +247,7 @@ pub async fn htlc_handler(
         global_htlc_ident.htlc_id,
         htlc_hook.htlc.amount_msat
     );
+    drop(hook_timer);
 
     return loop_htlc_hold(
         plugin.clone(),
@@ -208,34 +256,252 @@ pub async fn htlc_handler(
         invoice,
         htlc_hook.htlc.cltv_expiry,
         htlc_hook.htlc.amount_msat,
+        wakeup,
+    )
+    .await;
+}
+
+/// Invoices of hold offers are created by cln, we learn about them with their first htlc
+async fn new_hold_offer_invoice(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    payment_hash: &str,
+    invoice: ListinvoicesInvoices,
+) -> Result<HoldInvoice, Error> {
+    let state = match plugin
+        .state()
+        .holdstates
+        .create(rpc, payment_hash, Holdstate::Open)
+        .await
+    {
+        Ok(s) => s,
+        // another part of the same payment was faster
+        Err(e) if e.code == Some(1202) => {
+            let state = plugin
+                .state()
+                .holdstates
+                .get(rpc, payment_hash)
+                .await?
+                .ok_or_else(|| anyhow!("no holdstate for payment_hash: {}", payment_hash))?;
+            return load_holdinvoice(plugin, rpc, payment_hash, state).await;
+        }
+        Err(e) => {
+            HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+            return Err(e.into());
+        }
+    };
+    datastore_append_history(
+        rpc,
+        payment_hash.to_owned(),
+        &new_transition(
+            plugin,
+            None,
+            Holdstate::Open,
+            HoldStateReason::Created,
+            HoldInitiator::Htlc,
+            0,
+        ),
+    )
+    .await?;
+    send_state_event(
+        plugin,
+        rpc,
+        payment_hash,
+        Holdstate::Open,
+        HoldStateReason::Created,
+        HoldEventInvoice {
+            label: Some(invoice.label.clone()),
+            amount_msat: invoice.amount_msat.map(|a| a.msat()),
+            metadata: None,
+        },
     )
     .await;
+    let (cancel_before_htlc_expiry, cancel_before_invoice_expiry) =
+        resolve_safety_margins(plugin, None)?;
+    Ok(HoldInvoice {
+        hold_state: Holdstate::Open,
+        generation: state.generation,
+        htlc_data: HashMap::new(),
+        invoice,
+        hash_only: None,
+        min_amount_msat: None,
+        total_msat: None,
+        max_overpay: global_max_overpay(plugin)?,
+        cancel_before_htlc_expiry,
+        cancel_before_invoice_expiry,
+        on_expiry: global_on_expiry(plugin)?,
+        metadata: None,
+        wakeup: watch::channel(()).0,
+    })
+}
+
+/// Checks the htlc against its holdinvoice and adds it. Runs under the lock of
+/// the holdinvoice, so it must not await. None if the htlc must be rejected.
+fn add_htlc(
+    plugin: &Plugin<PluginState>,
+    htlc_hook: &HtlcHook,
+    global_htlc_ident: HtlcIdentifier,
+    replayed: bool,
+    holdinvoice: &mut HoldInvoice,
+) -> Result<Option<(Holdstate, ListinvoicesInvoices, watch::Receiver<()>)>, Error> {
+    // cln checks the payment_secret only for invoices it knows about
+    if let Some(h) = &holdinvoice.hash_only {
+        let secret = hex::encode(h.payment_secret.to_vec());
+        if !htlc_hook
+            .onion
+            .payment_secret
+            .as_ref()
+            .is_some_and(|s| s.eq_ignore_ascii_case(&secret))
+        {
+            info!(
+                "payment_hash: `{}` scid: `{}` htlc_id: `{}`. \
+                Wrong payment_secret for hash-only holdinvoice. Rejecting htlc...",
+                htlc_hook.htlc.payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
+            );
+            return Ok(None);
+        }
+    }
+
+    // for "any" invoices the sender tells us the full amount in the onion
+    if holdinvoice.invoice.amount_msat.is_none()
+        && holdinvoice
+            .min_amount_msat
+            .is_some_and(|min| htlc_hook.onion.total_msat.is_some_and(|t| t < min))
+    {
+        info!(
+            "payment_hash: `{}` scid: `{}` htlc_id: `{}`. \
+            total_msat below min_amount_msat. Rejecting htlc...",
+            htlc_hook.htlc.payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
+        );
+        return Ok(None);
+    }
+
+    // all parts of a payment carry the same total_msat
+    if holdinvoice.total_msat.is_none() {
+        holdinvoice.total_msat = htlc_hook.onion.total_msat;
+    }
+
+    // a replayed htlc may already be restored from the datastore, don't count it twice
+    let held_msat: u64 = holdinvoice
+        .htlc_data
+        .iter()
+        .filter(|(ident, _)| **ident != global_htlc_ident)
+        .map(|(_, htlc)| htlc.amount_msat)
+        .sum();
+    // after a restart cln replays the htlcs we were holding, those are not late
+    let replaying = replayed || !plugin.state().readiness.is_ready();
+    let hold_state = holdinvoice.hold_state;
+    let is_late = !replaying
+        && match hold_state {
+            Holdstate::Settled => true,
+            Holdstate::Accepted => held_msat >= holdinvoice.accept_threshold_msat(),
+            _ => false,
+        };
+    let is_overpaid = holdinvoice
+        .overpay_cap_msat()
+        .is_some_and(|cap| held_msat + htlc_hook.htlc.amount_msat > cap);
+    if is_late || is_overpaid {
+        info!(
+            "payment_hash: `{}` scid: `{}` htlc_id: `{}`. \
+            {}. Rejecting htlc...",
+            htlc_hook.htlc.payment_hash,
+            global_htlc_ident.scid,
+            global_htlc_ident.htlc_id,
+            if is_late {
+                format!("Htlc arrived after holdinvoice was {}", hold_state)
+            } else {
+                format!(
+                    "Holding {}msat more would exceed the overpay limit",
+                    htlc_hook.htlc.amount_msat
+                )
+            }
+        );
+        return Ok(None);
+    }
+
+    let hold_htlc = HoldHtlc {
+        amount_msat: htlc_hook.htlc.amount_msat,
+        cltv_expiry: htlc_hook.htlc.cltv_expiry,
+        arrived_at: match holdinvoice.htlc_data.get(&global_htlc_ident) {
+            Some(restored) => restored.arrived_at,
+            None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        },
+        peer_id: htlc_hook.peer_id,
+    };
+    holdinvoice
+        .htlc_data
+        .insert(global_htlc_ident, hold_htlc.clone());
+    send_htlc_event(
+        plugin,
+        &htlc_hook.htlc.payment_hash,
+        hold_state,
+        HoldEventType::HtlcAdded,
+        &global_htlc_ident,
+        &hold_htlc,
+        holdinvoice.metadata.as_ref(),
+    );
+    Ok(Some((
+        hold_state,
+        holdinvoice.invoice.clone(),
+        holdinvoice.wakeup.subscribe(),
+    )))
+}
+
+/// The holdstate already changed at this point, so a failed write is only logged
+/// Connections are only taken from the pool once the hold loop has to talk
+/// to cln, most rounds are answered from memory
+async fn hold_loop_rpc<'a>(
+    plugin: &Plugin<PluginState>,
+    pooled: &'a mut Option<PooledRpc>,
+    payment_hash: &str,
+) -> Option<&'a mut PooledRpc> {
+    if pooled.is_none() {
+        match plugin.state().rpc_pool.get().await {
+            Ok(r) => *pooled = Some(r),
+            Err(e) => {
+                HoldMetrics::inc(&plugin.state().metrics.rpc_errors);
+                warn!(
+                    "Error getting rpc connection for payment_hash: {} {}",
+                    payment_hash, e
+                );
+                return None;
+            }
+        }
+    }
+    pooled.as_mut()
+}
+
+/// Updates the in-memory holdstate and wakes the hold loops of the other htlcs
+fn set_hold_state(holdinvoice: &SharedHoldInvoice, hold_state: Holdstate) {
+    let mut holdinvoice = holdinvoice.lock();
+    holdinvoice.hold_state = hold_state;
+    holdinvoice.wakeup.send_replace(());
 }
 
 async fn loop_htlc_hold(
     plugin: Plugin<PluginState>,
-    // rpc: &mut ClnRpc,
     payment_hash: &str,
     global_htlc_ident: HtlcIdentifier,
     invoice: ListinvoicesInvoices,
     cltv_expiry: u32,
     amount_msat: u64,
+    mut wakeup: watch::Receiver<()>,
 ) -> Result<serde_json::Value, Error> {
-    let mut first_iter = true;
-    let cancel_hold_before_invoice_expiry_seconds =
-        plugin.option(&OPT_CANCEL_HOLD_BEFORE_INVOICE_EXPIRY_SECONDS)? as u64;
-    let cancel_hold_before_htlc_expiry_blocks =
-        plugin.option(&OPT_CANCEL_HOLD_BEFORE_HTLC_EXPIRY_BLOCKS)? as u32;
+    let mut wait = Duration::ZERO;
     loop {
-        if !first_iter {
-            time::sleep(Duration::from_secs(2)).await;
-        } else {
-            first_iter = false;
+        if !wait.is_zero() {
+            tokio::select! {
+                _ = wakeup.changed() => (),
+                _ = time::sleep(wait) => (),
+            }
         }
+        // wakeups from here on are for the next round
+        wakeup.borrow_and_update();
+        // errors `continue` and are retried after this
+        wait = Duration::from_secs(HOLD_LOOP_RETRY_INTERVAL);
 
-        let mut holdinvoices = plugin.state().holdinvoices.lock().await;
-        let holdinvoice_data = if let Some(hd) = holdinvoices.get_mut(payment_hash) {
-            hd
+        let holdinvoice = if let Some(h) = plugin.state().holdinvoices.get(payment_hash) {
+            h
         } else {
             warn!(
                 "payment_hash: `{}` scid: `{}` htlc: `{}`. \
@@ -247,238 +513,462 @@ async fn loop_htlc_hold(
                 payment_hash
             ));
         };
-        let mut rpc = plugin.state().rpc.lock().await;
+        let health = &plugin.state().channel_health;
+        let (
+            cancel_hold_before_invoice_expiry_seconds,
+            mut cancel_hold_before_htlc_expiry_blocks,
+            on_expiry,
+            is_hash_only,
+            htlc_unhealthy,
+            set_unhealthy,
+        ) = {
+            let h = holdinvoice.lock();
+            let peer_id = h
+                .htlc_data
+                .get(&global_htlc_ident)
+                .and_then(|htlc| htlc.peer_id);
+            (
+                h.cancel_before_invoice_expiry,
+                h.cancel_before_htlc_expiry,
+                h.on_expiry,
+                h.hash_only.is_some(),
+                health.issue(global_htlc_ident.scid, peer_id).is_some(),
+                health.affects(&h),
+            )
+        };
+        // unhealthy channels have to resolve htlcs on-chain, which takes longer
+        let tightened = htlc_unhealthy && health.policy == ChannelHealthPolicy::Tighten;
+        if tightened {
+            cancel_hold_before_htlc_expiry_blocks = cancel_hold_before_htlc_expiry_blocks
+                .max(health.unhealthy_cancel_before_htlc_expiry);
+        }
+        // only taken from the pool once this round has to talk to cln
+        let mut pooled = None;
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
             .as_secs();
 
+        let state = match plugin.state().holdstates.cached(payment_hash) {
+            Some(s) => Ok(s),
+            None => {
+                let Some(rpc) = hold_loop_rpc(&plugin, &mut pooled, payment_hash).await else {
+                    continue;
+                };
+                plugin
+                    .state()
+                    .holdstates
+                    .get(rpc, payment_hash)
+                    .await
+                    .and_then(|s| s.ok_or_else(|| anyhow!("no holdstate")))
+            }
+        };
+        let (mut hold_state, generation) = match state {
+            Ok(s) => (s.state, s.generation),
+            Err(e) => {
+                HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+                warn!(
+                    "Error getting state for payment_hash: {} {}",
+                    payment_hash, e
+                );
+                continue;
+            }
+        };
+        // htlcs can come and go while we talk to cln, the generation of the
+        // datastore makes sure only one hold loop changes the holdstate
+        let (amount_held_msat, accept_threshold_msat, event_invoice) = {
+            let mut h = holdinvoice.lock();
+            h.hold_state = hold_state;
+            h.generation = generation;
+            (
+                h.amount_held_msat(),
+                h.accept_threshold_msat(),
+                h.event_invoice(),
+            )
+        };
+
+        // cln cannot accept htlcs for expired invoices
         #[allow(clippy::clone_on_copy)]
-        if holdinvoice_data
-            .htlc_data
-            .get(&global_htlc_ident)
-            .unwrap()
-            .loop_mutex
-            .lock()
-            .await
-            .clone()
-            || invoice.expires_at <= now + cancel_hold_before_invoice_expiry_seconds
-        {
-            match listdatastore_state(&mut rpc, payment_hash.to_owned()).await {
-                Ok(s) => {
-                    holdinvoice_data.hold_state = Holdstate::from_str(&s.string.unwrap())?;
-                    holdinvoice_data.generation = s.generation.unwrap_or(0);
+        let blockheight = plugin.state().blockheight.lock().clone();
+        let soft_expired = cltv_expiry <= blockheight + cancel_hold_before_htlc_expiry_blocks
+            || invoice.expires_at <= now + cancel_hold_before_invoice_expiry_seconds;
+        let hard_expired = cltv_expiry <= blockheight || invoice.expires_at <= now;
+        // the raised margin is there to get the htlc back, not to take the
+        // payment before the holdinvoice is due
+        let settle_on_expiry = on_expiry == ExpiryPolicy::Settle && !tightened;
+        if soft_expired && hold_state == Holdstate::Accepted && settle_on_expiry && !hard_expired {
+            let Some(rpc) = hold_loop_rpc(&plugin, &mut pooled, payment_hash).await else {
+                continue;
+            };
+            match plugin
+                .state()
+                .holdstates
+                .update(rpc, payment_hash, Holdstate::Settled, generation)
+                .await
+            {
+                Ok(_o) => {
+                    info!(
+                        "payment_hash: `{}` scid: `{}` htlc: `{}`. \
+                        holdinvoice/htlc about to expire! Settling htlc...",
+                        payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
+                    );
+                    HoldMetrics::inc(&plugin.state().metrics.auto_settled);
+                    record_transition(
+                        &plugin,
+                        rpc,
+                        payment_hash,
+                        new_transition(
+                            &plugin,
+                            Some(hold_state),
+                            Holdstate::Settled,
+                            HoldStateReason::AutoSettled,
+                            HoldInitiator::AutoSoftExpiry,
+                            amount_held_msat,
+                        ),
+                    )
+                    .await;
+                    hold_state = Holdstate::Settled;
+                    send_state_event(
+                        &plugin,
+                        rpc,
+                        payment_hash,
+                        Holdstate::Settled,
+                        HoldStateReason::AutoSettled,
+                        event_invoice.clone(),
+                    )
+                    .await;
+                    set_hold_state(&holdinvoice, hold_state);
                 }
                 Err(e) => {
+                    HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
                     warn!(
-                        "Error getting state for payment_hash: {} {}",
-                        payment_hash,
-                        e.to_string()
+                        "Error updating state for payment_hash: {} {}",
+                        payment_hash, e
                     );
                     continue;
                 }
+            }
+        } else if (soft_expired
+            && (hold_state == Holdstate::Open
+                || (hold_state == Holdstate::Accepted && !settle_on_expiry)))
+            || hard_expired
+        {
+            let Some(rpc) = hold_loop_rpc(&plugin, &mut pooled, payment_hash).await else {
+                continue;
             };
-
-            // cln cannot accept htlcs for expired invoices
-            #[allow(clippy::clone_on_copy)]
-            let blockheight = plugin.state().blockheight.lock().clone();
-            let soft_expired = cltv_expiry <= blockheight + cancel_hold_before_htlc_expiry_blocks
-                || invoice.expires_at <= now + cancel_hold_before_invoice_expiry_seconds;
-            let hard_expired = cltv_expiry <= blockheight || invoice.expires_at <= now;
-            if soft_expired && holdinvoice_data.hold_state == Holdstate::Accepted && !hard_expired {
-                match datastore_update_state(
-                    &mut rpc,
-                    payment_hash.to_owned(),
-                    Holdstate::Settled.to_string(),
-                    holdinvoice_data.generation,
-                )
+            match plugin
+                .state()
+                .holdstates
+                .update(rpc, payment_hash, Holdstate::Canceled, generation)
                 .await
-                {
-                    Ok(_o) => {
-                        info!(
-                            "payment_hash: `{}` scid: `{}` htlc: `{}`. \
-                            holdinvoice/htlc about to expire! Settling htlc...",
-                            payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
-                        );
-                        holdinvoice_data.hold_state = Holdstate::Settled
-                    }
-                    Err(e) => {
-                        warn!(
-                            "Error updating state for payment_hash: {} {}",
+            {
+                Ok(_o) => {
+                    warn!(
+                        "payment_hash: `{}` scid: `{}` htlc: `{}`. \
+                        holdinvoice/htlc expired! Canceling htlc...",
+                        payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
+                    );
+                    if hold_state != Holdstate::Canceled {
+                        HoldMetrics::inc(&plugin.state().metrics.auto_canceled);
+                        record_transition(
+                            &plugin,
+                            rpc,
                             payment_hash,
-                            e.to_string()
-                        );
-                        continue;
+                            new_transition(
+                                &plugin,
+                                Some(hold_state),
+                                Holdstate::Canceled,
+                                HoldStateReason::Expired,
+                                if hard_expired {
+                                    HoldInitiator::AutoHardExpiry
+                                } else {
+                                    HoldInitiator::AutoSoftExpiry
+                                },
+                                amount_held_msat,
+                            ),
+                        )
+                        .await;
+                        send_state_event(
+                            &plugin,
+                            rpc,
+                            payment_hash,
+                            Holdstate::Canceled,
+                            HoldStateReason::Expired,
+                            event_invoice.clone(),
+                        )
+                        .await;
+                        set_hold_state(&holdinvoice, Holdstate::Canceled);
                     }
+                    hold_state = Holdstate::Canceled
                 }
-            } else if (soft_expired && holdinvoice_data.hold_state == Holdstate::Open)
-                || hard_expired
-            {
-                match datastore_update_state(
-                    &mut rpc,
-                    payment_hash.to_owned(),
-                    Holdstate::Canceled.to_string(),
-                    holdinvoice_data.generation,
-                )
+                Err(e) => {
+                    HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+                    warn!(
+                        "Error updating state for payment_hash: {} {}",
+                        payment_hash, e
+                    );
+                    continue;
+                }
+            }
+        } else if set_unhealthy
+            && hold_state == Holdstate::Open
+            && health.policy == ChannelHealthPolicy::Cancel
+        {
+            let Some(rpc) = hold_loop_rpc(&plugin, &mut pooled, payment_hash).await else {
+                continue;
+            };
+            match plugin
+                .state()
+                .holdstates
+                .update(rpc, payment_hash, Holdstate::Canceled, generation)
                 .await
-                {
-                    Ok(_o) => {
-                        warn!(
-                            "payment_hash: `{}` scid: `{}` htlc: `{}`. \
-                            holdinvoice/htlc expired! Canceling htlc...",
-                            payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
-                        );
-                        holdinvoice_data.hold_state = Holdstate::Canceled
-                    }
-                    Err(e) => {
-                        warn!(
-                            "Error updating state for payment_hash: {} {}",
-                            payment_hash,
-                            e.to_string()
-                        );
-                        continue;
-                    }
+            {
+                Ok(_o) => {
+                    warn!(
+                        "payment_hash: `{}` scid: `{}` htlc: `{}`. \
+                        htlc set is on an unhealthy channel! Canceling htlc...",
+                        payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
+                    );
+                    HoldMetrics::inc(&plugin.state().metrics.auto_canceled);
+                    record_transition(
+                        &plugin,
+                        rpc,
+                        payment_hash,
+                        new_transition(
+                            &plugin,
+                            Some(hold_state),
+                            Holdstate::Canceled,
+                            HoldStateReason::ChannelUnhealthy,
+                            HoldInitiator::ChannelMonitor,
+                            amount_held_msat,
+                        ),
+                    )
+                    .await;
+                    send_state_event(
+                        &plugin,
+                        rpc,
+                        payment_hash,
+                        Holdstate::Canceled,
+                        HoldStateReason::ChannelUnhealthy,
+                        event_invoice.clone(),
+                    )
+                    .await;
+                    set_hold_state(&holdinvoice, Holdstate::Canceled);
+                    hold_state = Holdstate::Canceled
+                }
+                Err(e) => {
+                    HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+                    warn!(
+                        "Error updating state for payment_hash: {} {}",
+                        payment_hash, e
+                    );
+                    continue;
                 }
             }
+        }
 
-            match holdinvoice_data.hold_state {
-                Holdstate::Open => {
-                    if Amount::msat(&invoice.amount_msat.unwrap())
-                        <= holdinvoice_data
-                            .htlc_data
-                            .values()
-                            .map(|htlc| htlc.amount_msat)
-                            .sum()
-                        && holdinvoice_data
-                            .hold_state
-                            .is_valid_transition(&Holdstate::Accepted)
-                    {
-                        match datastore_update_state(
-                            &mut rpc,
-                            payment_hash.to_owned(),
-                            Holdstate::Accepted.to_string(),
-                            holdinvoice_data.generation,
-                        )
+        match hold_state {
+            Holdstate::Open => {
+                if accept_threshold_msat <= amount_held_msat
+                    && hold_state.is_valid_transition(&Holdstate::Accepted)
+                {
+                    let Some(rpc) = hold_loop_rpc(&plugin, &mut pooled, payment_hash).await else {
+                        continue;
+                    };
+                    match plugin
+                        .state()
+                        .holdstates
+                        .update(rpc, payment_hash, Holdstate::Accepted, generation)
                         .await
-                        {
-                            Ok(_o) => (),
-                            Err(e) => {
-                                warn!(
-                                    "Error updating state for payment_hash: {} {}",
-                                    payment_hash,
-                                    e.to_string()
-                                );
-                                continue;
-                            }
-                        };
-                        info!(
-                            "payment_hash: `{}` scid: `{}` htlc: `{}`. \
-                                    Got enough msats for holdinvoice. \
-                                    State=ACCEPTED",
-                            payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
-                        );
-                        *holdinvoice_data
-                            .htlc_data
-                            .get(&global_htlc_ident)
-                            .unwrap()
-                            .loop_mutex
-                            .lock()
-                            .await = false;
-                    } else {
-                        debug!(
-                            "payment_hash: `{}` scid: `{}` htlc: `{}`. \
-                                    Not enough msats for holdinvoice yet.",
-                            payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
-                        );
-                    }
-                }
-                Holdstate::Accepted => {
-                    if Amount::msat(&invoice.amount_msat.unwrap())
-                        > holdinvoice_data
-                            .htlc_data
-                            .values()
-                            .map(|htlc| htlc.amount_msat)
-                            .sum()
                     {
-                        match datastore_update_state(
-                            &mut rpc,
-                            payment_hash.to_owned(),
-                            Holdstate::Open.to_string(),
-                            holdinvoice_data.generation,
-                        )
-                        .await
-                        {
-                            Ok(_o) => (),
-                            Err(e) => {
-                                warn!(
-                                    "Error updating state for payment_hash: {} {}",
-                                    payment_hash,
-                                    e.to_string()
-                                );
-                                continue;
-                            }
-                        };
-                        warn!(
-                            "payment_hash: `{}` scid: `{}` htlc: `{}`. \
-                                    No longer enough msats for holdinvoice! \
-                                    This should only happen during a node restart! \
-                                    Back to OPEN state!",
-                            payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
-                        );
-                    } else {
-                        debug!(
-                            "payment_hash: `{}` scid: `{}` htlc: `{}`. \
-                                    Holding accepted holdinvoice.",
-                            payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
-                        );
-                        *holdinvoice_data
-                            .htlc_data
-                            .get(&global_htlc_ident)
-                            .unwrap()
-                            .loop_mutex
-                            .lock()
-                            .await = false;
-                    }
-                }
-                Holdstate::Settled => {
+                        Ok(_o) => (),
+                        Err(e) => {
+                            HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+                            warn!(
+                                "Error updating state for payment_hash: {} {}",
+                                payment_hash, e
+                            );
+                            continue;
+                        }
+                    };
                     info!(
                         "payment_hash: `{}` scid: `{}` htlc: `{}`. \
-                                    Settling htlc for holdinvoice. State=SETTLED",
+                                Got enough msats for holdinvoice. \
+                                State=ACCEPTED",
                         payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
                     );
-
-                    cleanup_pluginstate_holdinvoices(
-                        &mut holdinvoices,
+                    record_transition(
+                        &plugin,
+                        rpc,
                         payment_hash,
-                        &global_htlc_ident,
+                        new_transition(
+                            &plugin,
+                            Some(Holdstate::Open),
+                            Holdstate::Accepted,
+                            HoldStateReason::HtlcsComplete,
+                            HoldInitiator::Htlc,
+                            amount_held_msat,
+                        ),
                     )
                     .await;
-
-                    return Ok(json!({"result": "continue"}));
+                    // lets a restart restore the holdinvoice as ACCEPTED
+                    let htlc_set = holdinvoice.lock().htlc_set();
+                    if let Err(e) =
+                        datastore_htlc_set(rpc, payment_hash.to_owned(), &htlc_set).await
+                    {
+                        HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+                        warn!(
+                            "Error persisting htlcs for payment_hash: {} {}",
+                            payment_hash, e
+                        );
+                    }
+                    send_state_event(
+                        &plugin,
+                        rpc,
+                        payment_hash,
+                        Holdstate::Accepted,
+                        HoldStateReason::HtlcsComplete,
+                        event_invoice.clone(),
+                    )
+                    .await;
+                    set_hold_state(&holdinvoice, Holdstate::Accepted);
+                } else {
+                    debug!(
+                        "payment_hash: `{}` scid: `{}` htlc: `{}`. \
+                                Not enough msats for holdinvoice yet.",
+                        payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
+                    );
                 }
-                Holdstate::Canceled => {
-                    info!(
+            }
+            Holdstate::Accepted => {
+                if accept_threshold_msat > amount_held_msat {
+                    let Some(rpc) = hold_loop_rpc(&plugin, &mut pooled, payment_hash).await else {
+                        continue;
+                    };
+                    match plugin
+                        .state()
+                        .holdstates
+                        .update(rpc, payment_hash, Holdstate::Open, generation)
+                        .await
+                    {
+                        Ok(_o) => (),
+                        Err(e) => {
+                            HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+                            warn!(
+                                "Error updating state for payment_hash: {} {}",
+                                payment_hash, e
+                            );
+                            continue;
+                        }
+                    };
+                    warn!(
                         "payment_hash: `{}` scid: `{}` htlc: `{}`. \
-                                    Rejecting htlc for canceled holdinvoice. \
-                                    State=CANCELED",
+                                No longer enough msats for holdinvoice! \
+                                This should only happen during a node restart! \
+                                Back to OPEN state!",
                         payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
                     );
-
-                    cleanup_pluginstate_holdinvoices(
-                        &mut holdinvoices,
+                    record_transition(
+                        &plugin,
+                        rpc,
                         payment_hash,
-                        &global_htlc_ident,
+                        new_transition(
+                            &plugin,
+                            Some(Holdstate::Accepted),
+                            Holdstate::Open,
+                            HoldStateReason::HtlcsMissing,
+                            HoldInitiator::Restart,
+                            amount_held_msat,
+                        ),
                     )
                     .await;
+                    send_state_event(
+                        &plugin,
+                        rpc,
+                        payment_hash,
+                        Holdstate::Open,
+                        HoldStateReason::HtlcsMissing,
+                        event_invoice.clone(),
+                    )
+                    .await;
+                    set_hold_state(&holdinvoice, Holdstate::Open);
+                } else {
+                    debug!(
+                        "payment_hash: `{}` scid: `{}` htlc: `{}`. \
+                                Holding accepted holdinvoice.",
+                        payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
+                    );
+                }
+            }
+            Holdstate::Settled => {
+                // cln can't settle hash-only invoices, we have to resolve them ourselves
+                let preimage = if is_hash_only {
+                    let Some(rpc) = hold_loop_rpc(&plugin, &mut pooled, payment_hash).await else {
+                        continue;
+                    };
+                    match listdatastore_preimage(rpc, payment_hash.to_owned()).await {
+                        Ok(p) => Some(p),
+                        Err(e) => {
+                            HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+                            warn!(
+                                "Error getting preimage for payment_hash: {} {}",
+                                payment_hash, e
+                            );
+                            continue;
+                        }
+                    }
+                } else {
+                    None
+                };
+                info!(
+                    "payment_hash: `{}` scid: `{}` htlc: `{}`. \
+                                Settling htlc for holdinvoice. State=SETTLED",
+                    payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
+                );
 
-                    return Ok(json!({"result": "fail",
-                    "failure_message": get_failure_message(
-                        *plugin.state().blockheight.lock(),
-                        amount_msat)
-                    }));
+                cleanup_pluginstate_holdinvoices(&plugin, payment_hash, &global_htlc_ident);
+
+                if let Some(p) = preimage {
+                    return Ok(json!({"result": "resolve", "payment_key": p}));
                 }
+                return Ok(json!({"result": "continue"}));
+            }
+            Holdstate::Canceled => {
+                info!(
+                    "payment_hash: `{}` scid: `{}` htlc: `{}`. \
+                                Rejecting htlc for canceled holdinvoice. \
+                                State=CANCELED",
+                    payment_hash, global_htlc_ident.scid, global_htlc_ident.htlc_id
+                );
+
+                cleanup_pluginstate_holdinvoices(&plugin, payment_hash, &global_htlc_ident);
+
+                return Ok(json!({"result": "fail",
+                "failure_message": get_failure_message(
+                    *plugin.state().blockheight.lock(),
+                    amount_msat)
+                }));
             }
         }
+
+        wait = next_wakeup(
+            invoice.expires_at,
+            cancel_hold_before_invoice_expiry_seconds,
+            now,
+        );
+    }
+}
+
+/// The hold loop also wakes up when the invoice (soft) expires, everything
+/// else wakes it via `HoldInvoice::wakeup` or a new block
+fn next_wakeup(expires_at: u64, cancel_before_invoice_expiry: u64, now: u64) -> Duration {
+    let soft_expiry = expires_at.saturating_sub(cancel_before_invoice_expiry);
+    if now < soft_expiry {
+        Duration::from_secs(soft_expiry - now)
+    } else if now < expires_at {
+        Duration::from_secs(expires_at - now)
+    } else {
+        Duration::from_secs(HOLD_LOOP_RETRY_INTERVAL)
     }
 }
 
@@ -506,12 +996,136 @@ pub async fn block_added(plugin: Plugin<PluginState>, v: serde_json::Value) -> R
         return Err(anyhow!("could not find height for block"));
     }
 
-    let mut holdinvoices = plugin.state().holdinvoices.lock().await;
-    for (_, invoice) in holdinvoices.iter_mut() {
-        for (_, htlc) in invoice.htlc_data.iter_mut() {
-            *htlc.loop_mutex.lock().await = true;
-        }
+    plugin.state().holdinvoices.wake_all();
+
+    Ok(())
+}
+
+pub async fn peer_connected(
+    plugin: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<(), Error> {
+    peer_connection_changed(plugin, v, "connect", true)
+}
+
+pub async fn peer_disconnected(
+    plugin: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<(), Error> {
+    peer_connection_changed(plugin, v, "disconnect", false)
+}
+
+fn peer_connection_changed(
+    plugin: Plugin<PluginState>,
+    v: serde_json::Value,
+    topic: &str,
+    connected: bool,
+) -> Result<(), Error> {
+    let peer_id = match v.get(topic).unwrap_or(&v).get("id") {
+        Some(id) => serde_json::from_value::<PublicKey>(id.clone())?,
+        None => return Err(anyhow!("could not find id for {} notification", topic)),
+    };
+    if plugin
+        .state()
+        .channel_health
+        .set_peer_connected(peer_id, connected)
+    {
+        debug!(
+            "peer {} {}",
+            peer_id,
+            if connected {
+                "reconnected"
+            } else {
+                "disconnected"
+            }
+        );
+        channel_health_changed(&plugin);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ChannelStateChanged {
+    peer_id: PublicKey,
+    channel_id: String,
+    #[serde(default)]
+    short_channel_id: Option<ShortChannelId>,
+    new_state: serde_json::Value,
+}
+
+pub async fn channel_state_changed(
+    plugin: Plugin<PluginState>,
+    v: serde_json::Value,
+) -> Result<(), Error> {
+    let changed = serde_json::from_value::<ChannelStateChanged>(
+        v.get("channel_state_changed").unwrap_or(&v).clone(),
+    )?;
+    // states newer than cln-rpc, like CLOSED, come after the channel was closing anyway
+    let closing = match serde_json::from_value::<ChannelState>(changed.new_state.clone()) {
+        Ok(s) if is_closing(s) => true,
+        Ok(ChannelState::CHANNELD_NORMAL) => false,
+        _ => return Ok(()),
+    };
+
+    let mut scids: Vec<ShortChannelId> = changed.short_channel_id.into_iter().collect();
+    // htlcs can arrive on the alias of unannounced channels
+    match plugin.state().rpc_pool.get().await {
+        Ok(mut rpc) => match rpc
+            .call_typed(&ListpeerchannelsRequest {
+                id: Some(changed.peer_id),
+                short_channel_id: None,
+            })
+            .await
+        {
+            Ok(response) => scids.extend(
+                response
+                    .channels
+                    .into_iter()
+                    .filter(|c| {
+                        c.channel_id.map(|i| i.to_string()).as_ref() == Some(&changed.channel_id)
+                    })
+                    .filter_map(|c| c.alias.and_then(|a| a.local)),
+            ),
+            Err(e) => warn!("Error looking up alias of {}: {}", changed.channel_id, e),
+        },
+        Err(e) => warn!("Error looking up alias of {}: {}", changed.channel_id, e),
     }
 
+    if plugin
+        .state()
+        .channel_health
+        .set_channel_closing(&scids, closing)
+    {
+        debug!(
+            "channel {} is {}",
+            changed.channel_id,
+            if closing { "closing" } else { "normal again" }
+        );
+        channel_health_changed(&plugin);
+    }
     Ok(())
 }
+
+/// Warns about holdinvoices with htlcs on unhealthy channels and lets their
+/// hold loops apply the channel health policy
+fn channel_health_changed(plugin: &Plugin<PluginState>) {
+    let health = &plugin.state().channel_health;
+    for holdinvoice in plugin.state().holdinvoices.values() {
+        let holdinvoice = holdinvoice.lock();
+        for (ident, htlc) in holdinvoice.htlc_data.iter() {
+            if let Some(issue) = health.issue(ident.scid, htlc.peer_id) {
+                warn!(
+                    "payment_hash: `{}` scid: `{}` htlc: `{}`. \
+                    Holding htlc on unhealthy channel: {}. State={} policy={}",
+                    holdinvoice.invoice.payment_hash,
+                    ident.scid,
+                    ident.htlc_id,
+                    issue,
+                    holdinvoice.hold_state,
+                    health.policy
+                );
+            }
+        }
+    }
+    plugin.state().holdinvoices.wake_all();
+}
@@ -1,51 +1,96 @@
 #![recursion_limit = "1024"]
 use std::{
-    collections::BTreeMap,
     error::Error,
     net::SocketAddr,
     path::{Path, PathBuf},
+    str::FromStr,
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, Context, Result};
 use cln_plugin::{
-    options::{ConfigOption, DefaultIntegerConfigOption, IntegerConfigOption},
+    messages::NotificationTopic,
+    options::{
+        ConfigOption,
+        DefaultBooleanConfigOption,
+        DefaultIntegerConfigOption,
+        DefaultStringConfigOption,
+        IntegerConfigOption,
+        StringConfigOption,
+    },
     Builder,
     ConfiguredPlugin,
     Plugin,
 };
-use cln_rpc::ClnRpc;
 use log::{debug, info, warn};
-use model::{PluginState, HOLD_STARTUP_LOCK};
+use metrics::HoldMetrics;
+use model::{
+    ChannelHealth,
+    ChannelHealthPolicy,
+    HoldInvoiceStore,
+    HoldOffers,
+    PluginState,
+    Readiness,
+    HOLD_EVENT_CHANNEL_SIZE,
+    HOLD_NOTIFICATION_ACCEPTED,
+    HOLD_NOTIFICATION_CANCELED,
+    HOLD_NOTIFICATION_SETTLED,
+};
 use parking_lot::Mutex;
+use pool::RpcPool;
+use statecache::HoldStateCache;
 use tls::do_certificates_exist;
 use tokio::time;
 
 use crate::{
-    hold::{hold_invoice, hold_invoice_cancel, hold_invoice_lookup, hold_invoice_settle},
-    model::Holdstate,
+    hold::{
+        hold_invoice,
+        hold_invoice_autoclean,
+        hold_invoice_cancel,
+        hold_invoice_cancel_many,
+        hold_invoice_history,
+        hold_invoice_list,
+        hold_invoice_lookup,
+        hold_invoice_settle,
+        hold_invoice_settle_many,
+        hold_invoice_status,
+        hold_invoice_wait,
+        hold_offer,
+    },
+    model::{HoldInitiator, Holdstate},
     pb::hold_server::HoldServer,
     util::make_rpc_path,
 };
 
+mod bolt11;
 mod config;
 mod errors;
+#[cfg(test)]
+mod fakecln;
 mod hold;
 mod hooks;
+mod metrics;
 mod model;
+mod pool;
 mod rpc;
 mod tasks;
 mod tls;
 mod util;
+mod webhook;
 
 pub mod pb;
 mod server;
+mod statecache;
 
 const OPT_GRPC_HOLD_PORT: IntegerConfigOption = ConfigOption::new_i64_no_default(
     "grpc-hold-port",
     "Which port should the grpc plugin listen for incoming connections?",
 );
+const OPT_METRICS_HOLD_PORT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "metrics-hold-port",
+    "Which localhost port should serve prometheus metrics on /metrics?",
+);
 const OPT_CANCEL_HOLD_BEFORE_HTLC_EXPIRY_BLOCKS: DefaultIntegerConfigOption =
     ConfigOption::new_i64_with_default(
         "holdinvoice-cancel-before-htlc-expiry",
@@ -58,6 +103,75 @@ const OPT_CANCEL_HOLD_BEFORE_INVOICE_EXPIRY_SECONDS: DefaultIntegerConfigOption
         1_800,
         "Seconds before invoice expiry when an invoice and pending htlcs get auto-canceled",
     );
+const OPT_WEBHOOK_URL: StringConfigOption = ConfigOption::new_str_no_default(
+    "holdinvoice-webhook-url",
+    "http url that gets a signed POST for every holdstate change",
+);
+const OPT_WEBHOOK_SECRET: StringConfigOption = ConfigOption::new_str_no_default(
+    "holdinvoice-webhook-secret",
+    "Secret used to sign webhook POSTs with HMAC-SHA256",
+);
+
+const OPT_MAX_OVERPAY_MSAT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "holdinvoice-max-overpay-msat",
+    "Fail htlcs that would make us hold more than this many msat above the invoice amount",
+);
+const OPT_MAX_OVERPAY_PERCENT: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "holdinvoice-max-overpay-percent",
+    "Fail htlcs that would make us hold more than this percentage above the invoice amount",
+);
+
+const OPT_RPC_POOL_SIZE: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+    "holdinvoice-rpc-pool-size",
+    10,
+    "Maximum number of rpc connections to cln the plugin keeps open",
+);
+
+const OPT_AUTOCLEAN_CYCLE: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+    "holdinvoice-autoclean-cycle",
+    3_600,
+    "Seconds between autoclean runs of the holdinvoice datastore, 0 disables them",
+);
+const OPT_AUTOCLEAN_SETTLED_AGE: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "holdinvoice-autoclean-settled-age",
+    "Remove SETTLED holdinvoices from the datastore this many seconds after they were settled",
+);
+const OPT_AUTOCLEAN_CANCELED_AGE: IntegerConfigOption = ConfigOption::new_i64_no_default(
+    "holdinvoice-autoclean-canceled-age",
+    "Remove CANCELED and expired holdinvoices from the datastore this many seconds after \
+    they were canceled or expired",
+);
+const OPT_AUTOCLEAN_DELINVOICE: DefaultBooleanConfigOption = ConfigOption::new_bool_with_default(
+    "holdinvoice-autoclean-delinvoice",
+    false,
+    "Also delete cln's invoice of CANCELED and expired holdinvoices removed by autoclean",
+);
+
+const OPT_STARTUP_TIMEOUT: DefaultIntegerConfigOption = ConfigOption::new_i64_with_default(
+    "holdinvoice-startup-timeout",
+    60,
+    "Maximum seconds to wait for cln to replay the htlcs of holdinvoices after a restart",
+);
+
+const OPT_CHANNEL_HEALTH_POLICY: DefaultStringConfigOption = ConfigOption::new_str_with_default(
+    "holdinvoice-channel-health-policy",
+    "warn",
+    "What to do with holdinvoices that have htlcs on a disconnected or closing channel: \
+    warn, cancel or tighten",
+);
+const OPT_UNHEALTHY_CANCEL_BEFORE_HTLC_EXPIRY_BLOCKS: DefaultIntegerConfigOption =
+    ConfigOption::new_i64_with_default(
+        "holdinvoice-unhealthy-cancel-before-htlc-expiry",
+        18,
+        "Number of blocks before expiry htlcs on a disconnected or closing channel get \
+        auto-canceled with the tighten policy",
+    );
+
+const OPT_ON_EXPIRY: DefaultStringConfigOption = ConfigOption::new_str_with_default(
+    "holdinvoice-on-expiry",
+    "settle",
+    "What to do with ACCEPTED holdinvoices close to expiry: settle or cancel",
+);
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), anyhow::Error> {
@@ -68,30 +182,88 @@ async fn main() -> Result<(), anyhow::Error> {
 
     let plugin = match Builder::new(tokio::io::stdin(), tokio::io::stdout())
         .option(OPT_GRPC_HOLD_PORT)
+        .option(OPT_METRICS_HOLD_PORT)
         .option(OPT_CANCEL_HOLD_BEFORE_HTLC_EXPIRY_BLOCKS)
         .option(OPT_CANCEL_HOLD_BEFORE_INVOICE_EXPIRY_SECONDS)
-        .rpcmethod(
-            "holdinvoice",
-            "create a new invoice and hold it",
-            hold_invoice,
-        )
+        .option(OPT_WEBHOOK_URL)
+        .option(OPT_WEBHOOK_SECRET)
+        .option(OPT_MAX_OVERPAY_MSAT)
+        .option(OPT_MAX_OVERPAY_PERCENT)
+        .option(OPT_ON_EXPIRY)
+        .option(OPT_RPC_POOL_SIZE)
+        .option(OPT_AUTOCLEAN_CYCLE)
+        .option(OPT_AUTOCLEAN_SETTLED_AGE)
+        .option(OPT_AUTOCLEAN_CANCELED_AGE)
+        .option(OPT_AUTOCLEAN_DELINVOICE)
+        .option(OPT_STARTUP_TIMEOUT)
+        .option(OPT_CHANNEL_HEALTH_POLICY)
+        .option(OPT_UNHEALTHY_CANCEL_BEFORE_HTLC_EXPIRY_BLOCKS)
+        .rpcmethod("holdinvoice", "create a new invoice and hold it", |p, v| {
+            hold_invoice(p, v, HoldInitiator::Rpc)
+        })
         .rpcmethod(
             "holdinvoicesettle",
             "settle htlcs to corresponding holdinvoice",
-            hold_invoice_settle,
+            |p, v| hold_invoice_settle(p, v, HoldInitiator::Rpc),
         )
         .rpcmethod(
             "holdinvoicecancel",
             "cancel htlcs to corresponding holdinvoice",
-            hold_invoice_cancel,
+            |p, v| hold_invoice_cancel(p, v, HoldInitiator::Rpc),
         )
         .rpcmethod(
             "holdinvoicelookup",
             "lookup hold status of holdinvoice",
-            hold_invoice_lookup,
+            |p, v| hold_invoice_lookup(p, v, HoldInitiator::Rpc),
+        )
+        .rpcmethod(
+            "holdinvoicelist",
+            "list holdinvoices with optional filters",
+            hold_invoice_list,
+        )
+        .rpcmethod(
+            "holdinvoicesettlemany",
+            "settle many holdinvoices by payment_hashes or label_prefix",
+            |p, v| hold_invoice_settle_many(p, v, HoldInitiator::Rpc),
+        )
+        .rpcmethod(
+            "holdinvoicecancelmany",
+            "cancel many holdinvoices by payment_hashes or label_prefix",
+            |p, v| hold_invoice_cancel_many(p, v, HoldInitiator::Rpc),
+        )
+        .rpcmethod(
+            "holdinvoicewait",
+            "wait until a holdinvoice reaches one of the given holdstates",
+            hold_invoice_wait,
+        )
+        .rpcmethod(
+            "holdoffer",
+            "hold every invoice paid against an existing offer",
+            hold_offer,
+        )
+        .rpcmethod(
+            "holdinvoicehistory",
+            "list the holdstate transitions of a holdinvoice",
+            hold_invoice_history,
+        )
+        .rpcmethod(
+            "holdinvoiceautoclean",
+            "remove old holdinvoices from the datastore now",
+            hold_invoice_autoclean,
+        )
+        .rpcmethod(
+            "holdinvoicestatus",
+            "show whether the plugin is ready after a restart",
+            hold_invoice_status,
         )
         .hook("htlc_accepted", hooks::htlc_handler)
         .subscribe("block_added", hooks::block_added)
+        .subscribe("connect", hooks::peer_connected)
+        .subscribe("disconnect", hooks::peer_disconnected)
+        .subscribe("channel_state_changed", hooks::channel_state_changed)
+        .notification(NotificationTopic::new(HOLD_NOTIFICATION_ACCEPTED))
+        .notification(NotificationTopic::new(HOLD_NOTIFICATION_SETTLED))
+        .notification(NotificationTopic::new(HOLD_NOTIFICATION_CANCELED))
         .configure()
         .await?
     {
@@ -127,18 +299,27 @@ async fn main() -> Result<(), anyhow::Error> {
         }
     };
 
+    // replayed htlcs wait in the hook until the accepted htlc sets are restored
+    let restore_guard = state.restore_lock.clone().write_owned().await;
     let confplugin;
     match plugin.start(state.clone()).await {
         Ok(p) => {
             confplugin = p;
+            if let Err(e) = tasks::restore_accepted_htlcs(confplugin.clone(), restore_guard).await {
+                warn!("Error restoring accepted htlcs: {}", e);
+            }
             let cleanupclone = confplugin.clone();
             tokio::spawn(async move {
                 match tasks::autoclean_holdinvoice_db(cleanupclone).await {
                     Ok(()) => (),
-                    Err(e) => warn!(
-                        "Error in autoclean_holdinvoice_db thread: {}",
-                        e.to_string()
-                    ),
+                    Err(e) => warn!("Error in autoclean_holdinvoice_db thread: {}", e),
+                };
+            });
+            let webhookclone = confplugin.clone();
+            tokio::spawn(async move {
+                match webhook::deliver_webhooks(webhookclone).await {
+                    Ok(()) => (),
+                    Err(e) => warn!("Error in deliver_webhooks thread: {}", e),
                 };
             });
         }
@@ -152,13 +333,33 @@ async fn main() -> Result<(), anyhow::Error> {
         tokio::spawn(async move {
             match run_interface(bind_addr, rpc_path, grpc_plugin_clone).await {
                 Ok(_) => log::info!("grpc interface stopped"),
-                Err(e) => log::warn!("{}", e.to_string()),
+                Err(e) => log::warn!("{}", e),
             }
         });
     }
 
-    time::sleep(Duration::from_secs(HOLD_STARTUP_LOCK)).await;
-    *confplugin.state().startup_lock.lock() = false;
+    if let Some(port) = confplugin.option(&OPT_METRICS_HOLD_PORT)? {
+        let bind_addr: SocketAddr = format!("127.0.0.1:{}", port).parse()?;
+        let metrics_plugin_clone = confplugin.clone();
+        tokio::spawn(async move {
+            match metrics::serve_metrics(bind_addr, metrics_plugin_clone).await {
+                Ok(_) => log::info!("metrics interface stopped"),
+                Err(e) => log::warn!("{}", e),
+            }
+        });
+    }
+
+    let readiness = confplugin.state().readiness.clone();
+    let until_deadline = readiness
+        .deadline()
+        .saturating_sub(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs());
+    tokio::select! {
+        _ = readiness.wait() => info!("all replayed htlcs arrived, plugin is ready"),
+        _ = time::sleep(Duration::from_secs(until_deadline)) => {
+            warn!("{} is reached, plugin is ready without all replayed htlcs", OPT_STARTUP_TIMEOUT.name);
+            readiness.time_out();
+        }
+    }
 
     confplugin.join().await
 }
@@ -179,15 +380,28 @@ async fn init_plugin_state(
 
     let rpc_path =
         Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file);
-    let rpc = ClnRpc::new(&rpc_path).await?;
+    let pool_size = plugin.option(&OPT_RPC_POOL_SIZE)? as usize;
+    let metrics = Arc::new(HoldMetrics::default());
+    let channel_health = ChannelHealth::new(
+        ChannelHealthPolicy::from_str(&plugin.option(&OPT_CHANNEL_HEALTH_POLICY)?)?,
+        plugin.option(&OPT_UNHEALTHY_CANCEL_BEFORE_HTLC_EXPIRY_BLOCKS)? as u32,
+    );
 
     Ok(PluginState {
         blockheight: Arc::new(Mutex::new(u32::default())),
-        holdinvoices: Arc::new(tokio::sync::Mutex::new(BTreeMap::new())),
+        holdinvoices: HoldInvoiceStore::default(),
         identity,
         ca_cert,
-        startup_lock: Arc::new(Mutex::new(true)),
-        rpc: Arc::new(tokio::sync::Mutex::new(rpc)),
+        readiness: Readiness::new(plugin.option(&OPT_STARTUP_TIMEOUT)? as u64),
+        restore_lock: Arc::new(tokio::sync::RwLock::new(())),
+        rpc_pool: Arc::new(RpcPool::new(rpc_path, pool_size, metrics.clone())),
+        holdstates: HoldStateCache::default(),
+        hold_offers: HoldOffers::default(),
+        channel_health,
+        autoclean_lock: Arc::new(tokio::sync::Mutex::new(())),
+        events: tokio::sync::broadcast::channel(HOLD_EVENT_CHANNEL_SIZE).0,
+        webhook_wakeup: Arc::new(tokio::sync::Notify::new()),
+        metrics,
     })
 }
 
@@ -220,7 +434,7 @@ async fn run_interface(
 
     server
         .await
-        .map_err(|e| anyhow!("Error serving grpc: {} {:?}", e.to_string(), e.source()))
+        .map_err(|e| anyhow!("Error serving grpc: {} {:?}", e, e.source()))
 }
 
 fn log_error(error: String) {
@@ -0,0 +1,273 @@
+//! Prometheus metrics. Counters are updated where things happen, gauges are
+//! computed from the plugin state and cln's datastore on every scrape.
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Error};
+use cln_plugin::Plugin;
+use hyper::{
+    header::CONTENT_TYPE,
+    service::{make_service_fn, service_fn},
+    Body,
+    Method,
+    Request,
+    Response,
+    Server,
+    StatusCode,
+};
+use log::{debug, warn};
+use parking_lot::Mutex;
+
+use crate::model::{Holdstate, PluginState};
+
+/// Upper bounds in seconds, same as the default buckets of the prometheus clients
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+pub struct HoldMetrics {
+    pub auto_settled: AtomicU64,
+    pub auto_canceled: AtomicU64,
+    pub datastore_errors: AtomicU64,
+    pub rpc_errors: AtomicU64,
+    hook_latency: Mutex<Histogram>,
+    rpc_pool_wait: Mutex<Histogram>,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|b| secs <= *b) {
+            self.buckets[idx] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) -> Result<(), Error> {
+        header(out, name, help, "histogram");
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative)?;
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, self.count)?;
+        writeln!(out, "{}_sum {}", name, self.sum)?;
+        writeln!(out, "{}_count {}", name, self.count)?;
+        Ok(())
+    }
+}
+
+impl HoldMetrics {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Observes the time until it is dropped
+    pub fn hook_timer(self: &Arc<Self>) -> HookTimer {
+        HookTimer {
+            metrics: self.clone(),
+            started: Instant::now(),
+        }
+    }
+
+    fn observe_hook(&self, elapsed: Duration) {
+        self.hook_latency.lock().observe(elapsed);
+    }
+
+    pub fn observe_rpc_pool_wait(&self, elapsed: Duration) {
+        self.rpc_pool_wait.lock().observe(elapsed);
+    }
+}
+
+pub struct HookTimer {
+    metrics: Arc<HoldMetrics>,
+    started: Instant,
+}
+
+impl Drop for HookTimer {
+    fn drop(&mut self) {
+        self.metrics.observe_hook(self.started.elapsed());
+    }
+}
+
+pub async fn serve_metrics(
+    bind_addr: SocketAddr,
+    plugin: Plugin<PluginState>,
+) -> Result<(), Error> {
+    let make_service = make_service_fn(move |_conn| {
+        let plugin = plugin.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle_request(plugin.clone(), req))) }
+    });
+    debug!("serving metrics on {:?}", bind_addr);
+    Server::try_bind(&bind_addr)?
+        .serve(make_service)
+        .await
+        .map_err(|e| anyhow!("Error serving metrics: {}", e))
+}
+
+async fn handle_request(
+    plugin: Plugin<PluginState>,
+    req: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        return Ok(status_response(StatusCode::NOT_FOUND));
+    }
+    match render_metrics(&plugin).await {
+        Ok(body) => Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(body))
+            .unwrap_or_else(|_| status_response(StatusCode::INTERNAL_SERVER_ERROR))),
+        Err(e) => {
+            warn!("Error rendering metrics: {}", e);
+            Ok(status_response(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+async fn render_metrics(plugin: &Plugin<PluginState>) -> Result<String, Error> {
+    let mut invoices = BTreeMap::from([
+        (Holdstate::Open.to_string(), 0u64),
+        (Holdstate::Accepted.to_string(), 0),
+        (Holdstate::Settled.to_string(), 0),
+        (Holdstate::Canceled.to_string(), 0),
+    ]);
+    for (_, cached) in plugin.state().holdstates.entries() {
+        *invoices.entry(cached.state.to_string()).or_default() += 1;
+    }
+
+    let blockheight = *plugin.state().blockheight.lock();
+    let mut held_msat = 0;
+    let mut held_htlcs = 0;
+    let mut nearest_expiry: Option<u32> = None;
+    for holdinvoice in plugin.state().holdinvoices.values() {
+        for htlc in holdinvoice.lock().htlc_data.values() {
+            held_msat += htlc.amount_msat;
+            held_htlcs += 1;
+            let blocks = htlc.cltv_expiry.saturating_sub(blockheight);
+            nearest_expiry = Some(nearest_expiry.map_or(blocks, |n| n.min(blocks)));
+        }
+    }
+
+    let metrics = &plugin.state().metrics;
+    let mut out = String::new();
+    header(
+        &mut out,
+        "holdinvoice_invoices",
+        "Holdinvoices per holdstate",
+        "gauge",
+    );
+    for (state, count) in invoices {
+        writeln!(out, "holdinvoice_invoices{{state=\"{}\"}} {}", state, count)?;
+    }
+    header(
+        &mut out,
+        "holdinvoice_held_msat",
+        "Sum of all currently held htlcs",
+        "gauge",
+    );
+    writeln!(out, "holdinvoice_held_msat {}", held_msat)?;
+    header(
+        &mut out,
+        "holdinvoice_held_htlcs",
+        "Number of currently held htlcs",
+        "gauge",
+    );
+    writeln!(out, "holdinvoice_held_htlcs {}", held_htlcs)?;
+    if let Some(blocks) = nearest_expiry {
+        header(
+            &mut out,
+            "holdinvoice_nearest_cltv_expiry_blocks",
+            "Blocks until the held htlc closest to its cltv_expiry expires",
+            "gauge",
+        );
+        writeln!(out, "holdinvoice_nearest_cltv_expiry_blocks {}", blocks)?;
+    }
+    for (name, help, counter) in [
+        (
+            "holdinvoice_auto_settled_total",
+            "Holdinvoices settled automatically close to expiry",
+            &metrics.auto_settled,
+        ),
+        (
+            "holdinvoice_auto_canceled_total",
+            "Holdinvoices canceled automatically close to or after expiry or on unhealthy channels",
+            &metrics.auto_canceled,
+        ),
+        (
+            "holdinvoice_datastore_errors_total",
+            "Failed reads and writes of holdinvoice data in cln's datastore",
+            &metrics.datastore_errors,
+        ),
+        (
+            "holdinvoice_rpc_errors_total",
+            "Failed calls to cln while processing htlcs",
+            &metrics.rpc_errors,
+        ),
+    ] {
+        header(&mut out, name, help, "counter");
+        writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed))?;
+    }
+
+    let pool = &plugin.state().rpc_pool;
+    for (name, help, value) in [
+        (
+            "holdinvoice_rpc_pool_size",
+            "Maximum number of rpc connections to cln",
+            pool.size(),
+        ),
+        (
+            "holdinvoice_rpc_pool_open",
+            "Currently open rpc connections to cln",
+            pool.open(),
+        ),
+        (
+            "holdinvoice_rpc_pool_in_use",
+            "Rpc connections to cln that are currently in use",
+            pool.in_use(),
+        ),
+    ] {
+        header(&mut out, name, help, "gauge");
+        writeln!(out, "{} {}", name, value)?;
+    }
+
+    metrics.hook_latency.lock().render(
+        &mut out,
+        "holdinvoice_htlc_hook_seconds",
+        "Time the htlc hook needs to hold or resolve an htlc, without the time it is held",
+    )?;
+    metrics.rpc_pool_wait.lock().render(
+        &mut out,
+        "holdinvoice_rpc_pool_wait_seconds",
+        "Time spent waiting for a free rpc connection to cln",
+    )?;
+    Ok(out)
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
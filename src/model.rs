@@ -1,26 +1,52 @@
 use std::{
-    collections::{BTreeMap, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt,
     str::FromStr,
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::anyhow;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use cln_plugin::Error;
 use cln_rpc::{
-    model::responses::ListinvoicesInvoices,
-    primitives::{Secret, ShortChannelId},
-    ClnRpc,
+    model::responses::{ListinvoicesInvoices, ListinvoicesInvoicesStatus},
+    primitives::{Amount, AmountOrAny, ChannelState, PublicKey, Secret, ShortChannelId},
 };
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 
-use crate::{pb, tls::Identity};
+use crate::{metrics::HoldMetrics, pb, pool::RpcPool, statecache::HoldStateCache, tls::Identity};
 
 pub const HOLD_INVOICE_PLUGIN_NAME: &str = "holdinvoice";
 pub const HOLD_INVOICE_DATASTORE_STATE: &str = "state";
-pub const HOLD_STARTUP_LOCK: u64 = 10;
+pub const HOLD_INVOICE_DATASTORE_INVOICE: &str = "invoice";
+pub const HOLD_INVOICE_DATASTORE_PREIMAGE: &str = "preimage";
+pub const HOLD_INVOICE_DATASTORE_WEBHOOK_URL: &str = "webhook_url";
+pub const HOLD_INVOICE_DATASTORE_MIN_AMOUNT: &str = "min_amount_msat";
+pub const HOLD_INVOICE_DATASTORE_MAX_OVERPAY: &str = "max_overpay";
+pub const HOLD_INVOICE_DATASTORE_SAFETY_MARGINS: &str = "safety_margins";
+pub const HOLD_INVOICE_DATASTORE_ON_EXPIRY: &str = "on_expiry";
+pub const HOLD_INVOICE_DATASTORE_METADATA: &str = "metadata";
+pub const HOLD_INVOICE_DATASTORE_HISTORY: &str = "history";
+pub const HOLD_INVOICE_DATASTORE_HTLCS: &str = "htlcs";
+pub const HOLD_METADATA_MAX_SIZE: usize = 4_096;
+pub const HOLD_WEBHOOK_OUTBOX: &str = "webhook_outbox";
+pub const HOLD_OFFERS: &str = "offers";
+pub const HOLD_LABELS: &str = "labels";
+pub const HOLD_LOOP_RETRY_INTERVAL: u64 = 2;
+pub const HOLD_LIST_PAGE_SIZE: u32 = 1_000;
+pub const HOLD_LIST_HASH_ONLY_START: u64 = 1 << 63;
+pub const HOLD_DEFAULT_INVOICE_EXPIRY: u64 = 604_800;
+pub const HOLD_EVENT_CHANNEL_SIZE: usize = 4_096;
+pub const HOLD_NOTIFICATION_ACCEPTED: &str = "holdinvoice_accepted";
+pub const HOLD_NOTIFICATION_SETTLED: &str = "holdinvoice_settled";
+pub const HOLD_NOTIFICATION_CANCELED: &str = "holdinvoice_canceled";
+pub const HOLD_WEBHOOK_TIMEOUT: u64 = 10;
+pub const HOLD_WEBHOOK_RETRY_INTERVAL: u64 = 5;
+pub const HOLD_WEBHOOK_MAX_BACKOFF: u64 = 3_600;
+pub const HOLD_WEBHOOK_MAX_ATTEMPTS: u32 = 30;
+pub const HOLD_AUTOCLEAN_STARTUP_DELAY: u64 = 120;
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "UPPERCASE")]
@@ -71,11 +97,92 @@ impl FromStr for Holdstate {
     }
 }
 
+/// What happens to an ACCEPTED holdinvoice that is about to expire
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExpiryPolicy {
+    Settle,
+    Cancel,
+}
+impl ExpiryPolicy {
+    pub fn as_i32(&self) -> i32 {
+        match self {
+            ExpiryPolicy::Settle => 0,
+            ExpiryPolicy::Cancel => 1,
+        }
+    }
+}
+impl fmt::Display for ExpiryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpiryPolicy::Settle => write!(f, "settle"),
+            ExpiryPolicy::Cancel => write!(f, "cancel"),
+        }
+    }
+}
+impl FromStr for ExpiryPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "settle" => Ok(ExpiryPolicy::Settle),
+            "cancel" => Ok(ExpiryPolicy::Cancel),
+            _ => Err(anyhow!("could not parse ExpiryPolicy from {}", s)),
+        }
+    }
+}
+
+/// What happens to holdinvoices with HTLC's on a channel that went bad
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelHealthPolicy {
+    Warn,
+    Cancel,
+    Tighten,
+}
+impl fmt::Display for ChannelHealthPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChannelHealthPolicy::Warn => write!(f, "warn"),
+            ChannelHealthPolicy::Cancel => write!(f, "cancel"),
+            ChannelHealthPolicy::Tighten => write!(f, "tighten"),
+        }
+    }
+}
+impl FromStr for ChannelHealthPolicy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "warn" => Ok(ChannelHealthPolicy::Warn),
+            "cancel" => Ok(ChannelHealthPolicy::Cancel),
+            "tighten" => Ok(ChannelHealthPolicy::Tighten),
+            _ => Err(anyhow!("could not parse ChannelHealthPolicy from {}", s)),
+        }
+    }
+}
+
+/// Why a held HTLC can no longer be resolved off-chain right now
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelIssue {
+    PeerDisconnected,
+    ChannelClosing,
+}
+impl fmt::Display for ChannelIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ChannelIssue::PeerDisconnected => write!(f, "peer_disconnected"),
+            ChannelIssue::ChannelClosing => write!(f, "channel_closing"),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct HoldHtlc {
     pub amount_msat: u64,
     pub cltv_expiry: u32,
-    pub loop_mutex: Arc<tokio::sync::Mutex<bool>>,
+    /// unix timestamp of when the htlc_accepted hook was called
+    pub arrived_at: u64,
+    pub peer_id: Option<PublicKey>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -90,28 +197,742 @@ pub struct HoldInvoice {
     pub generation: u64,
     pub htlc_data: HashMap<HtlcIdentifier, HoldHtlc>,
     pub invoice: ListinvoicesInvoices,
+    pub hash_only: Option<HashOnlyInvoice>,
+    pub min_amount_msat: Option<u64>,
+    /// total_msat of the payment from the sender's onion
+    pub total_msat: Option<u64>,
+    pub max_overpay: OverpayLimit,
+    pub cancel_before_htlc_expiry: u32,
+    pub cancel_before_invoice_expiry: u64,
+    pub on_expiry: ExpiryPolicy,
+    pub metadata: Option<serde_json::Value>,
+    /// wakes the hold loops of all htlcs of this holdinvoice
+    pub wakeup: tokio::sync::watch::Sender<()>,
+}
+impl HoldInvoice {
+    pub fn amount_held_msat(&self) -> u64 {
+        self.htlc_data.values().map(|htlc| htlc.amount_msat).sum()
+    }
+    /// msat we must hold before the invoice is ACCEPTED. For "any" invoices
+    /// that's the full payment of the sender, but at least `min_amount_msat`.
+    pub fn accept_threshold_msat(&self) -> u64 {
+        match self.invoice.amount_msat {
+            Some(a) => a.msat(),
+            None => self
+                .total_msat
+                .unwrap_or(0)
+                .max(self.min_amount_msat.unwrap_or(1)),
+        }
+    }
+    pub fn event_invoice(&self) -> HoldEventInvoice {
+        HoldEventInvoice {
+            label: Some(self.invoice.label.clone()),
+            amount_msat: self.invoice.amount_msat.map(|a| a.msat()),
+            metadata: self.metadata.clone(),
+        }
+    }
+    pub fn overpay_cap_msat(&self) -> Option<u64> {
+        self.max_overpay.cap_msat(self.accept_threshold_msat())
+    }
+    pub fn htlc_set(&self) -> HoldHtlcSet {
+        HoldHtlcSet {
+            total_msat: self.total_msat,
+            htlcs: self
+                .htlc_data
+                .iter()
+                .map(|(ident, htlc)| HeldHtlcRecord {
+                    short_channel_id: ident.scid,
+                    htlc_id: ident.htlc_id,
+                    amount_msat: htlc.amount_msat,
+                    cltv_expiry: htlc.cltv_expiry,
+                    arrived_at: htlc.arrived_at,
+                    peer_id: htlc.peer_id,
+                })
+                .collect(),
+        }
+    }
+    /// Currently held HTLC's, oldest first
+    pub fn held_htlcs(&self, blockheight: u32, health: &ChannelHealth) -> Vec<HoldLookupHtlc> {
+        let mut htlcs: Vec<HoldLookupHtlc> = self
+            .htlc_data
+            .iter()
+            .map(|(ident, htlc)| HoldLookupHtlc {
+                short_channel_id: ident.scid,
+                htlc_id: ident.htlc_id,
+                amount_msat: htlc.amount_msat,
+                cltv_expiry: htlc.cltv_expiry,
+                blocks_until_expiry: htlc.cltv_expiry.saturating_sub(blockheight),
+                arrived_at: htlc.arrived_at,
+                peer_id: htlc.peer_id,
+                channel_issue: health.issue(ident.scid, htlc.peer_id),
+            })
+            .collect();
+        htlcs.sort_by_key(|h| (h.arrived_at, h.short_channel_id.to_string(), h.htlc_id));
+        htlcs
+    }
+}
+
+/// An HTLC of the set that made a holdinvoice ACCEPTED
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HeldHtlcRecord {
+    pub short_channel_id: ShortChannelId,
+    pub htlc_id: u64,
+    pub amount_msat: u64,
+    pub cltv_expiry: u32,
+    pub arrived_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<PublicKey>,
+}
+impl HeldHtlcRecord {
+    pub fn ident(&self) -> HtlcIdentifier {
+        HtlcIdentifier {
+            scid: self.short_channel_id,
+            htlc_id: self.htlc_id,
+        }
+    }
+}
+
+/// The HTLC's of an ACCEPTED holdinvoice. We keep them in the datastore so the
+/// holdinvoice stays ACCEPTED while cln replays them after a restart.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HoldHtlcSet {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_msat: Option<u64>,
+    pub htlcs: Vec<HeldHtlcRecord>,
+}
+
+/// Per-invoice overrides of `holdinvoice-cancel-before-htlc-expiry` and
+/// `holdinvoice-cancel-before-invoice-expiry`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafetyMargins {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancel_before_htlc_expiry: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancel_before_invoice_expiry: Option<u64>,
+}
+
+/// Optional settings of a holdinvoice, stored next to its holdstate
+#[derive(Clone, Debug, Default)]
+pub struct HoldInvoiceOptions {
+    pub webhook_url: Option<String>,
+    pub min_amount_msat: Option<u64>,
+    pub max_overpay: Option<OverpayLimit>,
+    pub safety_margins: Option<SafetyMargins>,
+    pub on_expiry: Option<ExpiryPolicy>,
+    pub metadata: Option<serde_json::Value>,
+}
+
+/// How settle, cancel and lookup address a holdinvoice
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvoiceSelector {
+    PaymentHash(String),
+    Label(String),
+    Bolt11(String),
+}
+impl InvoiceSelector {
+    pub fn name(&self) -> &'static str {
+        match self {
+            InvoiceSelector::PaymentHash(_) => "payment_hash",
+            InvoiceSelector::Label(_) => "label",
+            InvoiceSelector::Bolt11(_) => "bolt11",
+        }
+    }
+    pub fn value(&self) -> &str {
+        match self {
+            InvoiceSelector::PaymentHash(v)
+            | InvoiceSelector::Label(v)
+            | InvoiceSelector::Bolt11(v) => v,
+        }
+    }
+}
+
+/// How much more than the invoice amount we are willing to hold.
+/// Unset fields mean no limit, if both are set the stricter one wins.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OverpayLimit {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percent: Option<u64>,
+}
+impl OverpayLimit {
+    pub fn is_unset(&self) -> bool {
+        self.msat.is_none() && self.percent.is_none()
+    }
+    pub fn cap_msat(&self, amount_msat: u64) -> Option<u64> {
+        let by_msat = self.msat.map(|m| amount_msat.saturating_add(m));
+        let by_percent = self
+            .percent
+            .map(|p| amount_msat.saturating_add(amount_msat.saturating_mul(p) / 100));
+        match (by_msat, by_percent) {
+            (Some(m), Some(p)) => Some(m.min(p)),
+            (m, p) => m.or(p),
+        }
+    }
+}
+
+/// Invoice data of a holdinvoice created with only a payment_hash.
+/// CLN does not know about these, so we keep them in our datastore.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HashOnlyInvoice {
+    pub bolt11: String,
+    pub label: String,
+    pub description: String,
+    pub amount_msat: u64,
+    pub payment_secret: Secret,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub cltv: u32,
+}
+impl HashOnlyInvoice {
+    pub fn to_listinvoices_invoice(&self, payment_hash: Sha256, now: u64) -> ListinvoicesInvoices {
+        ListinvoicesInvoices {
+            amount_msat: Some(Amount::from_msat(self.amount_msat)),
+            amount_received_msat: None,
+            bolt11: Some(self.bolt11.clone()),
+            bolt12: None,
+            created_index: None,
+            description: Some(self.description.clone()),
+            invreq_payer_note: None,
+            local_offer_id: None,
+            paid_at: None,
+            paid_outpoint: None,
+            pay_index: None,
+            payment_preimage: None,
+            updated_index: None,
+            status: if self.expires_at <= now {
+                ListinvoicesInvoicesStatus::EXPIRED
+            } else {
+                ListinvoicesInvoicesStatus::UNPAID
+            },
+            expires_at: self.expires_at,
+            label: self.label.clone(),
+            payment_hash,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldEventType {
+    StateChanged,
+    HtlcAdded,
+    HtlcRemoved,
+}
+impl HoldEventType {
+    pub fn as_i32(&self) -> i32 {
+        match self {
+            HoldEventType::StateChanged => 0,
+            HoldEventType::HtlcAdded => 1,
+            HoldEventType::HtlcRemoved => 2,
+        }
+    }
+}
+
+/// Why a holdinvoice changed its holdstate
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldStateReason {
+    Created,
+    HtlcsComplete,
+    HtlcsMissing,
+    SettleRequested,
+    CancelRequested,
+    AutoSettled,
+    Expired,
+    ChannelUnhealthy,
+}
+impl fmt::Display for HoldStateReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HoldStateReason::Created => write!(f, "created"),
+            HoldStateReason::HtlcsComplete => write!(f, "htlcs_complete"),
+            HoldStateReason::HtlcsMissing => write!(f, "htlcs_missing"),
+            HoldStateReason::SettleRequested => write!(f, "settle_requested"),
+            HoldStateReason::CancelRequested => write!(f, "cancel_requested"),
+            HoldStateReason::AutoSettled => write!(f, "auto_settled"),
+            HoldStateReason::Expired => write!(f, "expired"),
+            HoldStateReason::ChannelUnhealthy => write!(f, "channel_unhealthy"),
+        }
+    }
+}
+
+/// Who or what caused a holdstate transition
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldInitiator {
+    Rpc,
+    Grpc,
+    Htlc,
+    AutoSoftExpiry,
+    AutoHardExpiry,
+    Restart,
+    ChannelMonitor,
+}
+impl fmt::Display for HoldInitiator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HoldInitiator::Rpc => write!(f, "rpc"),
+            HoldInitiator::Grpc => write!(f, "grpc"),
+            HoldInitiator::Htlc => write!(f, "htlc"),
+            HoldInitiator::AutoSoftExpiry => write!(f, "auto_soft_expiry"),
+            HoldInitiator::AutoHardExpiry => write!(f, "auto_hard_expiry"),
+            HoldInitiator::Restart => write!(f, "restart"),
+            HoldInitiator::ChannelMonitor => write!(f, "channel_monitor"),
+        }
+    }
+}
+
+/// One entry of the persistent holdstate history of a holdinvoice
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldTransition {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<Holdstate>,
+    pub to: Holdstate,
+    pub reason: HoldStateReason,
+    pub initiator: HoldInitiator,
+    pub timestamp: u64,
+    pub blockheight: u32,
+    pub amount_held_msat: u64,
+}
+
+impl From<HoldTransition> for pb::HoldTransition {
+    fn from(c: HoldTransition) -> Self {
+        Self {
+            from: c.from.map(|s| s.as_i32()),
+            to: c.to.as_i32(),
+            reason: c.reason.to_string(),
+            initiator: c.initiator.to_string(),
+            timestamp: c.timestamp,
+            blockheight: c.blockheight,
+            amount_held_msat: Some(pb::Amount {
+                msat: c.amount_held_msat,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldEventHtlc {
+    pub short_channel_id: ShortChannelId,
+    pub htlc_id: u64,
+    pub amount_msat: u64,
+    pub cltv_expiry: u32,
+}
+
+/// What a holdstate change tells about its holdinvoice, known where the
+/// change happens so nobody has to ask cln again
+#[derive(Clone, Debug, Default)]
+pub struct HoldEventInvoice {
+    pub label: Option<String>,
+    pub amount_msat: Option<u64>,
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldInvoiceEvent {
+    pub payment_hash: String,
+    pub event_type: HoldEventType,
+    pub state: Holdstate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub htlc: Option<HoldEventHtlc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<HoldStateReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+impl From<HoldInvoiceEvent> for pb::HoldInvoiceEvent {
+    fn from(c: HoldInvoiceEvent) -> Self {
+        Self {
+            payment_hash: hex::decode(c.payment_hash).unwrap(),
+            event_type: c.event_type.as_i32(),
+            state: c.state.as_i32(),
+            htlc: c.htlc.map(|h| pb::HoldInvoiceEventHtlc {
+                short_channel_id: h.short_channel_id.to_string(),
+                htlc_id: h.htlc_id,
+                amount_msat: Some(pb::Amount {
+                    msat: h.amount_msat,
+                }),
+                cltv_expiry: h.cltv_expiry,
+            }),
+            reason: c.reason.map(|r| r.to_string()),
+            metadata: c.metadata.map(|m| m.to_string()),
+        }
+    }
+}
+
+/// Body of a webhook POST
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookEvent {
+    pub event_id: String,
+    pub payment_hash: String,
+    pub state: Holdstate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<HoldStateReason>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    pub timestamp: u64,
+}
+
+/// A webhook POST waiting in the outbox until the endpoint accepted it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    pub url: String,
+    pub body: String,
+    pub attempts: u32,
+    pub next_attempt_at: u64,
+}
+
+pub type SharedHoldInvoice = Arc<Mutex<HoldInvoice>>;
+
+/// Holdinvoices that currently have HTLC's. The map and every holdinvoice have
+/// their own lock. Both are sync and must never be held across an await, so
+/// rpc calls for one holdinvoice never block the others. Lock order is map,
+/// then holdinvoice.
+#[derive(Clone, Debug, Default)]
+pub struct HoldInvoiceStore {
+    invoices: Arc<Mutex<BTreeMap<String, SharedHoldInvoice>>>,
+}
+impl HoldInvoiceStore {
+    pub fn get(&self, pay_hash: &str) -> Option<SharedHoldInvoice> {
+        self.invoices.lock().get(pay_hash).cloned()
+    }
+    pub fn contains(&self, pay_hash: &str) -> bool {
+        self.invoices.lock().contains_key(pay_hash)
+    }
+    pub fn values(&self) -> Vec<SharedHoldInvoice> {
+        self.invoices.lock().values().cloned().collect()
+    }
+    /// Runs `f` on the holdinvoice or on `new` if there is none, `new` is only
+    /// kept if `f` added HTLC's to it. None if there is neither.
+    pub fn update_or_insert<R>(
+        &self,
+        pay_hash: &str,
+        new: Option<HoldInvoice>,
+        f: impl FnOnce(&mut HoldInvoice) -> R,
+    ) -> Option<R> {
+        let mut invoices = self.invoices.lock();
+        if let Some(holdinvoice) = invoices.get(pay_hash) {
+            return Some(f(&mut holdinvoice.lock()));
+        }
+        let mut new = new?;
+        let result = f(&mut new);
+        if !new.htlc_data.is_empty() {
+            invoices.insert(pay_hash.to_owned(), Arc::new(Mutex::new(new)));
+        }
+        Some(result)
+    }
+    /// Inserts restored holdinvoices, the htlc_accepted hook must not run yet
+    pub fn insert(&self, pay_hash: String, holdinvoice: HoldInvoice) {
+        self.invoices
+            .lock()
+            .insert(pay_hash, Arc::new(Mutex::new(holdinvoice)));
+    }
+    /// Removes the htlc and the holdinvoice once it has no HTLC's left. Returns
+    /// the htlc with the holdstate and metadata of its holdinvoice.
+    pub fn remove_htlc(
+        &self,
+        pay_hash: &str,
+        ident: &HtlcIdentifier,
+    ) -> Option<(HoldHtlc, Holdstate, Option<serde_json::Value>)> {
+        let mut invoices = self.invoices.lock();
+        let holdinvoice = invoices.get(pay_hash)?.clone();
+        let mut holdinvoice = holdinvoice.lock();
+        let htlc = holdinvoice.htlc_data.remove(ident);
+        if holdinvoice.htlc_data.is_empty() {
+            invoices.remove(pay_hash);
+        }
+        htlc.map(|h| (h, holdinvoice.hold_state, holdinvoice.metadata.clone()))
+    }
+    /// Wakes the hold loops of the holdinvoice, false if it has no HTLC's
+    pub fn wake(&self, pay_hash: &str) -> bool {
+        match self.get(pay_hash) {
+            Some(holdinvoice) => {
+                holdinvoice.lock().wakeup.send_replace(());
+                true
+            }
+            None => false,
+        }
+    }
+    pub fn wake_all(&self) {
+        for holdinvoice in self.values() {
+            holdinvoice.lock().wakeup.send_replace(());
+        }
+    }
+}
+
+/// After a restart rpc methods that read or change holdstates wait until cln
+/// replayed every htlc it still has for a holdinvoice, or until the deadline
+#[derive(Clone, Debug)]
+pub struct Readiness {
+    replays: Arc<Mutex<PendingReplays>>,
+    ready: tokio::sync::watch::Sender<bool>,
+    deadline: u64,
+}
+
+#[derive(Debug, Default)]
+struct PendingReplays {
+    counted: bool,
+    timed_out: bool,
+    expected: usize,
+    /// the scid and the alias of a replay point to the same entry
+    idents: HashMap<HtlcIdentifier, usize>,
+    pending: HashMap<usize, String>,
+}
+
+impl Readiness {
+    pub fn new(timeout: u64) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Readiness {
+            replays: Arc::new(Mutex::new(PendingReplays::default())),
+            ready: tokio::sync::watch::channel(false).0,
+            deadline: now + timeout,
+        }
+    }
+
+    /// Sets the replays cln still has to send, each with all identifiers it
+    /// can arrive with and its payment_hash
+    pub fn expect(&self, replays: Vec<(Vec<HtlcIdentifier>, String)>) {
+        let mut pending = self.replays.lock();
+        pending.counted = true;
+        pending.expected = replays.len();
+        for (id, (idents, pay_hash)) in replays.into_iter().enumerate() {
+            for ident in idents {
+                pending.idents.insert(ident, id);
+            }
+            pending.pending.insert(id, pay_hash);
+        }
+        if pending.pending.is_empty() {
+            self.ready.send_replace(true);
+        }
+    }
+
+    /// True if the htlc is a replay we were waiting for
+    pub fn replayed(&self, ident: &HtlcIdentifier) -> bool {
+        let mut pending = self.replays.lock();
+        let Some(id) = pending.idents.remove(ident) else {
+            return false;
+        };
+        pending.idents.retain(|_, i| *i != id);
+        pending.pending.remove(&id);
+        if pending.pending.is_empty() {
+            self.ready.send_replace(true);
+        }
+        true
+    }
+
+    pub fn time_out(&self) {
+        let mut pending = self.replays.lock();
+        if !self.is_ready() {
+            pending.timed_out = true;
+            self.ready.send_replace(true);
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        *self.ready.borrow()
+    }
+
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+
+    pub async fn wait(&self) {
+        let mut ready = self.ready.subscribe();
+        // the sender lives as long as the plugin state
+        let _ = ready.wait_for(|r| *r).await;
+    }
+
+    pub fn status(&self) -> HoldStatusResponse {
+        let pending = self.replays.lock();
+        HoldStatusResponse {
+            ready: self.is_ready(),
+            replays_counted: pending.counted,
+            timed_out: pending.timed_out,
+            expected_htlcs: pending.expected as u64,
+            pending_htlcs: pending.pending.len() as u64,
+            pending_payment_hashes: pending
+                .pending
+                .values()
+                .cloned()
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .collect(),
+            deadline: self.deadline,
+        }
+    }
+}
+
+/// Offer ids of the hold offers, loaded on startup and extended by `holdoffer`.
+/// HTLC's only have to ask cln about their invoice's offer while there are any.
+#[derive(Clone, Debug, Default)]
+pub struct HoldOffers {
+    offer_ids: Arc<Mutex<HashSet<String>>>,
+}
+impl HoldOffers {
+    pub fn replace(&self, offer_ids: HashSet<String>) {
+        *self.offer_ids.lock() = offer_ids;
+    }
+    pub fn insert(&self, offer_id: String) {
+        self.offer_ids.lock().insert(offer_id);
+    }
+    pub fn contains(&self, offer_id: &str) -> bool {
+        self.offer_ids.lock().contains(offer_id)
+    }
+    pub fn is_empty(&self) -> bool {
+        self.offer_ids.lock().is_empty()
+    }
+}
+
+/// Peers and channels of held HTLC's that can't resolve them off-chain, kept
+/// up to date by cln's connect, disconnect and channel_state_changed
+/// notifications
+#[derive(Clone, Debug)]
+pub struct ChannelHealth {
+    pub policy: ChannelHealthPolicy,
+    /// replaces a smaller cancel_before_htlc_expiry of unhealthy HTLC's with
+    /// the tighten policy
+    pub unhealthy_cancel_before_htlc_expiry: u32,
+    disconnected: Arc<Mutex<HashSet<PublicKey>>>,
+    closing: Arc<Mutex<HashSet<ShortChannelId>>>,
+}
+
+impl ChannelHealth {
+    pub fn new(policy: ChannelHealthPolicy, unhealthy_cancel_before_htlc_expiry: u32) -> Self {
+        ChannelHealth {
+            policy,
+            unhealthy_cancel_before_htlc_expiry,
+            disconnected: Arc::new(Mutex::new(HashSet::new())),
+            closing: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// True if this changed what we knew about the peer
+    pub fn set_peer_connected(&self, peer_id: PublicKey, connected: bool) -> bool {
+        let mut disconnected = self.disconnected.lock();
+        if connected {
+            disconnected.remove(&peer_id)
+        } else {
+            disconnected.insert(peer_id)
+        }
+    }
+
+    /// True if this changed what we knew about the channel, `scids` are the
+    /// scid and the alias of the same channel
+    pub fn set_channel_closing(&self, scids: &[ShortChannelId], closing: bool) -> bool {
+        let mut closing_channels = self.closing.lock();
+        let mut changed = false;
+        for scid in scids {
+            changed |= if closing {
+                closing_channels.insert(*scid)
+            } else {
+                closing_channels.remove(scid)
+            };
+        }
+        changed
+    }
+
+    pub fn issue(&self, scid: ShortChannelId, peer_id: Option<PublicKey>) -> Option<ChannelIssue> {
+        if self.closing.lock().contains(&scid) {
+            Some(ChannelIssue::ChannelClosing)
+        } else if peer_id.is_some_and(|p| self.disconnected.lock().contains(&p)) {
+            Some(ChannelIssue::PeerDisconnected)
+        } else {
+            None
+        }
+    }
+
+    /// True if any HTLC of the holdinvoice is on an unhealthy channel
+    pub fn affects(&self, holdinvoice: &HoldInvoice) -> bool {
+        holdinvoice
+            .htlc_data
+            .iter()
+            .any(|(ident, htlc)| self.issue(ident.scid, htlc.peer_id).is_some())
+    }
+}
+
+/// HTLC's on channels in these states have to be resolved on-chain
+pub fn is_closing(state: ChannelState) -> bool {
+    matches!(
+        state,
+        ChannelState::AWAITING_UNILATERAL
+            | ChannelState::FUNDING_SPEND_SEEN
+            | ChannelState::ONCHAIN
+    )
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldStatusResponse {
+    pub ready: bool,
+    pub replays_counted: bool,
+    pub timed_out: bool,
+    pub expected_htlcs: u64,
+    pub pending_htlcs: u64,
+    pub pending_payment_hashes: Vec<String>,
+    pub deadline: u64,
+}
+
+impl From<HoldStatusResponse> for pb::HoldInvoiceStatusResponse {
+    fn from(c: HoldStatusResponse) -> Self {
+        Self {
+            ready: c.ready,
+            replays_counted: c.replays_counted,
+            timed_out: c.timed_out,
+            expected_htlcs: c.expected_htlcs,
+            pending_htlcs: c.pending_htlcs,
+            pending_payment_hashes: c
+                .pending_payment_hashes
+                .into_iter()
+                .map(|p| hex::decode(p).unwrap())
+                .collect(),
+            deadline: c.deadline,
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct PluginState {
     pub blockheight: Arc<Mutex<u32>>,
-    pub holdinvoices: Arc<tokio::sync::Mutex<BTreeMap<String, HoldInvoice>>>,
+    pub holdinvoices: HoldInvoiceStore,
     pub identity: Identity,
     pub ca_cert: Vec<u8>,
-    pub startup_lock: Arc<Mutex<bool>>,
-    pub rpc: Arc<tokio::sync::Mutex<ClnRpc>>,
+    pub readiness: Readiness,
+    /// held for writing until the accepted htlc sets are restored on startup
+    pub restore_lock: Arc<tokio::sync::RwLock<()>>,
+    pub rpc_pool: Arc<RpcPool>,
+    pub holdstates: HoldStateCache,
+    pub hold_offers: HoldOffers,
+    pub channel_health: ChannelHealth,
+    /// one autoclean pass at a time, from the background task or the rpc
+    pub autoclean_lock: Arc<tokio::sync::Mutex<()>>,
+    pub events: tokio::sync::broadcast::Sender<HoldInvoiceEvent>,
+    /// new webhooks in the outbox
+    pub webhook_wakeup: Arc<tokio::sync::Notify>,
+    pub metrics: Arc<HoldMetrics>,
 }
 
 fn is_none_or_empty<T>(f: &Option<Vec<T>>) -> bool
 where
     T: Clone,
 {
-    f.as_ref().map_or(true, |value| value.is_empty())
+    f.as_ref().is_none_or(|value| value.is_empty())
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct HoldInvoiceRequest {
-    pub amount_msat: u64,
+    pub amount_msat: AmountOrAny,
     pub description: String,
     pub label: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -126,15 +947,42 @@ pub struct HoldInvoiceRequest {
     pub cltv: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub deschashonly: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payment_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_amount_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_overpay_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_overpay_percent: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancel_before_htlc_expiry: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cancel_before_invoice_expiry: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub on_expiry: Option<ExpiryPolicy>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
 }
 
 #[allow(unused_variables, deprecated)]
 impl From<HoldInvoiceRequest> for pb::HoldInvoiceRequest {
     fn from(c: HoldInvoiceRequest) -> Self {
         Self {
-            amount_msat: Some(pb::Amount {
-                msat: c.amount_msat,
-            }), // Rule #2 for type msat_or_any
+            amount_msat: match c.amount_msat {
+                AmountOrAny::Amount(a) => Some(pb::Amount { msat: a.msat() }),
+                AmountOrAny::Any => None,
+            }, // Rule #2 for type msat_or_any
+            any_amount: Some(matches!(c.amount_msat, AmountOrAny::Any)),
+            min_amount_msat: c.min_amount_msat.map(|msat| pb::Amount { msat }),
+            max_overpay_msat: c.max_overpay_msat.map(|msat| pb::Amount { msat }),
+            max_overpay_percent: c.max_overpay_percent,
+            cancel_before_htlc_expiry: c.cancel_before_htlc_expiry,
+            cancel_before_invoice_expiry: c.cancel_before_invoice_expiry,
+            on_expiry: c.on_expiry.map(|p| p.as_i32()),
+            metadata: c.metadata.map(|m| m.to_string()),
             description: c.description, // Rule #2 for type string
             label: c.label,             // Rule #2 for type string
             expiry: c.expiry,           // Rule #2 for type u64?
@@ -150,18 +998,43 @@ impl From<HoldInvoiceRequest> for pb::HoldInvoiceRequest {
             preimage: c.preimage.map(|v| hex::decode(v).unwrap()), // Rule #2 for type hex?
             cltv: c.cltv,                                          // Rule #2 for type u32?
             deschashonly: c.deschashonly,                          // Rule #2 for type boolean?
+            payment_hash: c.payment_hash.map(|v| hex::decode(v).unwrap()), // Rule #2 for type hex?
+            webhook_url: c.webhook_url,                            // Rule #2 for type string?
         }
     }
 }
 #[allow(unused_variables, deprecated)]
-impl From<pb::HoldInvoiceRequest> for HoldInvoiceRequest {
-    fn from(c: pb::HoldInvoiceRequest) -> Self {
-        Self {
-            amount_msat: if let Some(amount) = c.amount_msat {
-                amount.msat
-            } else {
-                0
-            },
+impl TryFrom<pb::HoldInvoiceRequest> for HoldInvoiceRequest {
+    type Error = tonic::Status;
+
+    fn try_from(c: pb::HoldInvoiceRequest) -> Result<Self, tonic::Status> {
+        let amount_msat = if c.any_amount.unwrap_or(false) {
+            AmountOrAny::Any
+        } else if let Some(a) = c.amount_msat {
+            AmountOrAny::Amount(Amount::from_msat(a.msat))
+        } else {
+            return Err(tonic::Status::invalid_argument(
+                "amount_msat is required unless any_amount is set",
+            ));
+        };
+        Ok(Self {
+            amount_msat,
+            min_amount_msat: c.min_amount_msat.map(|a| a.msat),
+            max_overpay_msat: c.max_overpay_msat.map(|a| a.msat),
+            max_overpay_percent: c.max_overpay_percent,
+            cancel_before_htlc_expiry: c.cancel_before_htlc_expiry,
+            cancel_before_invoice_expiry: c.cancel_before_invoice_expiry,
+            on_expiry: c
+                .on_expiry
+                .and_then(|p| match pb::ExpiryPolicy::try_from(p) {
+                    Ok(pb::ExpiryPolicy::Settle) => Some(ExpiryPolicy::Settle),
+                    Ok(pb::ExpiryPolicy::Cancel) => Some(ExpiryPolicy::Cancel),
+                    Err(_) => None,
+                }),
+            // invalid json is passed on as a string and rejected by holdinvoice
+            metadata: c
+                .metadata
+                .map(|m| serde_json::from_str(&m).unwrap_or(serde_json::Value::String(m))),
             description: c.description, // Rule #1 for type string
             label: c.label,             // Rule #1 for type string
             expiry: c.expiry,           // Rule #1 for type u64?
@@ -175,7 +1048,9 @@ impl From<pb::HoldInvoiceRequest> for HoldInvoiceRequest {
             preimage: c.preimage.map(hex::encode), // Rule #1 for type hex?
             cltv: c.cltv,               // Rule #1 for type u32?
             deschashonly: c.deschashonly, // Rule #1 for type boolean?
-        }
+            payment_hash: c.payment_hash.map(hex::encode), // Rule #1 for type hex?
+            webhook_url: c.webhook_url, // Rule #1 for type string?
+        })
     }
 }
 
@@ -222,9 +1097,421 @@ pub struct HoldLookupResponse {
     pub state: Holdstate,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub htlc_expiry: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_accepted_msat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offer_id: Option<Sha256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payer_note: Option<String>,
+    pub on_expiry: ExpiryPolicy,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+    pub htlcs: Vec<HoldLookupHtlc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_transition: Option<HoldTransition>,
+    /// true if any held HTLC has a `channel_issue`
+    pub channel_unhealthy: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldLookupHtlc {
+    pub short_channel_id: ShortChannelId,
+    pub htlc_id: u64,
+    pub amount_msat: u64,
+    pub cltv_expiry: u32,
+    pub blocks_until_expiry: u32,
+    pub arrived_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer_id: Option<PublicKey>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_issue: Option<ChannelIssue>,
+}
+
+impl From<HoldLookupHtlc> for pb::HeldHtlc {
+    fn from(c: HoldLookupHtlc) -> Self {
+        Self {
+            short_channel_id: c.short_channel_id.to_string(),
+            htlc_id: c.htlc_id,
+            amount_msat: Some(pb::Amount {
+                msat: c.amount_msat,
+            }),
+            cltv_expiry: c.cltv_expiry,
+            blocks_until_expiry: c.blocks_until_expiry,
+            arrived_at: c.arrived_at,
+            peer_id: c.peer_id.map(|p| p.serialize().to_vec()),
+            channel_issue: c.channel_issue.map(|i| i.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldHistoryResponse {
+    pub history: Vec<HoldTransition>,
+}
+
+impl From<HoldHistoryResponse> for pb::HoldInvoiceHistoryResponse {
+    fn from(c: HoldHistoryResponse) -> Self {
+        Self {
+            history: c.history.into_iter().map(|t| t.into()).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldOfferResponse {
+    pub offer_id: Sha256,
+    pub bolt12: String,
+}
+
+impl From<HoldOfferResponse> for pb::HoldOfferResponse {
+    fn from(c: HoldOfferResponse) -> Self {
+        Self {
+            offer_id: <Sha256 as AsRef<[u8]>>::as_ref(&c.offer_id).to_vec(),
+            bolt12: c.bolt12,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HoldStateResponse {
     pub state: Holdstate,
 }
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldBulkResult {
+    pub payment_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<Holdstate>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldBulkResponse {
+    pub results: Vec<HoldBulkResult>,
+}
+
+impl From<HoldBulkResult> for pb::HoldInvoiceBulkResult {
+    fn from(c: HoldBulkResult) -> Self {
+        Self {
+            payment_hash: hex::decode(c.payment_hash).unwrap(),
+            state: c.state.map(|s| s.as_i32()),
+            error: c.error.map(|e| {
+                e.get("message")
+                    .and_then(|m| m.as_str())
+                    .map(|m| m.to_owned())
+                    .unwrap_or_else(|| e.to_string())
+            }),
+        }
+    }
+}
+
+impl From<HoldBulkResponse> for pb::HoldInvoiceSettleManyResponse {
+    fn from(c: HoldBulkResponse) -> Self {
+        Self {
+            results: c.results.into_iter().map(|r| r.into()).collect(),
+        }
+    }
+}
+
+impl From<HoldBulkResponse> for pb::HoldInvoiceCancelManyResponse {
+    fn from(c: HoldBulkResponse) -> Self {
+        Self {
+            results: c.results.into_iter().map(|r| r.into()).collect(),
+        }
+    }
+}
+
+/// Why autoclean removed a holdinvoice
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AutocleanReason {
+    /// cln's invoice is already gone
+    Orphaned,
+    /// older than the configured retention for its holdstate
+    Retention,
+}
+impl fmt::Display for AutocleanReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AutocleanReason::Orphaned => write!(f, "orphaned"),
+            AutocleanReason::Retention => write!(f, "retention"),
+        }
+    }
+}
+
+/// Retention settings of autoclean, ages are in seconds and None keeps forever
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AutocleanRules {
+    pub settled_age: Option<u64>,
+    pub canceled_age: Option<u64>,
+    pub delinvoice: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldAutocleanEntry {
+    pub payment_hash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<Holdstate>,
+    pub reason: AutocleanReason,
+    pub invoice_deleted: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldAutocleanResponse {
+    pub removed: Vec<HoldAutocleanEntry>,
+}
+
+impl From<HoldAutocleanEntry> for pb::HoldInvoiceAutocleanEntry {
+    fn from(c: HoldAutocleanEntry) -> Self {
+        Self {
+            payment_hash: hex::decode(c.payment_hash).unwrap(),
+            state: c.state.map(|s| s.as_i32()),
+            reason: c.reason.to_string(),
+            invoice_deleted: c.invoice_deleted,
+        }
+    }
+}
+
+impl From<HoldAutocleanResponse> for pb::HoldInvoiceAutocleanResponse {
+    fn from(c: HoldAutocleanResponse) -> Self {
+        Self {
+            removed: c.removed.into_iter().map(|r| r.into()).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HoldInvoiceListFilter {
+    pub state: Option<Holdstate>,
+    pub label_prefix: Option<String>,
+    pub created_after: Option<u64>,
+    pub created_before: Option<u64>,
+    pub start: Option<u64>,
+    pub limit: Option<u32>,
+}
+impl HoldInvoiceListFilter {
+    pub fn matches_invoice(&self, label: &str, created_at: Option<u64>) -> bool {
+        if let Some(prefix) = &self.label_prefix {
+            if !label.starts_with(prefix) {
+                return false;
+            }
+        }
+        if let Some(after) = self.created_after {
+            if created_at.is_none_or(|c| c < after) {
+                return false;
+            }
+        }
+        if let Some(before) = self.created_before {
+            if created_at.is_none_or(|c| c >= before) {
+                return false;
+            }
+        }
+        true
+    }
+    pub fn is_limit_reached(&self, count: usize) -> bool {
+        self.limit.is_some_and(|l| count >= l as usize)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldInvoiceListEntry {
+    pub payment_hash: Sha256,
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bolt11: Option<String>,
+    pub state: Holdstate,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub amount_msat: Option<u64>,
+    pub amount_held_msat: u64,
+    pub expires_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_index: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offer_id: Option<Sha256>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payer_note: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<serde_json::Value>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HoldInvoiceListResponse {
+    pub holdinvoices: Vec<HoldInvoiceListEntry>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_start: Option<u64>,
+}
+
+impl From<HoldInvoiceListEntry> for pb::ListHoldInvoicesHoldinvoices {
+    fn from(c: HoldInvoiceListEntry) -> Self {
+        Self {
+            payment_hash: <Sha256 as AsRef<[u8]>>::as_ref(&c.payment_hash).to_vec(),
+            label: c.label,
+            bolt11: c.bolt11,
+            state: c.state.as_i32(),
+            amount_msat: c.amount_msat.map(|msat| pb::Amount { msat }),
+            amount_held_msat: Some(pb::Amount {
+                msat: c.amount_held_msat,
+            }),
+            expires_at: c.expires_at,
+            created_index: c.created_index,
+            created_at: c.created_at,
+            offer_id: c
+                .offer_id
+                .map(|o| <Sha256 as AsRef<[u8]>>::as_ref(&o).to_vec()),
+            payer_note: c.payer_note,
+            metadata: c.metadata.map(|m| m.to_string()),
+        }
+    }
+}
+
+impl From<HoldInvoiceListResponse> for pb::ListHoldInvoicesResponse {
+    fn from(c: HoldInvoiceListResponse) -> Self {
+        Self {
+            holdinvoices: c.holdinvoices.into_iter().map(|i| i.into()).collect(),
+            next_start: c.next_start,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{sync::Barrier, time};
+
+    use super::*;
+
+    const INVOICES: u8 = 8;
+    const HTLCS: u64 = 16;
+
+    fn holdinvoice(pay_hash: &str) -> HoldInvoice {
+        let hash_only = HashOnlyInvoice {
+            bolt11: String::new(),
+            label: pay_hash.to_owned(),
+            description: String::new(),
+            amount_msat: 1_000 * HTLCS,
+            payment_secret: Secret::try_from(vec![0u8; 32]).unwrap(),
+            created_at: 0,
+            expires_at: u64::MAX,
+            cltv: 144,
+        };
+        HoldInvoice {
+            hold_state: Holdstate::Open,
+            generation: 0,
+            htlc_data: HashMap::new(),
+            invoice: hash_only.to_listinvoices_invoice(Sha256::from_str(pay_hash).unwrap(), 0),
+            hash_only: Some(hash_only),
+            min_amount_msat: None,
+            total_msat: None,
+            max_overpay: OverpayLimit::default(),
+            cancel_before_htlc_expiry: 6,
+            cancel_before_invoice_expiry: 60,
+            on_expiry: ExpiryPolicy::Settle,
+            metadata: None,
+            wakeup: tokio::sync::watch::channel(()).0,
+        }
+    }
+
+    #[test]
+    fn any_amount_waits_for_total_msat() {
+        let mut h = holdinvoice(&hex::encode([0u8; 32]));
+        assert_eq!(h.accept_threshold_msat(), 1_000 * HTLCS);
+
+        h.invoice.amount_msat = None;
+        assert_eq!(h.accept_threshold_msat(), 1);
+        h.min_amount_msat = Some(5_000);
+        assert_eq!(h.accept_threshold_msat(), 5_000);
+        // reaching min_amount_msat is not enough while parts are missing
+        h.total_msat = Some(8_000);
+        assert_eq!(h.accept_threshold_msat(), 8_000);
+        h.min_amount_msat = None;
+        assert_eq!(h.accept_threshold_msat(), 8_000);
+    }
+
+    /// Every htlc task adds its htlc, waits for all others and removes it
+    /// again. The htlcs of the first holdinvoice wait for a slow datastore
+    /// write in between, which must not hold up the other holdinvoices.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_htlcs_with_slow_write() {
+        let store = HoldInvoiceStore::default();
+        let added = Arc::new(Barrier::new(usize::from(INVOICES) * HTLCS as usize + 1));
+        let checked = Arc::new(Barrier::new(usize::from(INVOICES) * HTLCS as usize + 1));
+        let mut slow = Vec::new();
+        let mut fast = Vec::new();
+        for invoice in 0..INVOICES {
+            let pay_hash = hex::encode([invoice; 32]);
+            for htlc_id in 0..HTLCS {
+                let (store, added, checked, pay_hash) = (
+                    store.clone(),
+                    added.clone(),
+                    checked.clone(),
+                    pay_hash.clone(),
+                );
+                let task = tokio::spawn(async move {
+                    let ident = HtlcIdentifier {
+                        scid: ShortChannelId::from_str("1x2x3").unwrap(),
+                        htlc_id,
+                    };
+                    store.update_or_insert(&pay_hash, Some(holdinvoice(&pay_hash)), |h| {
+                        h.htlc_data.insert(
+                            ident,
+                            HoldHtlc {
+                                amount_msat: 1_000,
+                                cltv_expiry: 800_000,
+                                arrived_at: 0,
+                                peer_id: None,
+                            },
+                        );
+                        if h.amount_held_msat() >= h.accept_threshold_msat() {
+                            h.hold_state = Holdstate::Accepted;
+                        }
+                    });
+                    added.wait().await;
+                    checked.wait().await;
+
+                    if invoice == 0 {
+                        time::sleep(Duration::from_millis(500)).await;
+                    }
+                    assert!(store.wake(&pay_hash));
+                    store.remove_htlc(&pay_hash, &ident)
+                });
+                if invoice == 0 {
+                    slow.push(task);
+                } else {
+                    fast.push(task);
+                }
+            }
+        }
+
+        time::timeout(Duration::from_secs(5), added.wait())
+            .await
+            .expect("htlcs deadlocked while being added");
+        assert_eq!(store.values().len(), usize::from(INVOICES));
+        for holdinvoice in store.values() {
+            let h = holdinvoice.lock();
+            assert_eq!(h.htlc_data.len(), HTLCS as usize);
+            assert_eq!(h.hold_state, Holdstate::Accepted);
+        }
+        checked.wait().await;
+
+        for task in fast {
+            let removed = time::timeout(Duration::from_millis(250), task)
+                .await
+                .expect("htlcs waited for the slow holdinvoice")
+                .unwrap();
+            assert_eq!(removed.unwrap().1, Holdstate::Accepted);
+        }
+        assert_eq!(store.values().len(), 1);
+        for task in slow {
+            let removed = time::timeout(Duration::from_secs(5), task)
+                .await
+                .expect("slow holdinvoice deadlocked")
+                .unwrap();
+            assert!(removed.is_some());
+        }
+        assert!(store.values().is_empty());
+    }
+}
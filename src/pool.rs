@@ -0,0 +1,152 @@
+//! Bounded pool of connections to cln's rpc socket. Opening a connection per
+//! call runs into the OS limits under load and a single shared connection
+//! serialises everything.
+use std::{
+    fmt::Debug,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Error;
+use cln_rpc::{ClnRpc, RpcError, TypedRequest};
+use log::debug;
+use parking_lot::Mutex;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics::HoldMetrics;
+
+/// Connections idle for longer than this are checked before they are reused
+const RPC_POOL_MAX_IDLE: Duration = Duration::from_secs(300);
+
+pub struct RpcPool {
+    rpc_path: PathBuf,
+    size: usize,
+    idle: Mutex<Vec<(ClnRpc, Instant)>>,
+    permits: Arc<Semaphore>,
+    open: AtomicUsize,
+    metrics: Arc<HoldMetrics>,
+}
+
+impl RpcPool {
+    pub fn new(rpc_path: PathBuf, size: usize, metrics: Arc<HoldMetrics>) -> Self {
+        RpcPool {
+            rpc_path,
+            size,
+            idle: Mutex::new(Vec::with_capacity(size)),
+            permits: Arc::new(Semaphore::new(size)),
+            open: AtomicUsize::new(0),
+            metrics,
+        }
+    }
+
+    /// Waits for a free connection, the connection goes back to the pool on drop
+    pub async fn get(self: &Arc<Self>) -> Result<PooledRpc, Error> {
+        let started = Instant::now();
+        let permit = self.permits.clone().acquire_owned().await?;
+        self.metrics.observe_rpc_pool_wait(started.elapsed());
+
+        loop {
+            let idle = self.idle.lock().pop();
+            match idle {
+                Some((mut rpc, idle_since)) => {
+                    if idle_since.elapsed() < RPC_POOL_MAX_IDLE || is_healthy(&mut rpc).await {
+                        return Ok(self.pooled(rpc, permit));
+                    }
+                    debug!("dropping unhealthy rpc connection");
+                    self.open.fetch_sub(1, Ordering::Relaxed);
+                }
+                None => {
+                    let rpc = ClnRpc::new(&self.rpc_path).await?;
+                    self.open.fetch_add(1, Ordering::Relaxed);
+                    return Ok(self.pooled(rpc, permit));
+                }
+            }
+        }
+    }
+
+    fn pooled(self: &Arc<Self>, rpc: ClnRpc, permit: OwnedSemaphorePermit) -> PooledRpc {
+        PooledRpc {
+            rpc: Some(rpc),
+            pool: self.clone(),
+            unusable: false,
+            _permit: permit,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.size - self.permits.available_permits()
+    }
+}
+
+/// cln may have closed a connection that sat idle for long. `check` only
+/// validates a command, so anything but our own answer means it must go.
+async fn is_healthy(rpc: &mut ClnRpc) -> bool {
+    match rpc
+        .call_raw::<serde_json::Value, _>("check", &json!({"command_to_check": "getinfo"}))
+        .await
+    {
+        Ok(v) => v.get("command_to_check").and_then(|c| c.as_str()) == Some("getinfo"),
+        Err(_) => false,
+    }
+}
+
+/// All calls go through this wrapper so a connection that broke or still has
+/// the response of a cancelled call on its socket is not reused
+pub struct PooledRpc {
+    rpc: Option<ClnRpc>,
+    pool: Arc<RpcPool>,
+    /// set while a call is in flight and after transport errors
+    unusable: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledRpc {
+    pub async fn call_typed<R>(&mut self, request: &R) -> Result<R::Response, RpcError>
+    where
+        R: TypedRequest + Serialize + Debug,
+        R::Response: DeserializeOwned + Debug,
+    {
+        self.call_raw(request.method(), request).await
+    }
+
+    pub async fn call_raw<R, P>(&mut self, method: &str, params: &P) -> Result<R, RpcError>
+    where
+        P: Serialize + Debug,
+        R: DeserializeOwned + Debug,
+    {
+        // stays set if the future is dropped before cln answered
+        self.unusable = true;
+        let result = self.rpc.as_mut().unwrap().call_raw(method, params).await;
+        // errors from cln have a code, without one it was the connection or
+        // a response we could not read
+        self.unusable = matches!(&result, Err(e) if e.code.is_none());
+        result
+    }
+}
+
+impl Drop for PooledRpc {
+    fn drop(&mut self) {
+        if let Some(rpc) = self.rpc.take() {
+            if self.unusable {
+                debug!("dropping rpc connection after an interrupted or failed call");
+                self.pool.open.fetch_sub(1, Ordering::Relaxed);
+            } else {
+                self.pool.idle.lock().push((rpc, Instant::now()));
+            }
+        }
+    }
+}
@@ -1,23 +1,68 @@
+use std::{collections::HashSet, str::FromStr};
+
 use anyhow::anyhow;
 use cln_plugin::Error;
 use cln_rpc::{
     model::{
-        requests::{DatastoreMode, DatastoreRequest, DeldatastoreRequest, ListdatastoreRequest},
+        requests::{
+            DatastoreMode,
+            DatastoreRequest,
+            DeldatastoreRequest,
+            ListdatastoreRequest,
+            ListinvoicesRequest,
+        },
         responses::{
             DatastoreResponse,
-            DeldatastoreResponse,
             ListdatastoreDatastore,
             ListdatastoreResponse,
+            ListinvoicesInvoices,
         },
     },
-    ClnRpc,
     RpcError,
 };
 
-use crate::model::{HOLD_INVOICE_DATASTORE_STATE, HOLD_INVOICE_PLUGIN_NAME};
+use crate::{
+    bolt11::bolt11_payment_hash,
+    model::{
+        ExpiryPolicy,
+        HashOnlyInvoice,
+        HoldHtlcSet,
+        HoldInvoiceOptions,
+        HoldOffers,
+        HoldTransition,
+        InvoiceSelector,
+        OverpayLimit,
+        SafetyMargins,
+        WebhookDelivery,
+        HOLD_INVOICE_DATASTORE_HISTORY,
+        HOLD_INVOICE_DATASTORE_HTLCS,
+        HOLD_INVOICE_DATASTORE_INVOICE,
+        HOLD_INVOICE_DATASTORE_MAX_OVERPAY,
+        HOLD_INVOICE_DATASTORE_METADATA,
+        HOLD_INVOICE_DATASTORE_MIN_AMOUNT,
+        HOLD_INVOICE_DATASTORE_ON_EXPIRY,
+        HOLD_INVOICE_DATASTORE_PREIMAGE,
+        HOLD_INVOICE_DATASTORE_SAFETY_MARGINS,
+        HOLD_INVOICE_DATASTORE_STATE,
+        HOLD_INVOICE_DATASTORE_WEBHOOK_URL,
+        HOLD_INVOICE_PLUGIN_NAME,
+        HOLD_LABELS,
+        HOLD_OFFERS,
+        HOLD_WEBHOOK_OUTBOX,
+    },
+    pool::PooledRpc,
+};
+
+fn holdstate_key(pay_hash: String) -> Vec<String> {
+    vec![
+        HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+        pay_hash,
+        HOLD_INVOICE_DATASTORE_STATE.to_owned(),
+    ]
+}
 
 pub async fn datastore_new_state(
-    rpc: &mut ClnRpc,
+    rpc: &mut PooledRpc,
     pay_hash: String,
     string: String,
 ) -> Result<DatastoreResponse, RpcError> {
@@ -26,17 +71,13 @@ pub async fn datastore_new_state(
         hex: None,
         mode: Some(DatastoreMode::MUST_CREATE),
         string: Some(string),
-        key: vec![
-            HOLD_INVOICE_PLUGIN_NAME.to_owned(),
-            pay_hash,
-            HOLD_INVOICE_DATASTORE_STATE.to_owned(),
-        ],
+        key: holdstate_key(pay_hash),
     })
     .await
 }
 
 pub async fn datastore_update_state(
-    rpc: &mut ClnRpc,
+    rpc: &mut PooledRpc,
     pay_hash: String,
     string: String,
     generation: u64,
@@ -46,17 +87,13 @@ pub async fn datastore_update_state(
         hex: None,
         mode: Some(DatastoreMode::MUST_REPLACE),
         string: Some(string),
-        key: vec![
-            HOLD_INVOICE_PLUGIN_NAME.to_owned(),
-            pay_hash,
-            HOLD_INVOICE_DATASTORE_STATE.to_owned(),
-        ],
+        key: holdstate_key(pay_hash),
     })
     .await
 }
 
 pub async fn datastore_update_state_forced(
-    rpc: &mut ClnRpc,
+    rpc: &mut PooledRpc,
     pay_hash: String,
     string: String,
 ) -> Result<DatastoreResponse, RpcError> {
@@ -65,33 +102,32 @@ pub async fn datastore_update_state_forced(
         hex: None,
         mode: Some(DatastoreMode::MUST_REPLACE),
         string: Some(string),
-        key: vec![
-            HOLD_INVOICE_PLUGIN_NAME.to_owned(),
-            pay_hash,
-            HOLD_INVOICE_DATASTORE_STATE.to_owned(),
-        ],
+        key: holdstate_key(pay_hash),
     })
     .await
 }
 
-pub async fn listdatastore_all(rpc: &mut ClnRpc) -> Result<ListdatastoreResponse, RpcError> {
-    rpc.call_typed(&ListdatastoreRequest {
-        key: Some(vec![HOLD_INVOICE_PLUGIN_NAME.to_owned()]),
-    })
-    .await
+/// All holdinvoice entries, one per payment_hash. Other data we keep under
+/// our prefix (e.g. the webhook outbox) is skipped.
+pub async fn listdatastore_all(rpc: &mut PooledRpc) -> Result<ListdatastoreResponse, RpcError> {
+    let mut response = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(vec![HOLD_INVOICE_PLUGIN_NAME.to_owned()]),
+        })
+        .await?;
+    response
+        .datastore
+        .retain(|d| d.key.get(1).is_some_and(|k| k.len() == 64));
+    Ok(response)
 }
 
 pub async fn listdatastore_state(
-    rpc: &mut ClnRpc,
+    rpc: &mut PooledRpc,
     pay_hash: String,
 ) -> Result<ListdatastoreDatastore, Error> {
     let response = rpc
         .call_typed(&ListdatastoreRequest {
-            key: Some(vec![
-                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
-                pay_hash.clone(),
-                HOLD_INVOICE_DATASTORE_STATE.to_owned(),
-            ]),
+            key: Some(holdstate_key(pay_hash.clone())),
         })
         .await?;
     let data = response.datastore.first().ok_or_else(|| {
@@ -103,17 +139,632 @@ pub async fn listdatastore_state(
     Ok(data.clone())
 }
 
-pub async fn del_datastore_state(
-    rpc: &mut ClnRpc,
+pub async fn datastore_new_invoice(
+    rpc: &mut PooledRpc,
     pay_hash: String,
-) -> Result<DeldatastoreResponse, RpcError> {
-    rpc.call_typed(&DeldatastoreRequest {
+    invoice: &HashOnlyInvoice,
+) -> Result<DatastoreResponse, Error> {
+    Ok(rpc
+        .call_typed(&DatastoreRequest {
+            generation: None,
+            hex: None,
+            mode: Some(DatastoreMode::MUST_CREATE),
+            string: Some(serde_json::to_string(invoice)?),
+            key: vec![
+                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                pay_hash,
+                HOLD_INVOICE_DATASTORE_INVOICE.to_owned(),
+            ],
+        })
+        .await?)
+}
+
+/// Index of the labels of hash-only holdinvoices, cln does not know them
+pub async fn datastore_new_label(
+    rpc: &mut PooledRpc,
+    label: String,
+    pay_hash: String,
+) -> Result<DatastoreResponse, RpcError> {
+    rpc.call_typed(&DatastoreRequest {
+        generation: None,
+        hex: None,
+        mode: Some(DatastoreMode::MUST_CREATE),
+        string: Some(pay_hash),
+        key: vec![
+            HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+            HOLD_LABELS.to_owned(),
+            label,
+        ],
+    })
+    .await
+}
+
+pub async fn listdatastore_label(
+    rpc: &mut PooledRpc,
+    label: String,
+) -> Result<Option<String>, RpcError> {
+    let response = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(vec![
+                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                HOLD_LABELS.to_owned(),
+                label,
+            ]),
+        })
+        .await?;
+    Ok(response.datastore.first().and_then(|d| d.string.clone()))
+}
+
+pub async fn listdatastore_invoice(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<Option<HashOnlyInvoice>, Error> {
+    let response = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(vec![
+                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                pay_hash,
+                HOLD_INVOICE_DATASTORE_INVOICE.to_owned(),
+            ]),
+        })
+        .await?;
+    match response.datastore.first().and_then(|d| d.string.as_ref()) {
+        Some(s) => Ok(Some(serde_json::from_str(s)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn datastore_preimage(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    preimage: String,
+) -> Result<DatastoreResponse, RpcError> {
+    rpc.call_typed(&DatastoreRequest {
+        generation: None,
+        hex: None,
+        mode: Some(DatastoreMode::CREATE_OR_REPLACE),
+        string: Some(preimage),
+        key: vec![
+            HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+            pay_hash,
+            HOLD_INVOICE_DATASTORE_PREIMAGE.to_owned(),
+        ],
+    })
+    .await
+}
+
+pub async fn listdatastore_preimage(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<String, Error> {
+    let response = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(vec![
+                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                pay_hash.clone(),
+                HOLD_INVOICE_DATASTORE_PREIMAGE.to_owned(),
+            ]),
+        })
+        .await?;
+    response
+        .datastore
+        .first()
+        .and_then(|d| d.string.clone())
+        .ok_or_else(|| anyhow!("no preimage stored for pay_hash: {}", pay_hash))
+}
+
+/// Delete every datastore entry we have for a payment_hash, the holdstate last
+pub async fn del_datastore_holdinvoice(rpc: &mut PooledRpc, pay_hash: String) -> Result<(), Error> {
+    if let Some(hash_only) = listdatastore_invoice(rpc, pay_hash.clone()).await? {
+        match rpc
+            .call_typed(&DeldatastoreRequest {
+                generation: None,
+                key: vec![
+                    HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                    HOLD_LABELS.to_owned(),
+                    hash_only.label,
+                ],
+            })
+            .await
+        {
+            Err(e) if e.code != Some(1200) => return Err(e.into()),
+            _ => (),
+        }
+    }
+    let entries = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(vec![HOLD_INVOICE_PLUGIN_NAME.to_owned(), pay_hash.clone()]),
+        })
+        .await?
+        .datastore;
+    let state_key = holdstate_key(pay_hash);
+    for entry in entries.into_iter().filter(|e| e.key != state_key) {
+        rpc.call_typed(&DeldatastoreRequest {
+            generation: None,
+            key: entry.key,
+        })
+        .await?;
+    }
+    match rpc
+        .call_typed(&DeldatastoreRequest {
+            generation: None,
+            key: state_key,
+        })
+        .await
+    {
+        // DATASTORE_DEL_DOES_NOT_EXIST, already gone
+        Err(e) if e.code != Some(1200) => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Store an optional per-invoice setting under `[holdinvoice, <pay_hash>, <field>]`
+pub async fn datastore_invoice_field(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    field: &str,
+    value: String,
+) -> Result<DatastoreResponse, RpcError> {
+    rpc.call_typed(&DatastoreRequest {
         generation: None,
+        hex: None,
+        mode: Some(DatastoreMode::MUST_CREATE),
+        string: Some(value),
         key: vec![
             HOLD_INVOICE_PLUGIN_NAME.to_owned(),
             pay_hash,
-            HOLD_INVOICE_DATASTORE_STATE.to_owned(),
+            field.to_owned(),
         ],
     })
     .await
 }
+
+pub async fn listdatastore_invoice_field(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    field: &str,
+) -> Result<Option<String>, RpcError> {
+    let response = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(vec![
+                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                pay_hash,
+                field.to_owned(),
+            ]),
+        })
+        .await?;
+    Ok(response.datastore.first().and_then(|d| d.string.clone()))
+}
+
+pub async fn datastore_webhook_url(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    url: String,
+) -> Result<DatastoreResponse, RpcError> {
+    datastore_invoice_field(rpc, pay_hash, HOLD_INVOICE_DATASTORE_WEBHOOK_URL, url).await
+}
+
+pub async fn listdatastore_webhook_url(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<Option<String>, RpcError> {
+    listdatastore_invoice_field(rpc, pay_hash, HOLD_INVOICE_DATASTORE_WEBHOOK_URL).await
+}
+
+pub async fn datastore_min_amount(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    min_amount_msat: u64,
+) -> Result<DatastoreResponse, RpcError> {
+    datastore_invoice_field(
+        rpc,
+        pay_hash,
+        HOLD_INVOICE_DATASTORE_MIN_AMOUNT,
+        min_amount_msat.to_string(),
+    )
+    .await
+}
+
+pub async fn listdatastore_min_amount(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<Option<u64>, Error> {
+    match listdatastore_invoice_field(rpc, pay_hash, HOLD_INVOICE_DATASTORE_MIN_AMOUNT).await? {
+        Some(s) => Ok(Some(s.parse()?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn datastore_max_overpay(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    max_overpay: &OverpayLimit,
+) -> Result<DatastoreResponse, Error> {
+    Ok(datastore_invoice_field(
+        rpc,
+        pay_hash,
+        HOLD_INVOICE_DATASTORE_MAX_OVERPAY,
+        serde_json::to_string(max_overpay)?,
+    )
+    .await?)
+}
+
+pub async fn listdatastore_max_overpay(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<Option<OverpayLimit>, Error> {
+    match listdatastore_invoice_field(rpc, pay_hash, HOLD_INVOICE_DATASTORE_MAX_OVERPAY).await? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn datastore_safety_margins(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    safety_margins: &SafetyMargins,
+) -> Result<DatastoreResponse, Error> {
+    Ok(datastore_invoice_field(
+        rpc,
+        pay_hash,
+        HOLD_INVOICE_DATASTORE_SAFETY_MARGINS,
+        serde_json::to_string(safety_margins)?,
+    )
+    .await?)
+}
+
+pub async fn listdatastore_safety_margins(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<Option<SafetyMargins>, Error> {
+    match listdatastore_invoice_field(rpc, pay_hash, HOLD_INVOICE_DATASTORE_SAFETY_MARGINS).await? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn datastore_on_expiry(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    on_expiry: ExpiryPolicy,
+) -> Result<DatastoreResponse, RpcError> {
+    datastore_invoice_field(
+        rpc,
+        pay_hash,
+        HOLD_INVOICE_DATASTORE_ON_EXPIRY,
+        on_expiry.to_string(),
+    )
+    .await
+}
+
+pub async fn listdatastore_on_expiry(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<Option<ExpiryPolicy>, Error> {
+    match listdatastore_invoice_field(rpc, pay_hash, HOLD_INVOICE_DATASTORE_ON_EXPIRY).await? {
+        Some(s) => Ok(Some(ExpiryPolicy::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn datastore_metadata(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    metadata: &serde_json::Value,
+) -> Result<DatastoreResponse, RpcError> {
+    datastore_invoice_field(
+        rpc,
+        pay_hash,
+        HOLD_INVOICE_DATASTORE_METADATA,
+        metadata.to_string(),
+    )
+    .await
+}
+
+pub async fn listdatastore_metadata(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<Option<serde_json::Value>, Error> {
+    match listdatastore_invoice_field(rpc, pay_hash, HOLD_INVOICE_DATASTORE_METADATA).await? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+/// Appends `transition` to the history of `pay_hash`, retried on concurrent appends
+pub async fn datastore_append_history(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    transition: &HoldTransition,
+) -> Result<(), Error> {
+    let key = vec![
+        HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+        pay_hash,
+        HOLD_INVOICE_DATASTORE_HISTORY.to_owned(),
+    ];
+    loop {
+        let existing = rpc
+            .call_typed(&ListdatastoreRequest {
+                key: Some(key.clone()),
+            })
+            .await?
+            .datastore
+            .into_iter()
+            .next();
+        let (mut history, generation, mode) = match existing {
+            Some(d) => (
+                serde_json::from_str::<Vec<HoldTransition>>(&d.string.unwrap_or_default())?,
+                d.generation,
+                DatastoreMode::MUST_REPLACE,
+            ),
+            None => (Vec::new(), None, DatastoreMode::MUST_CREATE),
+        };
+        history.push(transition.clone());
+        let result = rpc
+            .call_typed(&DatastoreRequest {
+                generation,
+                hex: None,
+                mode: Some(mode),
+                string: Some(serde_json::to_string(&history)?),
+                key: key.clone(),
+            })
+            .await;
+        match result {
+            Ok(_) => return Ok(()),
+            // someone else appended in between
+            Err(e) if matches!(e.code, Some(1202..=1204)) => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+pub async fn datastore_htlc_set(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    htlc_set: &HoldHtlcSet,
+) -> Result<DatastoreResponse, Error> {
+    Ok(rpc
+        .call_typed(&DatastoreRequest {
+            generation: None,
+            hex: None,
+            mode: Some(DatastoreMode::CREATE_OR_REPLACE),
+            string: Some(serde_json::to_string(htlc_set)?),
+            key: vec![
+                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                pay_hash,
+                HOLD_INVOICE_DATASTORE_HTLCS.to_owned(),
+            ],
+        })
+        .await?)
+}
+
+pub async fn listdatastore_htlc_set(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<Option<HoldHtlcSet>, Error> {
+    match listdatastore_invoice_field(rpc, pay_hash, HOLD_INVOICE_DATASTORE_HTLCS).await? {
+        Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+        None => Ok(None),
+    }
+}
+
+pub async fn listdatastore_history(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+) -> Result<Vec<HoldTransition>, Error> {
+    match listdatastore_invoice_field(rpc, pay_hash, HOLD_INVOICE_DATASTORE_HISTORY).await? {
+        Some(s) => Ok(serde_json::from_str(&s)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Store every optional setting that was given for a new holdinvoice
+pub async fn datastore_invoice_options(
+    rpc: &mut PooledRpc,
+    pay_hash: String,
+    options: &HoldInvoiceOptions,
+) -> Result<(), Error> {
+    if let Some(url) = &options.webhook_url {
+        datastore_webhook_url(rpc, pay_hash.clone(), url.clone()).await?;
+    }
+    if let Some(min) = options.min_amount_msat {
+        datastore_min_amount(rpc, pay_hash.clone(), min).await?;
+    }
+    if let Some(max) = &options.max_overpay {
+        datastore_max_overpay(rpc, pay_hash.clone(), max).await?;
+    }
+    if let Some(margins) = &options.safety_margins {
+        datastore_safety_margins(rpc, pay_hash.clone(), margins).await?;
+    }
+    if let Some(on_expiry) = options.on_expiry {
+        datastore_on_expiry(rpc, pay_hash.clone(), on_expiry).await?;
+    }
+    if let Some(metadata) = &options.metadata {
+        datastore_metadata(rpc, pay_hash, metadata).await?;
+    }
+    Ok(())
+}
+
+/// Mark an offer as hold, invoices cln creates for it are then held like holdinvoices
+pub async fn datastore_hold_offer(
+    rpc: &mut PooledRpc,
+    offer_id: String,
+    bolt12: String,
+) -> Result<DatastoreResponse, RpcError> {
+    rpc.call_typed(&DatastoreRequest {
+        generation: None,
+        hex: None,
+        mode: Some(DatastoreMode::CREATE_OR_REPLACE),
+        string: Some(bolt12),
+        key: vec![
+            HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+            HOLD_OFFERS.to_owned(),
+            offer_id,
+        ],
+    })
+    .await
+}
+
+pub async fn listdatastore_hold_offers(rpc: &mut PooledRpc) -> Result<HashSet<String>, RpcError> {
+    let response = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(vec![
+                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                HOLD_OFFERS.to_owned(),
+            ]),
+        })
+        .await?;
+    Ok(response
+        .datastore
+        .into_iter()
+        .filter_map(|d| d.key.get(2).map(|id| id.to_lowercase()))
+        .collect())
+}
+
+/// The invoice cln created for `pay_hash` if it belongs to a hold offer
+pub async fn listinvoices_hold_offer(
+    rpc: &mut PooledRpc,
+    hold_offers: &HoldOffers,
+    pay_hash: String,
+) -> Result<Option<ListinvoicesInvoices>, RpcError> {
+    if hold_offers.is_empty() {
+        return Ok(None);
+    }
+    let invoice = match rpc
+        .call_typed(&ListinvoicesRequest {
+            index: None,
+            invstring: None,
+            label: None,
+            limit: None,
+            offer_id: None,
+            payment_hash: Some(pay_hash),
+            start: None,
+        })
+        .await?
+        .invoices
+        .into_iter()
+        .next()
+    {
+        Some(i) => i,
+        None => return Ok(None),
+    };
+    match invoice.local_offer_id {
+        Some(offer_id) if hold_offers.contains(&offer_id.to_string()) => Ok(Some(invoice)),
+        _ => Ok(None),
+    }
+}
+
+pub async fn datastore_webhook_delivery(
+    rpc: &mut PooledRpc,
+    id: String,
+    delivery: &WebhookDelivery,
+) -> Result<DatastoreResponse, Error> {
+    Ok(rpc
+        .call_typed(&DatastoreRequest {
+            generation: None,
+            hex: None,
+            mode: Some(DatastoreMode::CREATE_OR_REPLACE),
+            string: Some(serde_json::to_string(delivery)?),
+            key: vec![
+                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                HOLD_WEBHOOK_OUTBOX.to_owned(),
+                id,
+            ],
+        })
+        .await?)
+}
+
+/// All pending webhook deliveries, oldest first
+pub async fn listdatastore_webhook_outbox(
+    rpc: &mut PooledRpc,
+) -> Result<Vec<(String, WebhookDelivery)>, Error> {
+    let response = rpc
+        .call_typed(&ListdatastoreRequest {
+            key: Some(vec![
+                HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+                HOLD_WEBHOOK_OUTBOX.to_owned(),
+            ]),
+        })
+        .await?;
+    let mut deliveries = Vec::new();
+    for data in response.datastore {
+        if let (Some(id), Some(s)) = (data.key.get(2), data.string) {
+            deliveries.push((id.clone(), serde_json::from_str(&s)?));
+        }
+    }
+    deliveries.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(deliveries)
+}
+
+pub async fn del_datastore_webhook_delivery(
+    rpc: &mut PooledRpc,
+    id: String,
+) -> Result<(), RpcError> {
+    rpc.call_typed(&DeldatastoreRequest {
+        generation: None,
+        key: vec![
+            HOLD_INVOICE_PLUGIN_NAME.to_owned(),
+            HOLD_WEBHOOK_OUTBOX.to_owned(),
+            id,
+        ],
+    })
+    .await?;
+    Ok(())
+}
+
+/// payment_hash of the invoice addressed by `selector`, a bolt11 is decoded
+/// locally and labels of hash-only invoices come from our label index
+pub async fn listinvoices_payment_hash(
+    rpc: &mut PooledRpc,
+    selector: &InvoiceSelector,
+) -> Result<Option<String>, Error> {
+    let label = match selector {
+        InvoiceSelector::PaymentHash(pay_hash) => return Ok(Some(pay_hash.clone())),
+        InvoiceSelector::Bolt11(bolt11) => return Ok(bolt11_payment_hash(bolt11)),
+        InvoiceSelector::Label(label) => label,
+    };
+    let invoices = rpc
+        .call_typed(&ListinvoicesRequest {
+            index: None,
+            invstring: None,
+            label: Some(label.clone()),
+            limit: None,
+            offer_id: None,
+            payment_hash: None,
+            start: None,
+        })
+        .await?
+        .invoices;
+    if let Some(inv) = invoices.into_iter().next() {
+        return Ok(Some(inv.payment_hash.to_string()));
+    }
+    Ok(listdatastore_label(rpc, label.clone()).await?)
+}
+
+/// Label and amount of a holdinvoice, from cln or our own record for hash-only ones
+pub async fn invoice_label_amount(
+    rpc: &mut PooledRpc,
+    pay_hash: &str,
+) -> Result<(Option<String>, Option<u64>), Error> {
+    let invoices = rpc
+        .call_typed(&ListinvoicesRequest {
+            index: None,
+            invstring: None,
+            label: None,
+            limit: None,
+            offer_id: None,
+            payment_hash: Some(pay_hash.to_owned()),
+            start: None,
+        })
+        .await?
+        .invoices;
+    if let Some(inv) = invoices.into_iter().next() {
+        return Ok((Some(inv.label), inv.amount_msat.map(|a| a.msat())));
+    }
+    Ok(
+        match listdatastore_invoice(rpc, pay_hash.to_owned()).await? {
+            Some(hash_only) => (Some(hash_only.label), Some(hash_only.amount_msat)),
+            None => (None, None),
+        },
+    )
+}
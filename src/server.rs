@@ -1,16 +1,35 @@
 use std::{
     path::{Path, PathBuf},
+    pin::Pin,
     str::FromStr,
 };
 
 use anyhow::Result;
 use cln_plugin::Plugin;
 use log::{debug, trace};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream,
+    StreamExt,
+};
 use tonic::{Code, Status};
 
 use crate::{
-    hold::{hold_invoice, hold_invoice_cancel, hold_invoice_lookup, hold_invoice_settle},
-    model::{self, Holdstate, PluginState},
+    hold::{
+        hold_invoice,
+        hold_invoice_autoclean,
+        hold_invoice_cancel,
+        hold_invoice_cancel_many,
+        hold_invoice_history,
+        hold_invoice_list,
+        hold_invoice_lookup,
+        hold_invoice_settle,
+        hold_invoice_settle_many,
+        hold_invoice_status,
+        hold_invoice_wait,
+        hold_offer,
+    },
+    model::{self, ExpiryPolicy, HoldInitiator, Holdstate, PluginState},
     pb,
     pb::hold_server::Hold,
 };
@@ -31,26 +50,56 @@ impl Server {
     }
 }
 
+/// Named arguments addressing a holdinvoice, an empty payment_hash means it is unset
+fn selector_args(
+    payment_hash: &[u8],
+    label: Option<String>,
+    bolt11: Option<String>,
+) -> serde_json::Map<String, serde_json::Value> {
+    let mut args = serde_json::Map::new();
+    if !payment_hash.is_empty() {
+        args.insert(
+            "payment_hash".to_owned(),
+            serde_json::Value::String(hex::encode(payment_hash)),
+        );
+    }
+    if let Some(label) = label {
+        args.insert("label".to_owned(), serde_json::Value::String(label));
+    }
+    if let Some(bolt11) = bolt11 {
+        args.insert("bolt11".to_owned(), serde_json::Value::String(bolt11));
+    }
+    args
+}
+
 #[tonic::async_trait]
 impl Hold for Server {
+    type SubscribeHoldInvoicesStream =
+        Pin<Box<dyn Stream<Item = Result<pb::HoldInvoiceEvent, Status>> + Send + 'static>>;
+
     async fn hold_invoice(
         &self,
         request: tonic::Request<pb::HoldInvoiceRequest>,
     ) -> Result<tonic::Response<pb::HoldInvoiceResponse>, tonic::Status> {
         let req = request.into_inner();
-        let req: model::HoldInvoiceRequest = req.into();
+        let req: model::HoldInvoiceRequest = req.try_into()?;
         debug!("Client asked for Holdinvoice");
         trace!("Holdinvoice request: {:?}", req);
-        let result =
-            match hold_invoice(self.plugin.clone(), serde_json::to_value(req).unwrap()).await {
-                Ok(res) => res,
-                Err(e) => {
-                    return Err(Status::new(
-                        Code::Internal,
-                        format!("Unexpected result {} to method call hold_invoice", e),
-                    ));
-                }
-            };
+        let result = match hold_invoice(
+            self.plugin.clone(),
+            serde_json::to_value(req).unwrap(),
+            HoldInitiator::Grpc,
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("Unexpected result {} to method call hold_invoice", e),
+                ));
+            }
+        };
         debug!("{:?}", result);
         match serde_json::from_value::<model::HoldInvoiceResponse>(result.clone()) {
             Ok(r) => {
@@ -71,11 +120,17 @@ impl Hold for Server {
         let req = request.into_inner();
         debug!("Client asked for Holdinvoicesettle");
         debug!("Holdinvoicesettle request: {:?}", req);
-        let pay_hash = hex::encode(req.payment_hash.clone());
-        debug!("payment_hash: {}", pay_hash);
+        let mut args = selector_args(&req.payment_hash, req.label, req.bolt11);
+        if let Some(preimage) = req.preimage {
+            args.insert(
+                "preimage".to_owned(),
+                serde_json::Value::String(hex::encode(preimage)),
+            );
+        }
         let result = match hold_invoice_settle(
             self.plugin.clone(),
-            serde_json::Value::Array(vec![serde_json::Value::String(pay_hash)]),
+            serde_json::Value::Object(args),
+            HoldInitiator::Grpc,
         )
         .await
         {
@@ -121,11 +176,11 @@ impl Hold for Server {
         let req = request.into_inner();
         debug!("Client asked for Holdinvoicecancel");
         debug!("Holdinvoicecancel request: {:?}", req);
-        let pay_hash = hex::encode(req.payment_hash.clone());
-        debug!("payment_hash: {}", pay_hash);
+        let args = selector_args(&req.payment_hash, req.label, req.bolt11);
         let result = match hold_invoice_cancel(
             self.plugin.clone(),
-            serde_json::Value::Array(vec![serde_json::Value::String(pay_hash)]),
+            serde_json::Value::Object(args),
+            HoldInitiator::Grpc,
         )
         .await
         {
@@ -171,11 +226,11 @@ impl Hold for Server {
         let req = request.into_inner();
         debug!("Client asked for Holdinvoicelookup");
         debug!("Holdinvoicelookup request: {:?}", req);
-        let pay_hash = hex::encode(req.payment_hash.clone());
-        debug!("payment_hash: {}", pay_hash);
+        let args = selector_args(&req.payment_hash, req.label, req.bolt11);
         let result = match hold_invoice_lookup(
             self.plugin.clone(),
-            serde_json::Value::Array(vec![serde_json::Value::String(pay_hash)]),
+            serde_json::Value::Object(args),
+            HoldInitiator::Grpc,
         )
         .await
         {
@@ -207,6 +262,44 @@ impl Hold for Server {
                             } else {
                                 None
                             },
+                            amount_accepted_msat: result
+                                .get("amount_accepted_msat")
+                                .and_then(|a| a.as_u64())
+                                .map(|msat| pb::Amount { msat }),
+                            offer_id: result
+                                .get("offer_id")
+                                .and_then(|o| o.as_str())
+                                .and_then(|o| hex::decode(o).ok()),
+                            payer_note: result
+                                .get("payer_note")
+                                .and_then(|n| n.as_str())
+                                .map(|n| n.to_owned()),
+                            on_expiry: result
+                                .get("on_expiry")
+                                .and_then(|o| o.as_str())
+                                .and_then(|o| ExpiryPolicy::from_str(o).ok())
+                                .unwrap_or(ExpiryPolicy::Settle)
+                                .as_i32(),
+                            metadata: result.get("metadata").map(|m| m.to_string()),
+                            htlcs: result
+                                .get("htlcs")
+                                .and_then(|h| {
+                                    serde_json::from_value::<Vec<model::HoldLookupHtlc>>(h.clone())
+                                        .ok()
+                                })
+                                .unwrap_or_default()
+                                .into_iter()
+                                .map(|h| h.into())
+                                .collect(),
+                            last_transition: result.get("last_transition").and_then(|t| {
+                                serde_json::from_value::<model::HoldTransition>(t.clone())
+                                    .ok()
+                                    .map(|t| t.into())
+                            }),
+                            channel_unhealthy: result
+                                .get("channel_unhealthy")
+                                .and_then(|u| u.as_bool())
+                                .unwrap_or(false),
                         };
                         return Ok(tonic::Response::new(hisr));
                     }
@@ -221,4 +314,455 @@ impl Hold for Server {
             }
         }
     }
+
+    async fn list_hold_invoices(
+        &self,
+        request: tonic::Request<pb::ListHoldInvoicesRequest>,
+    ) -> Result<tonic::Response<pb::ListHoldInvoicesResponse>, tonic::Status> {
+        let req = request.into_inner();
+        debug!("Client asked for ListHoldInvoices");
+        debug!("ListHoldInvoices request: {:?}", req);
+        let mut args = serde_json::Map::new();
+        if let Some(state) = req.state {
+            match pb::Holdstate::try_from(state) {
+                Ok(hs) => {
+                    args.insert(
+                        "state".to_owned(),
+                        serde_json::Value::String(hs.as_str_name().to_owned()),
+                    );
+                }
+                Err(_) => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        format!("Invalid holdstate: {}", state),
+                    ))
+                }
+            }
+        }
+        if let Some(label_prefix) = req.label_prefix {
+            args.insert(
+                "label_prefix".to_owned(),
+                serde_json::Value::String(label_prefix),
+            );
+        }
+        if let Some(created_after) = req.created_after {
+            args.insert("created_after".to_owned(), created_after.into());
+        }
+        if let Some(created_before) = req.created_before {
+            args.insert("created_before".to_owned(), created_before.into());
+        }
+        if let Some(start) = req.start {
+            args.insert("start".to_owned(), start.into());
+        }
+        if let Some(limit) = req.limit {
+            args.insert("limit".to_owned(), limit.into());
+        }
+        let result =
+            match hold_invoice_list(self.plugin.clone(), serde_json::Value::Object(args)).await {
+                Ok(res) => res,
+                Err(e) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        format!("Unexpected result {} to method call hold_invoice_list", e),
+                    ));
+                }
+            };
+
+        if result.get("code").is_some() {
+            return Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Unexpected result {} to method call hold_invoice_list",
+                    result
+                ),
+            ));
+        }
+        match serde_json::from_value::<model::HoldInvoiceListResponse>(result.clone()) {
+            Ok(r) => {
+                trace!("ListHoldInvoices response: {:?}", r);
+                Ok(tonic::Response::new(r.into()))
+            }
+            Err(_r) => Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Unexpected result {} to method call hold_invoice_list",
+                    result
+                ),
+            )),
+        }
+    }
+
+    async fn hold_invoice_settle_many(
+        &self,
+        request: tonic::Request<pb::HoldInvoiceSettleManyRequest>,
+    ) -> Result<tonic::Response<pb::HoldInvoiceSettleManyResponse>, tonic::Status> {
+        let req = request.into_inner();
+        debug!("Client asked for HoldInvoiceSettleMany");
+        debug!("HoldInvoiceSettleMany request: {:?}", req);
+        let result = match hold_invoice_settle_many(
+            self.plugin.clone(),
+            bulk_args(req.payment_hashes, req.label_prefix),
+            HoldInitiator::Grpc,
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!(
+                        "Unexpected result {} to method call hold_invoice_settle_many",
+                        e
+                    ),
+                ));
+            }
+        };
+        match serde_json::from_value::<model::HoldBulkResponse>(result.clone()) {
+            Ok(r) => Ok(tonic::Response::new(r.into())),
+            Err(_r) => Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Unexpected result {} to method call hold_invoice_settle_many",
+                    result
+                ),
+            )),
+        }
+    }
+
+    async fn hold_invoice_cancel_many(
+        &self,
+        request: tonic::Request<pb::HoldInvoiceCancelManyRequest>,
+    ) -> Result<tonic::Response<pb::HoldInvoiceCancelManyResponse>, tonic::Status> {
+        let req = request.into_inner();
+        debug!("Client asked for HoldInvoiceCancelMany");
+        debug!("HoldInvoiceCancelMany request: {:?}", req);
+        let result = match hold_invoice_cancel_many(
+            self.plugin.clone(),
+            bulk_args(req.payment_hashes, req.label_prefix),
+            HoldInitiator::Grpc,
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!(
+                        "Unexpected result {} to method call hold_invoice_cancel_many",
+                        e
+                    ),
+                ));
+            }
+        };
+        match serde_json::from_value::<model::HoldBulkResponse>(result.clone()) {
+            Ok(r) => Ok(tonic::Response::new(r.into())),
+            Err(_r) => Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Unexpected result {} to method call hold_invoice_cancel_many",
+                    result
+                ),
+            )),
+        }
+    }
+
+    async fn hold_invoice_wait(
+        &self,
+        request: tonic::Request<pb::HoldInvoiceWaitRequest>,
+    ) -> Result<tonic::Response<pb::HoldInvoiceWaitResponse>, tonic::Status> {
+        let req = request.into_inner();
+        debug!("Client asked for HoldInvoiceWait");
+        debug!("HoldInvoiceWait request: {:?}", req);
+        let mut states = Vec::new();
+        for state in req.states {
+            match pb::Holdstate::try_from(state) {
+                Ok(hs) => states.push(serde_json::Value::String(hs.as_str_name().to_owned())),
+                Err(_) => {
+                    return Err(Status::new(
+                        Code::InvalidArgument,
+                        format!("Invalid holdstate: {}", state),
+                    ))
+                }
+            }
+        }
+        let mut args = serde_json::Map::new();
+        args.insert(
+            "payment_hash".to_owned(),
+            serde_json::Value::String(hex::encode(req.payment_hash)),
+        );
+        args.insert("states".to_owned(), serde_json::Value::Array(states));
+        if let Some(timeout) = req.timeout {
+            args.insert("timeout".to_owned(), timeout.into());
+        }
+        let result =
+            match hold_invoice_wait(self.plugin.clone(), serde_json::Value::Object(args)).await {
+                Ok(res) => res,
+                Err(e) => {
+                    return Err(Status::new(
+                        Code::Internal,
+                        format!("Unexpected result {} to method call hold_invoice_wait", e),
+                    ));
+                }
+            };
+
+        match result.get("code") {
+            Some(code) => Err(Status::new(
+                if code.as_i64() == Some(904) {
+                    Code::DeadlineExceeded
+                } else {
+                    Code::Internal
+                },
+                format!(
+                    "Unexpected result {} to method call hold_invoice_wait",
+                    result
+                ),
+            )),
+            None => {
+                if let Some(state) = result.get("state") {
+                    if let Ok(hs) = Holdstate::from_str(state.as_str().unwrap()) {
+                        return Ok(tonic::Response::new(pb::HoldInvoiceWaitResponse {
+                            state: hs.as_i32(),
+                        }));
+                    }
+                }
+                Err(Status::new(
+                    Code::Internal,
+                    format!(
+                        "Unexpected result {} to method call hold_invoice_wait",
+                        result
+                    ),
+                ))
+            }
+        }
+    }
+
+    async fn hold_offer(
+        &self,
+        request: tonic::Request<pb::HoldOfferRequest>,
+    ) -> Result<tonic::Response<pb::HoldOfferResponse>, tonic::Status> {
+        let req = request.into_inner();
+        debug!("Client asked for HoldOffer");
+        debug!("HoldOffer request: {:?}", req);
+        let result = match hold_offer(
+            self.plugin.clone(),
+            serde_json::Value::Array(vec![serde_json::Value::String(hex::encode(req.offer_id))]),
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("Unexpected result {} to method call hold_offer", e),
+                ));
+            }
+        };
+
+        if result.get("code").is_some() {
+            return Err(Status::new(
+                Code::Internal,
+                format!("Unexpected result {} to method call hold_offer", result),
+            ));
+        }
+        match serde_json::from_value::<model::HoldOfferResponse>(result.clone()) {
+            Ok(r) => {
+                trace!("HoldOffer response: {:?}", r);
+                Ok(tonic::Response::new(r.into()))
+            }
+            Err(_r) => Err(Status::new(
+                Code::Internal,
+                format!("Unexpected result {} to method call hold_offer", result),
+            )),
+        }
+    }
+
+    async fn hold_invoice_history(
+        &self,
+        request: tonic::Request<pb::HoldInvoiceHistoryRequest>,
+    ) -> Result<tonic::Response<pb::HoldInvoiceHistoryResponse>, tonic::Status> {
+        let req = request.into_inner();
+        debug!("Client asked for HoldInvoiceHistory");
+        debug!("HoldInvoiceHistory request: {:?}", req);
+        let args = selector_args(&req.payment_hash, req.label, req.bolt11);
+        let result = match hold_invoice_history(
+            self.plugin.clone(),
+            serde_json::Value::Object(args),
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!(
+                        "Unexpected result {} to method call hold_invoice_history",
+                        e
+                    ),
+                ));
+            }
+        };
+
+        if result.get("code").is_some() {
+            return Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Unexpected result {} to method call hold_invoice_history",
+                    result
+                ),
+            ));
+        }
+        match serde_json::from_value::<model::HoldHistoryResponse>(result.clone()) {
+            Ok(r) => {
+                trace!("HoldInvoiceHistory response: {:?}", r);
+                Ok(tonic::Response::new(r.into()))
+            }
+            Err(_r) => Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Unexpected result {} to method call hold_invoice_history",
+                    result
+                ),
+            )),
+        }
+    }
+
+    async fn hold_invoice_autoclean(
+        &self,
+        request: tonic::Request<pb::HoldInvoiceAutocleanRequest>,
+    ) -> Result<tonic::Response<pb::HoldInvoiceAutocleanResponse>, tonic::Status> {
+        let req = request.into_inner();
+        debug!("Client asked for HoldInvoiceAutoclean");
+        debug!("HoldInvoiceAutoclean request: {:?}", req);
+        let result = match hold_invoice_autoclean(
+            self.plugin.clone(),
+            serde_json::Value::Object(serde_json::Map::new()),
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!(
+                        "Unexpected result {} to method call hold_invoice_autoclean",
+                        e
+                    ),
+                ));
+            }
+        };
+
+        if result.get("code").is_some() {
+            return Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Unexpected result {} to method call hold_invoice_autoclean",
+                    result
+                ),
+            ));
+        }
+        match serde_json::from_value::<model::HoldAutocleanResponse>(result.clone()) {
+            Ok(r) => {
+                trace!("HoldInvoiceAutoclean response: {:?}", r);
+                Ok(tonic::Response::new(r.into()))
+            }
+            Err(_r) => Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Unexpected result {} to method call hold_invoice_autoclean",
+                    result
+                ),
+            )),
+        }
+    }
+
+    async fn hold_invoice_status(
+        &self,
+        request: tonic::Request<pb::HoldInvoiceStatusRequest>,
+    ) -> Result<tonic::Response<pb::HoldInvoiceStatusResponse>, tonic::Status> {
+        let req = request.into_inner();
+        debug!("Client asked for HoldInvoiceStatus");
+        debug!("HoldInvoiceStatus request: {:?}", req);
+        let result = match hold_invoice_status(
+            self.plugin.clone(),
+            serde_json::Value::Object(serde_json::Map::new()),
+        )
+        .await
+        {
+            Ok(res) => res,
+            Err(e) => {
+                return Err(Status::new(
+                    Code::Internal,
+                    format!("Unexpected result {} to method call hold_invoice_status", e),
+                ));
+            }
+        };
+
+        match serde_json::from_value::<model::HoldStatusResponse>(result.clone()) {
+            Ok(r) => {
+                trace!("HoldInvoiceStatus response: {:?}", r);
+                Ok(tonic::Response::new(r.into()))
+            }
+            Err(_r) => Err(Status::new(
+                Code::Internal,
+                format!(
+                    "Unexpected result {} to method call hold_invoice_status",
+                    result
+                ),
+            )),
+        }
+    }
+
+    async fn subscribe_hold_invoices(
+        &self,
+        request: tonic::Request<pb::SubscribeHoldInvoicesRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeHoldInvoicesStream>, tonic::Status> {
+        let req = request.into_inner();
+        debug!("Client asked for SubscribeHoldInvoices");
+        debug!("SubscribeHoldInvoices request: {:?}", req);
+        let pay_hash = req.payment_hash.map(hex::encode);
+        if let Some(ph) = &pay_hash {
+            if ph.len() != 64 {
+                return Err(Status::new(
+                    Code::InvalidArgument,
+                    format!("payment_hash: should be a 32 byte hex value: {}", ph),
+                ));
+            }
+        }
+
+        let events = BroadcastStream::new(self.plugin.state().events.subscribe());
+        let stream = events.filter_map(move |event| match event {
+            Ok(e) => {
+                if pay_hash.as_ref().is_none_or(|ph| ph == &e.payment_hash) {
+                    trace!("SubscribeHoldInvoices event: {:?}", e);
+                    Some(Ok(e.into()))
+                } else {
+                    None
+                }
+            }
+            Err(BroadcastStreamRecvError::Lagged(count)) => Some(Err(Status::new(
+                Code::DataLoss,
+                format!("Subscriber lagged behind and missed {} events", count),
+            ))),
+        });
+        Ok(tonic::Response::new(Box::pin(stream)))
+    }
+}
+
+fn bulk_args(payment_hashes: Vec<Vec<u8>>, label_prefix: Option<String>) -> serde_json::Value {
+    let mut args = serde_json::Map::new();
+    if !payment_hashes.is_empty() {
+        args.insert(
+            "payment_hashes".to_owned(),
+            serde_json::Value::Array(
+                payment_hashes
+                    .into_iter()
+                    .map(|h| serde_json::Value::String(hex::encode(h)))
+                    .collect(),
+            ),
+        );
+    }
+    if let Some(prefix) = label_prefix {
+        args.insert("label_prefix".to_owned(), serde_json::Value::String(prefix));
+    }
+    serde_json::Value::Object(args)
 }
@@ -0,0 +1,495 @@
+//! Write-through cache of the holdstates in cln's datastore. The plugin is the
+//! only writer under its prefix, so once the cache is loaded on startup every
+//! read is answered from memory. Writes go to the datastore first and update
+//! the cache after cln confirmed them.
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Error};
+use cln_rpc::{
+    model::responses::{DatastoreResponse, ListdatastoreDatastore},
+    RpcError,
+};
+use log::{debug, warn};
+use parking_lot::Mutex;
+
+use crate::{
+    model::Holdstate,
+    pool::PooledRpc,
+    rpc::{
+        datastore_new_state,
+        datastore_update_state,
+        datastore_update_state_forced,
+        listdatastore_all,
+        listdatastore_state,
+    },
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CachedState {
+    pub state: Holdstate,
+    pub generation: u64,
+}
+
+impl TryFrom<&ListdatastoreDatastore> for CachedState {
+    type Error = Error;
+
+    fn try_from(data: &ListdatastoreDatastore) -> Result<Self, Error> {
+        Ok(CachedState {
+            state: Holdstate::from_str(data.string.as_deref().unwrap_or_default())?,
+            generation: data.generation.unwrap_or(0),
+        })
+    }
+}
+
+/// Without `complete` (before the startup load or after a write with an
+/// unknown outcome) misses fall back to the datastore.
+#[derive(Clone, Debug, Default)]
+pub struct HoldStateCache {
+    states: Arc<Mutex<HashMap<String, CachedState>>>,
+    complete: Arc<AtomicBool>,
+}
+
+impl HoldStateCache {
+    /// Reads every holdstate from the datastore, returns how many there are.
+    /// cln only lists direct children, so this is one call per holdinvoice and
+    /// only done once on startup.
+    pub async fn load(&self, rpc: &mut PooledRpc) -> Result<usize, Error> {
+        let mut loaded = Vec::new();
+        for data in listdatastore_all(rpc).await?.datastore {
+            let pay_hash = data.key[1].clone();
+            match listdatastore_state(rpc, pay_hash.clone()).await {
+                Ok(d) => loaded.push((pay_hash, CachedState::try_from(&d)?)),
+                // only leftovers of other fields, not a holdinvoice
+                Err(e) => debug!("no holdstate for {}: {}", pay_hash, e),
+            }
+        }
+
+        let mut states = self.states.lock();
+        for (pay_hash, state) in loaded {
+            insert_newer(&mut states, pay_hash, state);
+        }
+        self.complete.store(true, Ordering::Release);
+        Ok(states.len())
+    }
+
+    /// Only what is in memory, without asking cln
+    pub fn cached(&self, pay_hash: &str) -> Option<CachedState> {
+        self.states.lock().get(pay_hash).copied()
+    }
+
+    /// None if `pay_hash` is not a holdinvoice
+    pub async fn get(
+        &self,
+        rpc: &mut PooledRpc,
+        pay_hash: &str,
+    ) -> Result<Option<CachedState>, Error> {
+        if let Some(state) = self.cached(pay_hash) {
+            return Ok(Some(state));
+        }
+        if self.complete.load(Ordering::Acquire) {
+            return Ok(None);
+        }
+        match listdatastore_state(rpc, pay_hash.to_owned()).await {
+            Ok(d) => {
+                let state = CachedState::try_from(&d)?;
+                insert_newer(&mut self.states.lock(), pay_hash.to_owned(), state);
+                Ok(Some(state))
+            }
+            Err(e) => {
+                debug!("no holdstate for {}: {}", pay_hash, e);
+                Ok(None)
+            }
+        }
+    }
+
+    pub async fn create(
+        &self,
+        rpc: &mut PooledRpc,
+        pay_hash: &str,
+        state: Holdstate,
+    ) -> Result<CachedState, RpcError> {
+        let result = datastore_new_state(rpc, pay_hash.to_owned(), state.to_string()).await;
+        self.written(rpc, pay_hash, state, result).await
+    }
+
+    /// Fails if the holdstate was changed since `generation`
+    pub async fn update(
+        &self,
+        rpc: &mut PooledRpc,
+        pay_hash: &str,
+        state: Holdstate,
+        generation: u64,
+    ) -> Result<CachedState, RpcError> {
+        let result =
+            datastore_update_state(rpc, pay_hash.to_owned(), state.to_string(), generation).await;
+        self.written(rpc, pay_hash, state, result).await
+    }
+
+    pub async fn update_forced(
+        &self,
+        rpc: &mut PooledRpc,
+        pay_hash: &str,
+        state: Holdstate,
+    ) -> Result<CachedState, RpcError> {
+        let result =
+            datastore_update_state_forced(rpc, pay_hash.to_owned(), state.to_string()).await;
+        self.written(rpc, pay_hash, state, result).await
+    }
+
+    /// After the holdinvoice was deleted from the datastore
+    pub fn remove(&self, pay_hash: &str) {
+        self.states.lock().remove(pay_hash);
+    }
+
+    /// Every payment_hash with a holdstate, the cache is reloaded first if a
+    /// write left it incomplete
+    pub async fn pay_hashes(&self, rpc: &mut PooledRpc) -> Result<HashSet<String>, Error> {
+        if !self.complete.load(Ordering::Acquire) {
+            self.load(rpc).await?;
+        }
+        Ok(self.states.lock().keys().cloned().collect())
+    }
+
+    pub fn entries(&self) -> Vec<(String, CachedState)> {
+        self.states
+            .lock()
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect()
+    }
+
+    async fn written(
+        &self,
+        rpc: &mut PooledRpc,
+        pay_hash: &str,
+        state: Holdstate,
+        result: Result<DatastoreResponse, RpcError>,
+    ) -> Result<CachedState, RpcError> {
+        match result {
+            Ok(response) => {
+                let cached = CachedState {
+                    state,
+                    generation: response.generation.unwrap_or(0),
+                };
+                insert_newer(&mut self.states.lock(), pay_hash.to_owned(), cached);
+                Ok(cached)
+            }
+            Err(e) => {
+                // the write might still have happened, ask cln what it has now
+                self.refresh(rpc, pay_hash).await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn refresh(&self, rpc: &mut PooledRpc, pay_hash: &str) {
+        let state = match listdatastore_state(rpc, pay_hash.to_owned()).await {
+            Ok(d) => CachedState::try_from(&d),
+            Err(e) => Err(anyhow!(e)),
+        };
+        let mut states = self.states.lock();
+        match state {
+            Ok(s) => {
+                states.insert(pay_hash.to_owned(), s);
+            }
+            Err(e) => {
+                warn!(
+                    "Could not refresh cached holdstate for payment_hash: {} {}",
+                    pay_hash, e
+                );
+                states.remove(pay_hash);
+                self.complete.store(false, Ordering::Release);
+            }
+        }
+    }
+}
+
+/// Generations only go up, so an older read or write never replaces a newer one
+fn insert_newer(states: &mut HashMap<String, CachedState>, pay_hash: String, state: CachedState) {
+    match states.get(&pay_hash) {
+        Some(s) if s.generation >= state.generation => (),
+        _ => {
+            states.insert(pay_hash, state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::fakecln::{serve, FakeDatastore};
+
+    const HASH: &str = "6d6f6e65792c20666f72206e6f7468696e6720616e6420746865206368697073";
+
+    fn state_key(pay_hash: &str) -> Vec<String> {
+        vec![
+            "holdinvoice".to_owned(),
+            pay_hash.to_owned(),
+            "state".to_owned(),
+        ]
+    }
+
+    #[test]
+    fn insert_newer_keeps_highest_generation() {
+        let mut states = HashMap::new();
+        let accepted = CachedState {
+            state: Holdstate::Accepted,
+            generation: 2,
+        };
+        insert_newer(&mut states, HASH.to_owned(), accepted);
+        insert_newer(
+            &mut states,
+            HASH.to_owned(),
+            CachedState {
+                state: Holdstate::Open,
+                generation: 1,
+            },
+        );
+        assert_eq!(states[HASH], accepted);
+        let settled = CachedState {
+            state: Holdstate::Settled,
+            generation: 3,
+        };
+        insert_newer(&mut states, HASH.to_owned(), settled);
+        assert_eq!(states[HASH], settled);
+    }
+
+    #[tokio::test]
+    async fn failed_write_leaves_cache_unchanged() {
+        let store = Arc::new(Mutex::new(FakeDatastore::default()));
+        let pool = serve(store.clone());
+        let mut rpc = pool.get().await.unwrap();
+        let cache = HoldStateCache::default();
+        cache.load(&mut rpc).await.unwrap();
+
+        let open = cache.create(&mut rpc, HASH, Holdstate::Open).await.unwrap();
+        store.lock().fail_writes = Some(1204);
+        assert!(cache
+            .update(&mut rpc, HASH, Holdstate::Accepted, open.generation)
+            .await
+            .is_err());
+        assert_eq!(cache.cached(HASH), Some(open));
+        assert_eq!(
+            cache.get(&mut rpc, HASH).await.unwrap(),
+            Some(open),
+            "complete cache must still answer from memory"
+        );
+    }
+
+    #[tokio::test]
+    async fn lost_write_invalidates_entry() {
+        let store = Arc::new(Mutex::new(FakeDatastore::default()));
+        let pool = serve(store.clone());
+        let cache = HoldStateCache::default();
+        let open = {
+            let mut rpc = pool.get().await.unwrap();
+            cache.load(&mut rpc).await.unwrap();
+            cache.create(&mut rpc, HASH, Holdstate::Open).await.unwrap()
+        };
+
+        // cln never answers, the cache can not know what the datastore has
+        store.lock().drop_writes = true;
+        let mut rpc = pool.get().await.unwrap();
+        assert!(cache
+            .update(&mut rpc, HASH, Holdstate::Accepted, open.generation)
+            .await
+            .is_err());
+        drop(rpc);
+        assert_eq!(cache.cached(HASH), None);
+
+        store.lock().drop_writes = false;
+        let mut rpc = pool.get().await.unwrap();
+        assert_eq!(cache.get(&mut rpc, HASH).await.unwrap(), Some(open));
+        assert!(cache.pay_hashes(&mut rpc).await.unwrap().contains(HASH));
+    }
+
+    #[tokio::test]
+    async fn generations_survive_restart() {
+        let store = Arc::new(Mutex::new(FakeDatastore::default()));
+        let pool = serve(store.clone());
+        let mut rpc = pool.get().await.unwrap();
+        let before = HoldStateCache::default();
+        before.load(&mut rpc).await.unwrap();
+        let open = before
+            .create(&mut rpc, HASH, Holdstate::Open)
+            .await
+            .unwrap();
+        let accepted = before
+            .update(&mut rpc, HASH, Holdstate::Accepted, open.generation)
+            .await
+            .unwrap();
+
+        let after = HoldStateCache::default();
+        assert_eq!(after.load(&mut rpc).await.unwrap(), 1);
+        assert_eq!(after.cached(HASH), Some(accepted));
+
+        // a writer that still holds the generation from before the restart loses
+        assert!(after
+            .update(&mut rpc, HASH, Holdstate::Canceled, open.generation)
+            .await
+            .is_err());
+        assert_eq!(after.cached(HASH), Some(accepted));
+
+        let settled = after
+            .update(&mut rpc, HASH, Holdstate::Settled, accepted.generation)
+            .await
+            .unwrap();
+        assert!(settled.generation > accepted.generation);
+        assert_eq!(
+            store.lock().entries[&state_key(HASH)],
+            (Holdstate::Settled.to_string(), settled.generation)
+        );
+    }
+
+    #[tokio::test]
+    async fn load_keeps_the_key_layout() {
+        let store = Arc::new(Mutex::new(FakeDatastore::default()));
+        store
+            .lock()
+            .entries
+            .insert(state_key(HASH), (Holdstate::Accepted.to_string(), 7));
+        // leftovers of a deleted holdinvoice and data that is not per invoice
+        let leftover = hex::encode([1u8; 32]);
+        store.lock().entries.insert(
+            vec![
+                "holdinvoice".to_owned(),
+                leftover.clone(),
+                "metadata".to_owned(),
+            ],
+            ("{}".to_owned(), 0),
+        );
+        store.lock().entries.insert(
+            vec![
+                "holdinvoice".to_owned(),
+                "webhook_outbox".to_owned(),
+                "1".to_owned(),
+            ],
+            ("{}".to_owned(), 0),
+        );
+        let before = store.lock().entries.clone();
+        let pool = serve(store.clone());
+        let mut rpc = pool.get().await.unwrap();
+
+        let cache = HoldStateCache::default();
+        assert_eq!(cache.load(&mut rpc).await.unwrap(), 1);
+        assert_eq!(
+            cache.cached(HASH),
+            Some(CachedState {
+                state: Holdstate::Accepted,
+                generation: 7
+            })
+        );
+        assert_eq!(cache.cached(&leftover), None);
+        assert_eq!(store.lock().entries, before, "loading must not write");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn settle_cancel_race_has_one_winner() {
+        let store = Arc::new(Mutex::new(FakeDatastore::default()));
+        let pool = serve(store.clone());
+        let cache = HoldStateCache::default();
+        cache.load(&mut pool.get().await.unwrap()).await.unwrap();
+
+        for round in 0..20u8 {
+            let pay_hash = hex::encode([round; 32]);
+            let accepted = {
+                let mut rpc = pool.get().await.unwrap();
+                let open = cache
+                    .create(&mut rpc, &pay_hash, Holdstate::Open)
+                    .await
+                    .unwrap();
+                cache
+                    .update(&mut rpc, &pay_hash, Holdstate::Accepted, open.generation)
+                    .await
+                    .unwrap()
+            };
+
+            let race = |state| {
+                let (pool, cache, pay_hash) = (pool.clone(), cache.clone(), pay_hash.clone());
+                tokio::spawn(async move {
+                    let mut rpc = pool.get().await.unwrap();
+                    cache
+                        .update(&mut rpc, &pay_hash, state, accepted.generation)
+                        .await
+                        .map(|s| s.state)
+                })
+            };
+            let settle = race(Holdstate::Settled);
+            let cancel = race(Holdstate::Canceled);
+            let (settle, cancel) = (settle.await.unwrap(), cancel.await.unwrap());
+
+            let winner = match (settle, cancel) {
+                (Ok(s), Err(e)) | (Err(e), Ok(s)) => {
+                    assert_eq!(e.code, Some(1204));
+                    s
+                }
+                r => panic!("expected exactly one winner, got {:?}", r),
+            };
+            let cached = cache.cached(&pay_hash).unwrap();
+            assert_eq!(cached.state, winner);
+            assert_eq!(
+                store.lock().entries[&state_key(&pay_hash)],
+                (winner.to_string(), cached.generation)
+            );
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn slow_write_does_not_block_other_holdinvoices() {
+        let store = Arc::new(Mutex::new(FakeDatastore::default()));
+        let pool = serve(store.clone());
+        let cache = HoldStateCache::default();
+        let other = hex::encode([1u8; 32]);
+        let (open, other_open) = {
+            let mut rpc = pool.get().await.unwrap();
+            cache.load(&mut rpc).await.unwrap();
+            (
+                cache.create(&mut rpc, HASH, Holdstate::Open).await.unwrap(),
+                cache
+                    .create(&mut rpc, &other, Holdstate::Open)
+                    .await
+                    .unwrap(),
+            )
+        };
+        store.lock().slow_writes = Some((HASH.to_owned(), Duration::from_millis(500)));
+
+        let slow = {
+            let (pool, cache) = (pool.clone(), cache.clone());
+            tokio::spawn(async move {
+                let mut rpc = pool.get().await.unwrap();
+                cache
+                    .update(&mut rpc, HASH, Holdstate::Accepted, open.generation)
+                    .await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let mut rpc = pool.get().await.unwrap();
+        let fast = tokio::time::timeout(
+            Duration::from_millis(250),
+            cache.update(&mut rpc, &other, Holdstate::Accepted, other_open.generation),
+        )
+        .await
+        .expect("write for another holdinvoice waited for the slow one")
+        .unwrap();
+        assert_eq!(fast.state, Holdstate::Accepted);
+        // readers see the last confirmed holdstate while the write is pending
+        assert_eq!(cache.cached(HASH), Some(open));
+
+        let accepted = tokio::time::timeout(Duration::from_secs(5), slow)
+            .await
+            .expect("slow write never finished")
+            .unwrap()
+            .unwrap();
+        assert_eq!(cache.cached(HASH), Some(accepted));
+    }
+}
@@ -1,58 +1,437 @@
-use std::time::Duration;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Error;
 use cln_plugin::Plugin;
-use cln_rpc::{model::requests::ListinvoicesRequest, ClnRpc};
-use log::info;
-use tokio::time::{self, Instant};
+use cln_rpc::{
+    model::{
+        requests::{
+            DelinvoiceRequest,
+            DelinvoiceStatus,
+            ListinvoicesIndex,
+            ListinvoicesRequest,
+            ListpeerchannelsRequest,
+        },
+        responses::{ListinvoicesInvoicesStatus, ListpeerchannelsChannelsHtlcsDirection},
+    },
+    primitives::{HtlcState, ShortChannelId},
+};
+use log::{info, warn};
+use tokio::{
+    sync::OwnedRwLockWriteGuard,
+    time::{self, Instant},
+};
 
 use crate::{
-    model::PluginState,
-    rpc::{del_datastore_state, listdatastore_all},
-    util::make_rpc_path,
+    metrics::HoldMetrics,
+    model::{
+        is_closing,
+        AutocleanReason,
+        AutocleanRules,
+        HoldAutocleanEntry,
+        HoldAutocleanResponse,
+        HoldHtlc,
+        HoldInitiator,
+        HoldStateReason,
+        Holdstate,
+        HtlcIdentifier,
+        PluginState,
+        HOLD_AUTOCLEAN_STARTUP_DELAY,
+        HOLD_LIST_PAGE_SIZE,
+    },
+    pool::PooledRpc,
+    rpc::{
+        del_datastore_holdinvoice,
+        listdatastore_all,
+        listdatastore_history,
+        listdatastore_hold_offers,
+        listdatastore_htlc_set,
+        listdatastore_invoice,
+    },
+    util::{
+        global_autoclean_rules,
+        load_holdinvoice,
+        new_transition,
+        record_transition,
+        send_state_event,
+    },
+    OPT_AUTOCLEAN_CYCLE,
 };
 
 pub async fn autoclean_holdinvoice_db(plugin: Plugin<PluginState>) -> Result<(), Error> {
-    time::sleep(Duration::from_secs(120)).await;
+    let cycle = plugin.option(&OPT_AUTOCLEAN_CYCLE)? as u64;
+    if cycle == 0 {
+        info!("`holdinvoice-autoclean-cycle` is 0, autoclean only runs on holdinvoiceautoclean");
+        return Ok(());
+    }
+    time::sleep(Duration::from_secs(HOLD_AUTOCLEAN_STARTUP_DELAY)).await;
     info!("Starting autoclean_holdinvoice_db");
 
-    let rpc_path = make_rpc_path(plugin.clone());
     loop {
-        let now = Instant::now();
-        let mut count = 0;
-        {
-            let mut rpc = ClnRpc::new(&rpc_path).await?;
-            let node_invoices = rpc
-                .call_typed(&ListinvoicesRequest {
-                    index: None,
-                    invstring: None,
-                    label: None,
-                    limit: None,
-                    offer_id: None,
-                    payment_hash: None,
-                    start: None,
-                })
-                .await?
-                .invoices;
+        if let Err(e) = autoclean_pass(&plugin).await {
+            warn!("Error cleaning up holdinvoice database: {}", e);
+        }
+        time::sleep(Duration::from_secs(cycle)).await;
+    }
+}
+
+/// Removes the datastore entries of holdinvoices whose invoice cln no longer
+/// has and of holdinvoices past the retention for their holdstate
+pub async fn autoclean_pass(plugin: &Plugin<PluginState>) -> Result<HoldAutocleanResponse, Error> {
+    let _lock = plugin.state().autoclean_lock.lock().await;
+    let now = Instant::now();
+    let rules = global_autoclean_rules(plugin)?;
+    let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut rpc = plugin.state().rpc_pool.get().await?;
+
+    let mut unseen: HashSet<String> = listdatastore_all(&mut rpc)
+        .await?
+        .datastore
+        .into_iter()
+        .filter_map(|data| data.key.get(1).cloned())
+        .collect();
+    unseen.extend(plugin.state().holdstates.pay_hashes(&mut rpc).await?);
+    let mut removed = Vec::new();
+
+    let mut start = 0;
+    loop {
+        let page_start = start;
+        let invoices = rpc
+            .call_typed(&ListinvoicesRequest {
+                index: Some(ListinvoicesIndex::CREATED),
+                invstring: None,
+                label: None,
+                limit: Some(HOLD_LIST_PAGE_SIZE),
+                offer_id: None,
+                payment_hash: None,
+                start: Some(start),
+            })
+            .await?
+            .invoices;
+        for inv in invoices {
+            if let Some(created_index) = inv.created_index {
+                start = created_index + 1;
+            }
+            let pay_hash = inv.payment_hash.to_string();
+            if !unseen.remove(&pay_hash) {
+                continue;
+            }
+            let expired = inv.status == ListinvoicesInvoicesStatus::EXPIRED;
+            let final_at = match inv.status {
+                ListinvoicesInvoicesStatus::PAID => inv.paid_at,
+                _ => Some(inv.expires_at),
+            };
+            let candidate = retention_candidate(
+                plugin, &mut rpc, &rules, &pay_hash, expired, final_at, unix_now,
+            )
+            .await?;
+            let Some(state) = candidate else {
+                continue;
+            };
+            let mut invoice_deleted = false;
+            if rules.delinvoice && state != Holdstate::Settled {
+                let status = if expired {
+                    DelinvoiceStatus::EXPIRED
+                } else {
+                    DelinvoiceStatus::UNPAID
+                };
+                if let Err(e) = rpc
+                    .call_typed(&DelinvoiceRequest {
+                        desconly: None,
+                        status,
+                        label: inv.label.clone(),
+                    })
+                    .await
+                {
+                    warn!(
+                        "Error deleting invoice for payment_hash: {} {}",
+                        pay_hash, e
+                    );
+                    continue;
+                }
+                invoice_deleted = true;
+            }
+            remove_holdinvoice(plugin, &mut rpc, &pay_hash).await?;
+            removed.push(HoldAutocleanEntry {
+                payment_hash: pay_hash,
+                state: Some(state),
+                reason: AutocleanReason::Retention,
+                invoice_deleted,
+            });
+        }
+        if start == page_start {
+            break;
+        }
+    }
+
+    // hash-only holdinvoices are never in cln's invoice table
+    for pay_hash in unseen {
+        let (candidate, reason) = match listdatastore_invoice(&mut rpc, pay_hash.clone()).await? {
+            Some(hash_only) => (
+                retention_candidate(
+                    plugin,
+                    &mut rpc,
+                    &rules,
+                    &pay_hash,
+                    hash_only.expires_at <= unix_now,
+                    Some(hash_only.expires_at),
+                    unix_now,
+                )
+                .await?,
+                AutocleanReason::Retention,
+            ),
+            None if plugin.state().holdinvoices.contains(&pay_hash) => continue,
+            None => (
+                plugin
+                    .state()
+                    .holdstates
+                    .get(&mut rpc, &pay_hash)
+                    .await?
+                    .map(|s| s.state),
+                AutocleanReason::Orphaned,
+            ),
+        };
+        if reason == AutocleanReason::Retention && candidate.is_none() {
+            continue;
+        }
+        remove_holdinvoice(plugin, &mut rpc, &pay_hash).await?;
+        removed.push(HoldAutocleanEntry {
+            payment_hash: pay_hash,
+            state: candidate,
+            reason,
+            invoice_deleted: false,
+        });
+    }
+
+    info!(
+        "cleaned up {} holdinvoice database entries in {}ms",
+        removed.len(),
+        now.elapsed().as_millis()
+    );
+    Ok(HoldAutocleanResponse { removed })
+}
+
+/// The holdstate if the holdinvoice is past its retention. `final_at` is only
+/// used for holdinvoices that predate the recorded history.
+async fn retention_candidate(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    rules: &AutocleanRules,
+    pay_hash: &str,
+    expired: bool,
+    final_at: Option<u64>,
+    now: u64,
+) -> Result<Option<Holdstate>, Error> {
+    if plugin.state().holdinvoices.contains(pay_hash) {
+        return Ok(None);
+    }
+    let state = match plugin.state().holdstates.get(rpc, pay_hash).await? {
+        Some(s) => s.state,
+        None => return Ok(None),
+    };
+    let retention = match state {
+        Holdstate::Settled => rules.settled_age,
+        Holdstate::Canceled => rules.canceled_age,
+        // expired OPEN holdinvoices can never be paid anymore
+        Holdstate::Open if expired => rules.canceled_age,
+        Holdstate::Open | Holdstate::Accepted => None,
+    };
+    let Some(retention) = retention else {
+        return Ok(None);
+    };
+    let final_at = match listdatastore_history(rpc, pay_hash.to_owned()).await?.pop() {
+        Some(t) if state != Holdstate::Open => Some(t.timestamp),
+        _ => final_at,
+    };
+    match final_at {
+        Some(t) if t.saturating_add(retention) <= now => Ok(Some(state)),
+        _ => Ok(None),
+    }
+}
 
-            let payment_hashes: Vec<String> = node_invoices
+async fn remove_holdinvoice(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    pay_hash: &str,
+) -> Result<(), Error> {
+    del_datastore_holdinvoice(rpc, pay_hash.to_owned()).await?;
+    plugin.state().holdstates.remove(pay_hash);
+    Ok(())
+}
+
+/// Restores the HTLC's of ACCEPTED holdinvoices that cln still has, so the
+/// holdinvoices stay ACCEPTED while cln replays them, and counts the replays
+/// the plugin has to wait for before it is ready. Also learns which peers are
+/// disconnected and which channels are closing. Replayed htlcs wait in the
+/// hook until `_restore_guard` is dropped.
+pub async fn restore_accepted_htlcs(
+    plugin: Plugin<PluginState>,
+    _restore_guard: OwnedRwLockWriteGuard<()>,
+) -> Result<(), Error> {
+    let now = Instant::now();
+    let mut rpc = plugin.state().rpc_pool.get().await?;
+
+    let mut pending = HashMap::new();
+    let mut replays = Vec::new();
+    let channels = rpc
+        .call_typed(&ListpeerchannelsRequest {
+            id: None,
+            short_channel_id: None,
+        })
+        .await?
+        .channels;
+    for chan in channels {
+        // htlcs can arrive on the alias of unannounced channels
+        let scids: Vec<ShortChannelId> = chan
+            .short_channel_id
+            .into_iter()
+            .chain(chan.alias.and_then(|a| a.local))
+            .collect();
+        // notifications only tell us about changes from here on
+        let health = &plugin.state().channel_health;
+        health.set_peer_connected(chan.peer_id, chan.peer_connected);
+        if is_closing(chan.state) {
+            health.set_channel_closing(&scids, true);
+        }
+        for htlc in chan.htlcs.unwrap_or_default() {
+            if !matches!(htlc.direction, ListpeerchannelsChannelsHtlcsDirection::IN) {
+                continue;
+            }
+            let idents: Vec<HtlcIdentifier> = scids
                 .iter()
-                .map(|invoice| invoice.payment_hash.to_string())
+                .map(|scid| HtlcIdentifier {
+                    scid: *scid,
+                    htlc_id: htlc.id,
+                })
                 .collect();
+            for ident in &idents {
+                pending.insert(*ident, htlc.payment_hash.to_string());
+            }
+            // only fully committed and unresolved htlcs are replayed right away
+            if matches!(htlc.state, HtlcState::RCVD_ADD_ACK_REVOCATION) {
+                replays.push((idents, htlc.payment_hash.to_string()));
+            }
+        }
+    }
 
-            let datastore = listdatastore_all(&mut rpc).await?.datastore;
-            for data in datastore {
-                if !payment_hashes.contains(&data.key[1]) {
-                    let _res = del_datastore_state(&mut rpc, data.key[1].clone()).await;
-                    count += 1;
+    let holdstates = plugin.state().holdstates.load(&mut rpc).await?;
+    info!("loaded {} holdstates", holdstates);
+    let hold_offers = listdatastore_hold_offers(&mut rpc).await?;
+    info!("loaded {} hold offers", hold_offers.len());
+    plugin.state().hold_offers.replace(hold_offers);
+
+    replays.retain(|(_, pay_hash)| plugin.state().holdstates.cached(pay_hash).is_some());
+    info!(
+        "waiting for {} replayed htlcs of holdinvoices",
+        replays.len()
+    );
+    plugin.state().readiness.expect(replays);
+
+    let mut restored = 0;
+    let mut missing = 0;
+    let mut reopened = 0;
+    for (pay_hash, state) in plugin.state().holdstates.entries() {
+        if state.state != Holdstate::Accepted {
+            continue;
+        }
+        let htlc_set = listdatastore_htlc_set(&mut rpc, pay_hash.clone()).await?;
+        // without a stored set the replayed htlcs decide, if there are any
+        if htlc_set.is_none() && pending.values().any(|h| h == &pay_hash) {
+            continue;
+        }
+        let htlc_set = htlc_set.unwrap_or_default();
+        let mut holdinvoice = match load_holdinvoice(&plugin, &mut rpc, &pay_hash, state).await {
+            Ok(h) => h,
+            Err(e) => {
+                warn!("Error restoring htlcs for payment_hash: {} {}", pay_hash, e);
+                continue;
+            }
+        };
+        holdinvoice.total_msat = htlc_set.total_msat;
+
+        for record in htlc_set.htlcs {
+            if pending.get(&record.ident()) != Some(&pay_hash) {
+                warn!(
+                    "payment_hash: `{}` scid: `{}` htlc: `{}`. \
+                    Held htlc of {}msat did not come back after restart!",
+                    pay_hash, record.short_channel_id, record.htlc_id, record.amount_msat
+                );
+                missing += 1;
+                continue;
+            }
+            holdinvoice.htlc_data.insert(
+                record.ident(),
+                HoldHtlc {
+                    amount_msat: record.amount_msat,
+                    cltv_expiry: record.cltv_expiry,
+                    arrived_at: record.arrived_at,
+                    peer_id: record.peer_id,
+                },
+            );
+            restored += 1;
+        }
+
+        let amount_held_msat = holdinvoice.amount_held_msat();
+        if amount_held_msat < holdinvoice.accept_threshold_msat() {
+            match plugin
+                .state()
+                .holdstates
+                .update(&mut rpc, &pay_hash, Holdstate::Open, holdinvoice.generation)
+                .await
+            {
+                Ok(s) => {
+                    holdinvoice.hold_state = s.state;
+                    holdinvoice.generation = s.generation;
+                }
+                Err(e) => {
+                    HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+                    warn!("Error updating state for payment_hash: {} {}", pay_hash, e);
+                    continue;
                 }
             }
+            warn!(
+                "payment_hash: `{}`. Only {}msat of held htlcs came back after restart. \
+                No longer enough msats for holdinvoice! Back to OPEN state!",
+                pay_hash, amount_held_msat
+            );
+            record_transition(
+                &plugin,
+                &mut rpc,
+                &pay_hash,
+                new_transition(
+                    &plugin,
+                    Some(Holdstate::Accepted),
+                    Holdstate::Open,
+                    HoldStateReason::HtlcsMissing,
+                    HoldInitiator::Restart,
+                    amount_held_msat,
+                ),
+            )
+            .await;
+            send_state_event(
+                &plugin,
+                &mut rpc,
+                &pay_hash,
+                Holdstate::Open,
+                HoldStateReason::HtlcsMissing,
+                holdinvoice.event_invoice(),
+            )
+            .await;
+            reopened += 1;
+        }
+        if !holdinvoice.htlc_data.is_empty() {
+            plugin.state().holdinvoices.insert(pay_hash, holdinvoice);
         }
-        info!(
-            "cleaned up {} holdinvoice database entries in {}ms",
-            count,
-            now.elapsed().as_millis()
-        );
-        time::sleep(Duration::from_secs(3_600)).await;
     }
+
+    info!(
+        "restored {} held htlcs in {}ms, {} did not come back, {} holdinvoices back to OPEN",
+        restored,
+        now.elapsed().as_millis(),
+        missing,
+        reopened
+    );
+    Ok(())
 }
@@ -1,105 +1,774 @@
 use std::{
-    collections::BTreeMap,
+    collections::HashMap,
     path::{Path, PathBuf},
     str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use cln_plugin::Plugin;
+use anyhow::anyhow;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use cln_plugin::{Error, Plugin};
 use cln_rpc::{
-    model::requests::InvoiceRequest,
+    model::requests::{InvoiceRequest, ListinvoicesRequest},
     primitives::{Amount, AmountOrAny, ShortChannelId},
 };
+use log::warn;
 use serde_json::json;
 
 use crate::{
     errors::*,
-    model::{HoldInvoice, HtlcIdentifier, PluginState},
+    metrics::HoldMetrics,
+    model::{
+        AutocleanRules,
+        ExpiryPolicy,
+        HoldEventHtlc,
+        HoldEventInvoice,
+        HoldEventType,
+        HoldHtlc,
+        HoldInitiator,
+        HoldInvoice,
+        HoldInvoiceEvent,
+        HoldInvoiceListFilter,
+        HoldStateReason,
+        HoldTransition,
+        Holdstate,
+        HtlcIdentifier,
+        InvoiceSelector,
+        OverpayLimit,
+        PluginState,
+        SafetyMargins,
+        HOLD_METADATA_MAX_SIZE,
+        HOLD_NOTIFICATION_ACCEPTED,
+        HOLD_NOTIFICATION_CANCELED,
+        HOLD_NOTIFICATION_SETTLED,
+    },
+    pool::PooledRpc,
+    rpc::{
+        datastore_append_history,
+        invoice_label_amount,
+        listdatastore_invoice,
+        listdatastore_max_overpay,
+        listdatastore_metadata,
+        listdatastore_min_amount,
+        listdatastore_on_expiry,
+        listdatastore_safety_margins,
+    },
+    statecache::CachedState,
+    webhook::{enqueue_webhooks, is_valid_webhook_url},
+    OPT_AUTOCLEAN_CANCELED_AGE,
+    OPT_AUTOCLEAN_DELINVOICE,
+    OPT_AUTOCLEAN_SETTLED_AGE,
     OPT_CANCEL_HOLD_BEFORE_HTLC_EXPIRY_BLOCKS,
     OPT_CANCEL_HOLD_BEFORE_INVOICE_EXPIRY_SECONDS,
+    OPT_MAX_OVERPAY_MSAT,
+    OPT_MAX_OVERPAY_PERCENT,
+    OPT_ON_EXPIRY,
+    OPT_WEBHOOK_SECRET,
 };
 
 pub fn make_rpc_path(plugin: Plugin<PluginState>) -> PathBuf {
     Path::new(&plugin.configuration().lightning_dir).join(plugin.configuration().rpc_file)
 }
 
-pub async fn cleanup_pluginstate_holdinvoices(
-    hold_invoices: &mut BTreeMap<String, HoldInvoice>,
+pub fn cleanup_pluginstate_holdinvoices(
+    plugin: &Plugin<PluginState>,
     pay_hash: &str,
     global_htlc_ident: &HtlcIdentifier,
 ) {
-    if let Some(h_inv) = hold_invoices.get_mut(pay_hash) {
-        h_inv.htlc_data.remove(global_htlc_ident);
-        if h_inv.htlc_data.is_empty() {
-            hold_invoices.remove(pay_hash);
+    if let Some((htlc, hold_state, metadata)) = plugin
+        .state()
+        .holdinvoices
+        .remove_htlc(pay_hash, global_htlc_ident)
+    {
+        send_htlc_event(
+            plugin,
+            pay_hash,
+            hold_state,
+            HoldEventType::HtlcRemoved,
+            global_htlc_ident,
+            &htlc,
+            metadata.as_ref(),
+        );
+    }
+}
+
+/// Builds the in-memory holdinvoice for `pay_hash` from cln and our datastore,
+/// without any HTLC's
+pub async fn load_holdinvoice(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    pay_hash: &str,
+    state: CachedState,
+) -> Result<HoldInvoice, Error> {
+    let CachedState {
+        state: hold_state,
+        generation,
+    } = state;
+
+    let invoices = rpc
+        .call_typed(&ListinvoicesRequest {
+            index: None,
+            invstring: None,
+            label: None,
+            limit: None,
+            offer_id: None,
+            payment_hash: Some(pay_hash.to_owned()),
+            start: None,
+        })
+        .await?
+        .invoices;
+    let (invoice, hash_only) = if let Some(inv) = invoices.into_iter().next() {
+        (inv, None)
+    } else {
+        let h = listdatastore_invoice(rpc, pay_hash.to_owned())
+            .await?
+            .ok_or(anyhow!(
+                "payment_hash: `{}`. holdinvoice not found!",
+                pay_hash
+            ))?;
+        let inv = h.to_listinvoices_invoice(
+            Sha256::from_str(pay_hash)?,
+            SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        );
+        (inv, Some(h))
+    };
+    let max_overpay = match listdatastore_max_overpay(rpc, pay_hash.to_owned()).await? {
+        Some(m) => m,
+        None => global_max_overpay(plugin)?,
+    };
+    let (cancel_before_htlc_expiry, cancel_before_invoice_expiry) = resolve_safety_margins(
+        plugin,
+        listdatastore_safety_margins(rpc, pay_hash.to_owned()).await?,
+    )?;
+    let on_expiry = match listdatastore_on_expiry(rpc, pay_hash.to_owned()).await? {
+        Some(p) => p,
+        None => global_on_expiry(plugin)?,
+    };
+
+    Ok(HoldInvoice {
+        hold_state,
+        generation,
+        htlc_data: HashMap::new(),
+        invoice,
+        hash_only,
+        min_amount_msat: listdatastore_min_amount(rpc, pay_hash.to_owned()).await?,
+        total_msat: None,
+        max_overpay,
+        cancel_before_htlc_expiry,
+        cancel_before_invoice_expiry,
+        on_expiry,
+        metadata: listdatastore_metadata(rpc, pay_hash.to_owned()).await?,
+        wakeup: tokio::sync::watch::channel(()).0,
+    })
+}
+
+/// Tells webhooks, subscribers and other plugins about a holdstate change,
+/// right where it happened so nothing is lost if they can't keep up
+pub async fn send_state_event(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    pay_hash: &str,
+    state: Holdstate,
+    reason: HoldStateReason,
+    invoice: HoldEventInvoice,
+) {
+    if let Err(e) = enqueue_webhooks(plugin, rpc, pay_hash, state, reason, &invoice).await {
+        HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+        warn!(
+            "Error queueing webhook for payment_hash: {} {}",
+            pay_hash, e
+        );
+    }
+    let topic = match state {
+        Holdstate::Accepted => Some(HOLD_NOTIFICATION_ACCEPTED),
+        Holdstate::Settled => Some(HOLD_NOTIFICATION_SETTLED),
+        Holdstate::Canceled => Some(HOLD_NOTIFICATION_CANCELED),
+        Holdstate::Open => None,
+    };
+    if let Some(topic) = topic {
+        if let Err(e) = plugin
+            .send_custom_notification(
+                topic.to_owned(),
+                json!({
+                    "payment_hash": pay_hash,
+                    "label": invoice.label,
+                    "amount_msat": invoice.amount_msat,
+                    "state": state,
+                    "reason": reason,
+                    "metadata": invoice.metadata,
+                }),
+            )
+            .await
+        {
+            warn!(
+                "Error sending {} notification for payment_hash: {} {}",
+                topic, pay_hash, e
+            );
         }
     }
+    // only fails if there are no subscribers
+    let _ = plugin.state().events.send(HoldInvoiceEvent {
+        payment_hash: pay_hash.to_owned(),
+        event_type: HoldEventType::StateChanged,
+        state,
+        htlc: None,
+        reason: Some(reason),
+        label: invoice.label,
+        amount_msat: invoice.amount_msat,
+        metadata: invoice.metadata,
+    });
 }
 
-pub fn parse_payment_hash(args: serde_json::Value) -> Result<String, serde_json::Value> {
+/// For holdstate changes outside of the hold loops, from memory while the
+/// holdinvoice has HTLC's
+pub async fn event_invoice(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    pay_hash: &str,
+) -> Result<HoldEventInvoice, Error> {
+    if let Some(h) = plugin.state().holdinvoices.get(pay_hash) {
+        return Ok(h.lock().event_invoice());
+    }
+    let (label, amount_msat) = invoice_label_amount(rpc, pay_hash).await?;
+    Ok(HoldEventInvoice {
+        label,
+        amount_msat,
+        metadata: listdatastore_metadata(rpc, pay_hash.to_owned()).await?,
+    })
+}
+
+pub fn new_transition(
+    plugin: &Plugin<PluginState>,
+    from: Option<Holdstate>,
+    to: Holdstate,
+    reason: HoldStateReason,
+    initiator: HoldInitiator,
+    amount_held_msat: u64,
+) -> HoldTransition {
+    HoldTransition {
+        from,
+        to,
+        reason,
+        initiator,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        blockheight: *plugin.state().blockheight.lock(),
+        amount_held_msat,
+    }
+}
+
+pub async fn record_transition(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    payment_hash: &str,
+    transition: HoldTransition,
+) {
+    if let Err(e) = datastore_append_history(rpc, payment_hash.to_owned(), &transition).await {
+        HoldMetrics::inc(&plugin.state().metrics.datastore_errors);
+        warn!(
+            "Error recording history for payment_hash: {} {}",
+            payment_hash, e
+        );
+    }
+}
+
+pub fn amount_held_msat(plugin: &Plugin<PluginState>, pay_hash: &str) -> u64 {
+    plugin
+        .state()
+        .holdinvoices
+        .get(pay_hash)
+        .map(|h| h.lock().amount_held_msat())
+        .unwrap_or(0)
+}
+
+pub fn send_htlc_event(
+    plugin: &Plugin<PluginState>,
+    pay_hash: &str,
+    state: Holdstate,
+    event_type: HoldEventType,
+    global_htlc_ident: &HtlcIdentifier,
+    htlc: &HoldHtlc,
+    metadata: Option<&serde_json::Value>,
+) {
+    let _ = plugin.state().events.send(HoldInvoiceEvent {
+        payment_hash: pay_hash.to_owned(),
+        event_type,
+        state,
+        htlc: Some(HoldEventHtlc {
+            short_channel_id: global_htlc_ident.scid,
+            htlc_id: global_htlc_ident.htlc_id,
+            amount_msat: htlc.amount_msat,
+            cltv_expiry: htlc.cltv_expiry,
+        }),
+        metadata: metadata.cloned(),
+        reason: None,
+        label: None,
+        amount_msat: None,
+    });
+}
+
+/// Positional calls only take the payment_hash, label and bolt11 need named arguments
+pub fn parse_invoice_selector(
+    args: serde_json::Value,
+) -> Result<InvoiceSelector, serde_json::Value> {
+    let valid_arg_keys = ["payment_hash", "label", "bolt11"];
+
+    let new_args = match args {
+        serde_json::Value::Array(a) => {
+            if a.is_empty() {
+                return Err(missing_parameter_error("payment_hash"));
+            } else if a.len() != 1 {
+                return Err(too_many_params_error(a.len(), 1));
+            }
+            json!({"payment_hash": a[0]})
+        }
+        serde_json::Value::Object(o) => {
+            for (k, _v) in o.iter() {
+                if !valid_arg_keys.contains(&k.as_str()) {
+                    return Err(invalid_argument_error(k));
+                }
+            }
+            serde_json::Value::Object(o)
+        }
+        _ => return Err(invalid_input_error(&args.to_string())),
+    };
+
+    select_invoice(&new_args)
+}
+
+fn select_invoice(args: &serde_json::Value) -> Result<InvoiceSelector, serde_json::Value> {
+    let pay_hash = parse_optional_hash(args, "payment_hash")?;
+    let label = match args.get("label") {
+        Some(serde_json::Value::Null) | None => None,
+        Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+        Some(serde_json::Value::String(s)) => Some(s.clone()),
+        Some(e) => return Err(invalid_input_error(&e.to_string())),
+    };
+    let bolt11 = match args.get("bolt11") {
+        Some(serde_json::Value::Null) | None => None,
+        Some(serde_json::Value::String(s)) => Some(s.clone()),
+        Some(e) => return Err(invalid_input_error(&e.to_string())),
+    };
+
+    match (pay_hash, label, bolt11) {
+        (Some(ph), None, None) => Ok(InvoiceSelector::PaymentHash(ph)),
+        (None, Some(l), None) => Ok(InvoiceSelector::Label(l)),
+        (None, None, Some(b)) => Ok(InvoiceSelector::Bolt11(b)),
+        (None, None, None) => Err(missing_parameter_error("payment_hash")),
+        _ => Err(json!({
+            "code": -32602,
+            "message": "Must specify only one of payment_hash, label or bolt11"
+        })),
+    }
+}
+
+pub fn parse_offer_id(args: serde_json::Value) -> Result<String, serde_json::Value> {
+    parse_single_hash(args, "offer_id")
+}
+
+fn parse_single_hash(args: serde_json::Value, name: &str) -> Result<String, serde_json::Value> {
     if let serde_json::Value::Array(i) = args {
         if i.is_empty() {
-            Err(missing_parameter_error("payment_hash"))
+            Err(missing_parameter_error(name))
         } else if i.len() != 1 {
             Err(too_many_params_error(i.len(), 1))
         } else if let serde_json::Value::String(s) = i.first().unwrap() {
             if s.len() != 64 {
-                Err(invalid_hash_error("payment_hash", s))
+                Err(invalid_hash_error(name, s))
             } else {
                 Ok(s.clone())
             }
         } else {
-            Err(invalid_hash_error(
-                "payment_hash",
-                &i.first().unwrap().to_string(),
-            ))
+            Err(invalid_hash_error(name, &i.first().unwrap().to_string()))
         }
     } else if let serde_json::Value::Object(o) = args {
-        let valid_arg_keys = ["payment_hash"];
+        let valid_arg_keys = [name];
         for (k, _v) in o.iter() {
             if !valid_arg_keys.contains(&k.as_str()) {
                 return Err(invalid_argument_error(k));
             }
         }
-        if let Some(pay_hash) = o.get("payment_hash") {
-            if let serde_json::Value::String(s) = pay_hash {
+        if let Some(hash) = o.get(name) {
+            if let serde_json::Value::String(s) = hash {
                 if s.len() != 64 {
-                    Err(invalid_hash_error("payment_hash", s))
+                    Err(invalid_hash_error(name, s))
                 } else {
                     Ok(s.clone())
                 }
             } else {
-                Err(invalid_hash_error("payment_hash", &pay_hash.to_string()))
+                Err(invalid_hash_error(name, &hash.to_string()))
             }
         } else {
-            Err(missing_parameter_error("payment_hash"))
+            Err(missing_parameter_error(name))
         }
     } else {
         Err(invalid_input_error(&args.to_string()))
     }
 }
 
-pub fn build_invoice_request(
+pub fn parse_settle_args(
+    args: serde_json::Value,
+) -> Result<(InvoiceSelector, Option<String>), serde_json::Value> {
+    let valid_arg_keys = ["payment_hash", "preimage", "label", "bolt11"];
+
+    let mut new_args = serde_json::Value::Object(Default::default());
+    match args {
+        serde_json::Value::Array(a) => {
+            if a.len() > valid_arg_keys.len() {
+                return Err(too_many_params_error(a.len(), valid_arg_keys.len()));
+            }
+            for (idx, arg) in a.iter().enumerate() {
+                new_args[valid_arg_keys[idx]] = arg.clone();
+            }
+        }
+        serde_json::Value::Object(o) => {
+            for (k, v) in o.iter() {
+                if !valid_arg_keys.contains(&k.as_str()) {
+                    return Err(invalid_argument_error(k));
+                }
+                new_args[k] = v.clone();
+            }
+        }
+        _ => return Err(invalid_input_error(&args.to_string())),
+    };
+
+    let selector = select_invoice(&new_args)?;
+    let preimage = parse_optional_hash(&new_args, "preimage")?;
+    Ok((selector, preimage))
+}
+
+pub fn parse_bulk_args(
+    args: serde_json::Value,
+) -> Result<(Vec<String>, Option<String>), serde_json::Value> {
+    let valid_arg_keys = ["payment_hashes", "label_prefix"];
+
+    let mut new_args = serde_json::Value::Object(Default::default());
+    match args {
+        serde_json::Value::Array(a) => {
+            if a.len() > valid_arg_keys.len() {
+                return Err(too_many_params_error(a.len(), valid_arg_keys.len()));
+            }
+            for (idx, arg) in a.iter().enumerate() {
+                new_args[valid_arg_keys[idx]] = arg.clone();
+            }
+        }
+        serde_json::Value::Object(o) => {
+            for (k, v) in o.iter() {
+                if !valid_arg_keys.contains(&k.as_str()) {
+                    return Err(invalid_argument_error(k));
+                }
+                new_args[k] = v.clone();
+            }
+        }
+        _ => return Err(invalid_input_error(&args.to_string())),
+    };
+
+    let mut payment_hashes = Vec::new();
+    match new_args.get("payment_hashes") {
+        Some(serde_json::Value::Null) | None => (),
+        Some(serde_json::Value::Array(a)) => {
+            for pay_hash in a {
+                match pay_hash.as_str() {
+                    Some(s) if s.len() == 64 && hex::decode(s).is_ok() => {
+                        payment_hashes.push(s.to_lowercase())
+                    }
+                    _ => return Err(invalid_hash_error("payment_hashes", &pay_hash.to_string())),
+                }
+            }
+        }
+        Some(v) => {
+            return Err(json!({
+                "code": -32602,
+                "message": format!("payment_hashes: should be an array: \
+                invalid token '{}'", v)
+            }))
+        }
+    };
+
+    let label_prefix = match new_args.get("label_prefix") {
+        Some(serde_json::Value::Null) | None => None,
+        Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+        Some(serde_json::Value::String(s)) => Some(s.clone()),
+        Some(e) => return Err(invalid_input_error(&e.to_string())),
+    };
+
+    if payment_hashes.is_empty() == label_prefix.is_none() {
+        return Err(json!({
+            "code": -32602,
+            "message": "Must specify either payment_hashes or label_prefix"
+        }));
+    }
+
+    Ok((payment_hashes, label_prefix))
+}
+
+/// For methods without parameters
+pub fn parse_no_args(args: serde_json::Value) -> Result<(), serde_json::Value> {
+    match args {
+        serde_json::Value::Array(a) if !a.is_empty() => Err(too_many_params_error(a.len(), 0)),
+        serde_json::Value::Array(_) => Ok(()),
+        serde_json::Value::Object(o) => match o.keys().next() {
+            Some(k) => Err(invalid_argument_error(k)),
+            None => Ok(()),
+        },
+        _ => Err(invalid_input_error(&args.to_string())),
+    }
+}
+
+pub fn parse_wait_args(
+    args: serde_json::Value,
+) -> Result<(String, Vec<Holdstate>, Option<u64>), serde_json::Value> {
+    let valid_arg_keys = ["payment_hash", "states", "timeout"];
+
+    let mut new_args = serde_json::Value::Object(Default::default());
+    match args {
+        serde_json::Value::Array(a) => {
+            if a.len() > valid_arg_keys.len() {
+                return Err(too_many_params_error(a.len(), valid_arg_keys.len()));
+            }
+            for (idx, arg) in a.iter().enumerate() {
+                new_args[valid_arg_keys[idx]] = arg.clone();
+            }
+        }
+        serde_json::Value::Object(o) => {
+            for (k, v) in o.iter() {
+                if !valid_arg_keys.contains(&k.as_str()) {
+                    return Err(invalid_argument_error(k));
+                }
+                new_args[k] = v.clone();
+            }
+        }
+        _ => return Err(invalid_input_error(&args.to_string())),
+    };
+
+    let pay_hash = match parse_optional_hash(&new_args, "payment_hash")? {
+        Some(ph) => ph,
+        None => return Err(missing_parameter_error("payment_hash")),
+    };
+
+    // a single state is accepted without wrapping it in an array
+    let state_values = match new_args.get("states") {
+        Some(serde_json::Value::Null) | None => return Err(missing_parameter_error("states")),
+        Some(serde_json::Value::Array(a)) => a.clone(),
+        Some(v) => vec![v.clone()],
+    };
+    let mut states = Vec::new();
+    for value in state_values {
+        match value.as_str().map(Holdstate::from_str) {
+            Some(Ok(hs)) => states.push(hs),
+            _ => return Err(invalid_holdstate_error(&value.to_string())),
+        }
+    }
+    if states.is_empty() {
+        return Err(missing_parameter_error("states"));
+    }
+
+    let timeout = parse_optional_u64(&new_args, "timeout")?;
+    Ok((pay_hash, states, timeout))
+}
+
+pub fn parse_optional_hash(
+    args: &serde_json::Value,
+    name: &str,
+) -> Result<Option<String>, serde_json::Value> {
+    match args.get(name) {
+        Some(serde_json::Value::Null) | None => Ok(None),
+        Some(serde_json::Value::String(s)) => {
+            if s.len() != 64 || hex::decode(s).is_err() {
+                Err(invalid_hash_error(name, s))
+            } else {
+                Ok(Some(s.to_lowercase()))
+            }
+        }
+        Some(v) => Err(invalid_hash_error(name, &v.to_string())),
+    }
+}
+
+pub fn parse_webhook_url(
     args: &serde_json::Value,
     plugin: &Plugin<PluginState>,
+) -> Result<Option<String>, serde_json::Value> {
+    match args.get("webhook_url") {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(s)) => {
+            if !is_valid_webhook_url(s) {
+                Err(invalid_webhook_url_error(s))
+            } else if plugin.option(&OPT_WEBHOOK_SECRET).ok().flatten().is_none() {
+                Err(webhook_secret_missing_error())
+            } else {
+                Ok(Some(s.clone()))
+            }
+        }
+        Some(v) => Err(invalid_webhook_url_error(&v.to_string())),
+    }
+}
+
+/// `min_amount_msat` only makes sense for invoices without a fixed amount
+pub fn parse_min_amount(
+    args: &serde_json::Value,
+    amount_msat: &AmountOrAny,
+) -> Result<Option<u64>, serde_json::Value> {
+    let min_amount_msat = parse_optional_u64(args, "min_amount_msat")?;
+    if min_amount_msat.is_some() && !matches!(amount_msat, AmountOrAny::Any) {
+        return Err(json!({
+            "code": -32602,
+            "message": "min_amount_msat: only allowed with amount_msat 'any'"
+        }));
+    }
+    Ok(min_amount_msat)
+}
+
+pub fn parse_max_overpay(
+    args: &serde_json::Value,
+) -> Result<Option<OverpayLimit>, serde_json::Value> {
+    let max_overpay = OverpayLimit {
+        msat: parse_optional_u64(args, "max_overpay_msat")?,
+        percent: parse_optional_u64(args, "max_overpay_percent")?,
+    };
+    if max_overpay.is_unset() {
+        Ok(None)
+    } else {
+        Ok(Some(max_overpay))
+    }
+}
+
+/// The overpay limit for invoices that don't bring their own
+pub fn global_max_overpay(plugin: &Plugin<PluginState>) -> Result<OverpayLimit, Error> {
+    Ok(OverpayLimit {
+        msat: plugin.option(&OPT_MAX_OVERPAY_MSAT)?.map(|m| m as u64),
+        percent: plugin.option(&OPT_MAX_OVERPAY_PERCENT)?.map(|p| p as u64),
+    })
+}
+
+pub fn global_autoclean_rules(plugin: &Plugin<PluginState>) -> Result<AutocleanRules, Error> {
+    Ok(AutocleanRules {
+        settled_age: plugin.option(&OPT_AUTOCLEAN_SETTLED_AGE)?.map(|a| a as u64),
+        canceled_age: plugin
+            .option(&OPT_AUTOCLEAN_CANCELED_AGE)?
+            .map(|a| a as u64),
+        delinvoice: plugin.option(&OPT_AUTOCLEAN_DELINVOICE)?,
+    })
+}
+
+pub fn parse_safety_margins(
+    args: &serde_json::Value,
+) -> Result<Option<SafetyMargins>, serde_json::Value> {
+    let cancel_before_htlc_expiry = match parse_optional_u64(args, "cancel_before_htlc_expiry")? {
+        Some(b) => match u32::try_from(b) {
+            Ok(b) if b > 0 => Some(b),
+            _ => {
+                return Err(invalid_integer_error(
+                    "cancel_before_htlc_expiry",
+                    &b.to_string(),
+                ))
+            }
+        },
+        None => None,
+    };
+    let cancel_before_invoice_expiry =
+        match parse_optional_u64(args, "cancel_before_invoice_expiry")? {
+            Some(0) => return Err(invalid_integer_error("cancel_before_invoice_expiry", "0")),
+            s => s,
+        };
+    if cancel_before_htlc_expiry.is_none() && cancel_before_invoice_expiry.is_none() {
+        Ok(None)
+    } else {
+        Ok(Some(SafetyMargins {
+            cancel_before_htlc_expiry,
+            cancel_before_invoice_expiry,
+        }))
+    }
+}
+
+/// Safety margins in blocks and seconds, the global options fill in what
+/// the holdinvoice doesn't set itself
+pub fn resolve_safety_margins(
+    plugin: &Plugin<PluginState>,
+    safety_margins: Option<SafetyMargins>,
+) -> Result<(u32, u64), Error> {
+    let safety_margins = safety_margins.unwrap_or_default();
+    Ok((
+        match safety_margins.cancel_before_htlc_expiry {
+            Some(b) => b,
+            None => plugin.option(&OPT_CANCEL_HOLD_BEFORE_HTLC_EXPIRY_BLOCKS)? as u32,
+        },
+        match safety_margins.cancel_before_invoice_expiry {
+            Some(s) => s,
+            None => plugin.option(&OPT_CANCEL_HOLD_BEFORE_INVOICE_EXPIRY_SECONDS)? as u64,
+        },
+    ))
+}
+
+pub fn global_on_expiry(plugin: &Plugin<PluginState>) -> Result<ExpiryPolicy, Error> {
+    ExpiryPolicy::from_str(&plugin.option(&OPT_ON_EXPIRY)?)
+}
+
+/// `on_expiry` of a new holdinvoice, defaults to `holdinvoice-on-expiry`
+pub fn parse_on_expiry(
+    args: &serde_json::Value,
+    plugin: &Plugin<PluginState>,
+) -> Result<ExpiryPolicy, serde_json::Value> {
+    match args.get("on_expiry") {
+        Some(serde_json::Value::Null) | None => {
+            global_on_expiry(plugin).map_err(|e| invalid_on_expiry_error(&e.to_string()))
+        }
+        Some(serde_json::Value::String(s)) => {
+            ExpiryPolicy::from_str(s).map_err(|_| invalid_on_expiry_error(s))
+        }
+        Some(v) => Err(invalid_on_expiry_error(&v.to_string())),
+    }
+}
+
+/// `metadata` must be a JSON object and is limited to `HOLD_METADATA_MAX_SIZE` bytes
+pub fn parse_metadata(
+    args: &serde_json::Value,
+) -> Result<Option<serde_json::Value>, serde_json::Value> {
+    match args.get("metadata") {
+        Some(serde_json::Value::Null) | None => Ok(None),
+        Some(m @ serde_json::Value::Object(_)) => {
+            let size = m.to_string().len();
+            if size > HOLD_METADATA_MAX_SIZE {
+                Err(invalid_metadata_error(&format!(
+                    "too large: {} bytes, maximum is {}",
+                    size, HOLD_METADATA_MAX_SIZE
+                )))
+            } else {
+                Ok(Some(m.clone()))
+            }
+        }
+        Some(_) => Err(invalid_metadata_error("should be a JSON object")),
+    }
+}
+
+pub fn build_invoice_request(
+    args: &serde_json::Value,
+    cancel_hold_before_htlc_expiry_blocks: u32,
+    cancel_hold_before_invoice_expiry_seconds: u64,
 ) -> Result<InvoiceRequest, serde_json::Value> {
-    let cancel_hold_before_invoice_expiry_seconds = plugin
-        .option(&OPT_CANCEL_HOLD_BEFORE_INVOICE_EXPIRY_SECONDS)
-        .unwrap() as u64;
-    let cancel_hold_before_htlc_expiry_blocks = plugin
-        .option(&OPT_CANCEL_HOLD_BEFORE_HTLC_EXPIRY_BLOCKS)
-        .unwrap() as u32;
     let amount_msat = if let Some(amt) = args.get("amount_msat") {
-        AmountOrAny::Amount(Amount::from_msat(if let Some(amt_u64) = amt.as_u64() {
-            amt_u64
-        } else {
-            return Err(invalid_integer_error(
-                "amount_msat|msatoshi",
-                &amt.to_string(),
-            ));
-        }))
+        match amt {
+            serde_json::Value::String(s) if s.eq_ignore_ascii_case("any") => AmountOrAny::Any,
+            serde_json::Value::String(s) => match Amount::try_from(s.as_str()) {
+                Ok(a) => AmountOrAny::Amount(a),
+                Err(_) => return Err(invalid_integer_error("amount_msat|msatoshi", s)),
+            },
+            _ => AmountOrAny::Amount(Amount::from_msat(if let Some(amt_u64) = amt.as_u64() {
+                amt_u64
+            } else {
+                return Err(invalid_integer_error(
+                    "amount_msat|msatoshi",
+                    &amt.to_string(),
+                ));
+            })),
+        }
     } else {
         return Err(missing_parameter_error("amount_msat|msatoshi"));
     };
+    if matches!(amount_msat, AmountOrAny::Amount(a) if a.msat() == 0) {
+        return Err(nonpositive_amount_error("amount_msat|msatoshi"));
+    }
 
     let label = if let Some(lbl) = args.get("label") {
         match lbl {
@@ -249,3 +918,99 @@ pub fn build_invoice_request(
         exposeprivatechannels,
     })
 }
+
+pub fn parse_list_filter(
+    args: serde_json::Value,
+) -> Result<HoldInvoiceListFilter, serde_json::Value> {
+    let valid_arg_keys = [
+        "state",
+        "label_prefix",
+        "created_after",
+        "created_before",
+        "start",
+        "limit",
+    ];
+
+    let mut new_args = serde_json::Value::Object(Default::default());
+    match args {
+        serde_json::Value::Array(a) => {
+            if a.len() > valid_arg_keys.len() {
+                return Err(too_many_params_error(a.len(), valid_arg_keys.len()));
+            }
+            for (idx, arg) in a.iter().enumerate() {
+                new_args[valid_arg_keys[idx]] = arg.clone();
+            }
+        }
+        serde_json::Value::Object(o) => {
+            for (k, v) in o.iter() {
+                if !valid_arg_keys.contains(&k.as_str()) {
+                    return Err(invalid_argument_error(k));
+                }
+                new_args[k] = v.clone();
+            }
+        }
+        _ => return Err(invalid_input_error(&args.to_string())),
+    };
+
+    let state = match new_args.get("state") {
+        Some(serde_json::Value::Null) | None => None,
+        Some(serde_json::Value::String(s)) => match Holdstate::from_str(s) {
+            Ok(hs) => Some(hs),
+            Err(_) => return Err(invalid_holdstate_error(s)),
+        },
+        Some(e) => return Err(invalid_holdstate_error(&e.to_string())),
+    };
+
+    let label_prefix = match new_args.get("label_prefix") {
+        Some(serde_json::Value::Null) | None => None,
+        Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+        Some(serde_json::Value::String(s)) => Some(s.clone()),
+        Some(e) => return Err(invalid_input_error(&e.to_string())),
+    };
+
+    let created_after = parse_optional_u64(&new_args, "created_after")?;
+    let created_before = parse_optional_u64(&new_args, "created_before")?;
+    let start = parse_optional_u64(&new_args, "start")?;
+    let limit = parse_optional_u64(&new_args, "limit")?;
+
+    let limit = if let Some(l) = limit {
+        if let Ok(l_u32) = u32::try_from(l) {
+            if l_u32 == 0 {
+                return Err(json!({
+                    "code": -32602,
+                    "message": "limit: needs to be greater than '0'"
+                }));
+            }
+            Some(l_u32)
+        } else {
+            return Err(invalid_integer_error("limit", &l.to_string()));
+        }
+    } else {
+        None
+    };
+
+    Ok(HoldInvoiceListFilter {
+        state,
+        label_prefix,
+        created_after,
+        created_before,
+        start,
+        limit,
+    })
+}
+
+fn parse_optional_u64(
+    args: &serde_json::Value,
+    name: &str,
+) -> Result<Option<u64>, serde_json::Value> {
+    match args.get(name) {
+        Some(serde_json::Value::Null) | None => Ok(None),
+        Some(v) => {
+            if let Some(v_u64) = v.as_u64() {
+                Ok(Some(v_u64))
+            } else {
+                Err(invalid_integer_error(name, &v.to_string()))
+            }
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Webhook delivery of holdstate changes. Every POST is written to an outbox
+//! in cln's datastore together with the holdstate change and only removed
+//! once the endpoint answered with a 2xx status, so undelivered events
+//! survive a restart and are retried with exponential backoff.
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, Error};
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use cln_plugin::Plugin;
+use hyper::{client::HttpConnector, header::CONTENT_TYPE, Body, Client, Request, Uri};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use log::{debug, info, warn};
+use tokio::time;
+
+use crate::{
+    model::{
+        HoldEventInvoice,
+        HoldStateReason,
+        Holdstate,
+        PluginState,
+        WebhookDelivery,
+        WebhookEvent,
+        HOLD_WEBHOOK_MAX_ATTEMPTS,
+        HOLD_WEBHOOK_MAX_BACKOFF,
+        HOLD_WEBHOOK_RETRY_INTERVAL,
+        HOLD_WEBHOOK_TIMEOUT,
+    },
+    pool::PooledRpc,
+    rpc::{
+        datastore_webhook_delivery,
+        del_datastore_webhook_delivery,
+        listdatastore_webhook_outbox,
+        listdatastore_webhook_url,
+    },
+    OPT_WEBHOOK_SECRET,
+    OPT_WEBHOOK_URL,
+};
+
+pub const SIGNATURE_HEADER: &str = "X-Holdinvoice-Signature";
+
+/// We only speak plain http, TLS should be terminated by a local proxy
+pub fn is_valid_webhook_url(url: &str) -> bool {
+    match url.parse::<Uri>() {
+        Ok(uri) => matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some(),
+        Err(_) => false,
+    }
+}
+
+pub fn sign_body(secret: &str, body: &str) -> String {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+    engine.input(body.as_bytes());
+    hmac::Hmac::<sha256::Hash>::from_engine(engine).to_string()
+}
+
+/// Sends what is in the outbox, woken up by new webhooks and otherwise
+/// sleeping until the next retry is due
+pub async fn deliver_webhooks(plugin: Plugin<PluginState>) -> Result<(), Error> {
+    let secret = match plugin.option(&OPT_WEBHOOK_SECRET)? {
+        Some(s) => s,
+        None => {
+            info!("`holdinvoice-webhook-secret` not set, webhooks are disabled");
+            return Ok(());
+        }
+    };
+    let connector = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build();
+    let client = Client::builder().build(connector);
+
+    loop {
+        let wait = match flush_outbox(&plugin, &client, &secret).await {
+            Ok(Some(next_attempt_at)) => next_attempt_at
+                .saturating_sub(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+                .max(1),
+            Ok(None) => HOLD_WEBHOOK_MAX_BACKOFF,
+            Err(e) => {
+                warn!("Error flushing webhook outbox: {}", e);
+                HOLD_WEBHOOK_RETRY_INTERVAL
+            }
+        };
+        tokio::select! {
+            _ = plugin.state().webhook_wakeup.notified() => (),
+            _ = time::sleep(Duration::from_secs(wait)) => (),
+        }
+    }
+}
+
+/// Writes the webhooks of a holdstate change to the outbox. Called where the
+/// change is recorded, so they are in the datastore before anyone is told.
+pub async fn enqueue_webhooks(
+    plugin: &Plugin<PluginState>,
+    rpc: &mut PooledRpc,
+    pay_hash: &str,
+    state: Holdstate,
+    reason: HoldStateReason,
+    invoice: &HoldEventInvoice,
+) -> Result<(), Error> {
+    if plugin.option(&OPT_WEBHOOK_SECRET)?.is_none() {
+        return Ok(());
+    }
+    let mut urls = Vec::new();
+    if let Some(url) = plugin.option(&OPT_WEBHOOK_URL)? {
+        urls.push(url);
+    }
+    if let Some(url) = listdatastore_webhook_url(rpc, pay_hash.to_owned()).await? {
+        if !urls.contains(&url) {
+            urls.push(url);
+        }
+    }
+    if urls.is_empty() {
+        return Ok(());
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+    // millis first so the outbox sorts by creation time
+    let event_id = format!(
+        "{:016}-{}",
+        now.as_millis(),
+        hex::encode(rand::random::<[u8; 8]>())
+    );
+    let body = serde_json::to_string(&WebhookEvent {
+        event_id: event_id.clone(),
+        payment_hash: pay_hash.to_owned(),
+        state,
+        reason: Some(reason),
+        label: invoice.label.clone(),
+        amount_msat: invoice.amount_msat,
+        metadata: invoice.metadata.clone(),
+        timestamp: now.as_secs(),
+    })?;
+
+    for (idx, url) in urls.into_iter().enumerate() {
+        datastore_webhook_delivery(
+            rpc,
+            format!("{}-{}", event_id, idx),
+            &WebhookDelivery {
+                url,
+                body: body.clone(),
+                attempts: 0,
+                next_attempt_at: 0,
+            },
+        )
+        .await?;
+    }
+    plugin.state().webhook_wakeup.notify_one();
+    Ok(())
+}
+
+/// Returns when the next delivery is due. No rpc connection is held while
+/// waiting for an endpoint.
+async fn flush_outbox(
+    plugin: &Plugin<PluginState>,
+    client: &Client<HttpsConnector<HttpConnector>>,
+    secret: &str,
+) -> Result<Option<u64>, Error> {
+    let outbox = {
+        let mut rpc = plugin.state().rpc_pool.get().await?;
+        listdatastore_webhook_outbox(&mut rpc).await?
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut next_attempt_at: Option<u64> = None;
+    // keep events in order per endpoint: once one is stuck, later ones wait
+    let mut blocked_urls = HashSet::new();
+    for (id, mut delivery) in outbox {
+        if blocked_urls.contains(&delivery.url) {
+            continue;
+        }
+        if delivery.next_attempt_at > now {
+            next_attempt_at = Some(next_attempt_at.map_or(delivery.next_attempt_at, |n| {
+                n.min(delivery.next_attempt_at)
+            }));
+            blocked_urls.insert(delivery.url);
+            continue;
+        }
+        let result = post(client, secret, &delivery).await;
+        let mut rpc = plugin.state().rpc_pool.get().await?;
+        match result {
+            Ok(()) => {
+                debug!("delivered webhook {} to {}", id, delivery.url);
+                del_datastore_webhook_delivery(&mut rpc, id).await?;
+            }
+            Err(e) => {
+                delivery.attempts += 1;
+                if delivery.attempts >= HOLD_WEBHOOK_MAX_ATTEMPTS {
+                    warn!(
+                        "Giving up on webhook {} to {} after {} attempts: {}",
+                        id, delivery.url, delivery.attempts, e
+                    );
+                    del_datastore_webhook_delivery(&mut rpc, id).await?;
+                    continue;
+                }
+                let backoff = HOLD_WEBHOOK_RETRY_INTERVAL
+                    .saturating_mul(1 << delivery.attempts.min(20))
+                    .min(HOLD_WEBHOOK_MAX_BACKOFF);
+                delivery.next_attempt_at = now + backoff;
+                next_attempt_at = Some(next_attempt_at.map_or(delivery.next_attempt_at, |n| {
+                    n.min(delivery.next_attempt_at)
+                }));
+                blocked_urls.insert(delivery.url.clone());
+                debug!(
+                    "Error delivering webhook {} to {}: {}. Retrying in {}s",
+                    id, delivery.url, e, backoff
+                );
+                datastore_webhook_delivery(&mut rpc, id, &delivery).await?;
+            }
+        }
+    }
+    Ok(next_attempt_at)
+}
+
+async fn post(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    secret: &str,
+    delivery: &WebhookDelivery,
+) -> Result<(), Error> {
+    let request = Request::post(&delivery.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(
+            SIGNATURE_HEADER,
+            format!("sha256={}", sign_body(secret, &delivery.body)),
+        )
+        .body(Body::from(delivery.body.clone()))?;
+    let response = time::timeout(
+        Duration::from_secs(HOLD_WEBHOOK_TIMEOUT),
+        client.request(request),
+    )
+    .await??;
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(anyhow!("endpoint answered with {}", response.status()))
+    }
+}
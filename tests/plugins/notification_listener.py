@@ -0,0 +1,37 @@
+#!/usr/bin/env python3
+"""Records the custom notifications of holdinvoice for the tests"""
+from pyln.client import Plugin
+
+plugin = Plugin()
+plugin.notifications = []
+
+
+def record(topic, origin, payload, kwargs):
+    # newer cln versions wrap the notification in an object named after the topic
+    if payload is None and topic in kwargs:
+        origin = kwargs[topic].get("origin")
+        payload = kwargs[topic].get("payload")
+    plugin.notifications.append({"topic": topic, "origin": origin, "payload": payload})
+
+
+@plugin.subscribe("holdinvoice_accepted")
+def on_accepted(plugin, origin=None, payload=None, **kwargs):
+    record("holdinvoice_accepted", origin, payload, kwargs)
+
+
+@plugin.subscribe("holdinvoice_settled")
+def on_settled(plugin, origin=None, payload=None, **kwargs):
+    record("holdinvoice_settled", origin, payload, kwargs)
+
+
+@plugin.subscribe("holdinvoice_canceled")
+def on_canceled(plugin, origin=None, payload=None, **kwargs):
+    record("holdinvoice_canceled", origin, payload, kwargs)
+
+
+@plugin.method("holdnotifications")
+def holdnotifications(plugin):
+    return {"notifications": plugin.notifications}
+
+
+plugin.run()
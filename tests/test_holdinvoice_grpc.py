@@ -12,6 +12,7 @@ import hold_pb2_grpc as holdstub
 import pytest
 from grpc._channel import _InactiveRpcError
 from pyln.testing.fixtures import *  # noqa: F403
+from pyln.testing.utils import wait_for
 from util import (
     find_unused_port,
     generate_random_label,
@@ -124,6 +125,19 @@ def test_inputs(node_factory, bitcoind, get_plugin):  # noqa: F811
     ):
         hold_stub.HoldInvoice(request)
 
+    # missing amount_msat without any_amount
+    request = holdrpc.HoldInvoiceRequest(
+        description="Missing amount",
+        label=generate_random_label(),
+        cltv=144,
+    )
+    with pytest.raises(
+        _InactiveRpcError,
+        match=r"amount_msat is required unless any_amount is set",
+    ) as e:
+        hold_stub.HoldInvoice(request)
+    assert e.value.code() == grpc.StatusCode.INVALID_ARGUMENT
+
     # Fallbacks not as a list of strings
     request = holdrpc.HoldInvoiceRequest(
         description="Invalid fallbacks",
@@ -433,3 +447,109 @@ def test_valid_hold_then_cancel(node_factory, bitcoind, get_plugin):  # noqa: F8
         match=r"Holdinvoice is in wrong state: \\\'CANCELED\\\'\\",
     ):
         hold_stub.HoldInvoiceSettle(request_settle_canceled)
+
+
+def test_subscribe(node_factory, bitcoind, get_plugin):  # noqa: F811
+    port = find_unused_port()
+    l1, l2 = node_factory.get_nodes(
+        2, opts=[{}, {"important-plugin": get_plugin, "grpc-hold-port": port}]
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    CLN_DIR = l2.rpc.getinfo()["lightning-dir"]
+
+    with open(os.path.join(CLN_DIR, "client.pem"), "rb") as f:
+        client_cert = f.read()
+    with open(os.path.join(CLN_DIR, "client-key.pem"), "rb") as f:
+        client_key = f.read()
+    with open(os.path.join(CLN_DIR, "server.pem"), "rb") as f:
+        server_cert = f.read()
+
+    os.environ["GRPC_SSL_CIPHER_SUITES"] = "HIGH+ECDSA"
+
+    creds = grpc.ssl_channel_credentials(
+        root_certificates=server_cert,
+        private_key=client_key,
+        certificate_chain=client_cert,
+    )
+    holdchannel = grpc.secure_channel(
+        f"localhost:{port}",
+        creds,
+        options=(("grpc.ssl_target_name_override", "cln"),),
+    )
+    hold_stub = holdstub.HoldStub(holdchannel)
+
+    result = hold_stub.HoldInvoice(
+        holdrpc.HoldInvoiceRequest(
+            description="Subscribe",
+            amount_msat=holdrpc.Amount(msat=1_000_000),
+            label=generate_random_label(),
+            cltv=144,
+        )
+    )
+    other = hold_stub.HoldInvoice(
+        holdrpc.HoldInvoiceRequest(
+            description="Subscribe other",
+            amount_msat=holdrpc.Amount(msat=1_000_000),
+            label=generate_random_label(),
+            cltv=144,
+        )
+    )
+
+    stream = hold_stub.SubscribeHoldInvoices(
+        holdrpc.SubscribeHoldInvoicesRequest(payment_hash=result.payment_hash)
+    )
+    events = []
+
+    def collect():
+        for event in stream:
+            events.append(event)
+            if (
+                event.event_type == holdrpc.HoldInvoiceEventType.STATE_CHANGED
+                and event.state == holdrpc.Holdstate.SETTLED
+            ):
+                break
+
+    collector = threading.Thread(target=collect)
+    collector.start()
+    # give the server a moment to register the subscriber
+    time.sleep(1)
+
+    hold_stub.HoldInvoiceCancel(
+        holdrpc.HoldInvoiceCancelRequest(payment_hash=other.payment_hash)
+    )
+
+    threading.Thread(target=pay_with_thread, args=(l1, result.bolt11)).start()
+
+    wait_for(
+        lambda: any(
+            e.event_type == holdrpc.HoldInvoiceEventType.STATE_CHANGED
+            and e.state == holdrpc.Holdstate.ACCEPTED
+            for e in events
+        )
+    )
+
+    hold_stub.HoldInvoiceSettle(
+        holdrpc.HoldInvoiceSettleRequest(payment_hash=result.payment_hash)
+    )
+    collector.join(timeout=30)
+    stream.cancel()
+
+    assert all(e.payment_hash == result.payment_hash for e in events)
+    added = [
+        e for e in events if e.event_type == holdrpc.HoldInvoiceEventType.HTLC_ADDED
+    ]
+    assert len(added) >= 1
+    assert added[0].htlc.short_channel_id == cl1
+    assert sum(e.htlc.amount_msat.msat for e in added) >= 1_000_000
+    states = [
+        e.state
+        for e in events
+        if e.event_type == holdrpc.HoldInvoiceEventType.STATE_CHANGED
+    ]
+    assert states[-2:] == [holdrpc.Holdstate.ACCEPTED, holdrpc.Holdstate.SETTLED]
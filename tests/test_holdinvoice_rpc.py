@@ -1,14 +1,21 @@
 #!/usr/bin/python
 
+import hashlib
+import hmac
+import json
 import secrets
 import threading
 import time
+import urllib.request
+from http.server import BaseHTTPRequestHandler, HTTPServer
+from pathlib import Path
 
 import pytest
 from pyln.client import Millisatoshi, RpcError
 from pyln.testing.fixtures import *  # noqa: F403
 from pyln.testing.utils import only_one, sync_blockheight, wait_for
 from util import (
+    find_unused_port,
     generate_random_label,
     generate_random_number,
     get_plugin,  # noqa: F401
@@ -877,3 +884,1673 @@ def test_hold_then_invoice_timeout_hard(node_factory, bitcoind, get_plugin):  #
     assert "state" in result_lookup
     assert result_lookup["state"] == "CANCELED"
     assert "htlc_expiry" not in result_lookup
+
+
+def test_list(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        }
+    )
+    prefix = generate_random_label()
+    payment_hashes = []
+    for i in range(3):
+        invoice = node.rpc.call(
+            "holdinvoice",
+            {
+                "amount_msat": 1_000_000 + i,
+                "description": "test_list",
+                "label": f"{prefix}-{i}",
+                "cltv": 144,
+            },
+        )
+        payment_hashes.append(invoice["payment_hash"])
+    node.rpc.call(
+        "invoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "not a holdinvoice",
+            "label": f"{prefix}-normal",
+        },
+    )
+
+    result_list = node.rpc.call("holdinvoicelist", {"label_prefix": prefix})
+    assert len(result_list["holdinvoices"]) == 3
+    for holdinvoice in result_list["holdinvoices"]:
+        assert holdinvoice["payment_hash"] in payment_hashes
+        assert holdinvoice["state"] == "OPEN"
+        assert holdinvoice["amount_held_msat"] == 0
+        assert "expires_at" in holdinvoice
+        assert "created_at" in holdinvoice
+
+    node.rpc.call("holdinvoicecancel", {"payment_hash": payment_hashes[0]})
+    result_list = node.rpc.call(
+        "holdinvoicelist", {"label_prefix": prefix, "state": "CANCELED"}
+    )
+    assert len(result_list["holdinvoices"]) == 1
+    assert result_list["holdinvoices"][0]["payment_hash"] == payment_hashes[0]
+
+    result_list = node.rpc.call("holdinvoicelist", {"label_prefix": prefix, "limit": 2})
+    assert len(result_list["holdinvoices"]) == 2
+    next_start = result_list["holdinvoices"][-1]["created_index"] + 1
+    result_list = node.rpc.call(
+        "holdinvoicelist", {"label_prefix": prefix, "start": next_start}
+    )
+    assert len(result_list["holdinvoices"]) == 1
+    assert result_list["holdinvoices"][0]["payment_hash"] == payment_hashes[2]
+    assert "next_start" not in result_list
+
+    hash_only_hashes = []
+    for i in range(3):
+        invoice = node.rpc.call(
+            "holdinvoice",
+            {
+                "amount_msat": 1_000_000,
+                "description": "test_list hash-only",
+                "label": f"{prefix}-hash-only-{i}",
+                "cltv": 144,
+                "payment_hash": hashlib.sha256(secrets.token_bytes(32)).hexdigest(),
+            },
+        )
+        hash_only_hashes.append(invoice["payment_hash"])
+
+    listed = []
+    params = {"label_prefix": prefix, "limit": 2}
+    while True:
+        result_list = node.rpc.call("holdinvoicelist", params)
+        listed.extend(h["payment_hash"] for h in result_list["holdinvoices"])
+        if "next_start" not in result_list:
+            break
+        params["start"] = result_list["next_start"]
+    assert len(listed) == len(set(listed))
+    assert set(listed) == set(payment_hashes + hash_only_hashes)
+    assert listed[:3] == payment_hashes
+
+    result_list = node.rpc.call(
+        "holdinvoicelist", {"label_prefix": prefix, "created_before": 1}
+    )
+    assert len(result_list["holdinvoices"]) == 0
+
+    result_list = node.rpc.call("holdinvoicelist", {"state": "PAID"})
+    expected_message = (
+        "state: should be one of OPEN, ACCEPTED, SETTLED or CANCELED: "
+        "invalid token 'PAID'"
+    )
+    assert result_list["message"] == expected_message
+
+
+def test_hash_only_hold_then_settle(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    preimage = secrets.token_hex(32)
+    payment_hash = hashlib.sha256(bytes.fromhex(preimage)).hexdigest()
+
+    result = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 10_000_000,
+            "description": "hash-only with preimage",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "payment_hash": payment_hash,
+            "preimage": preimage,
+        },
+    )
+    expected_message = (
+        "preimage: not supported for holdinvoices created with payment_hash"
+    )
+    assert result["message"] == expected_message
+
+    invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 10_000_000,
+            "description": "test_hash_only_hold_then_settle",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "payment_hash": payment_hash,
+        },
+    )
+    assert invoice["payment_hash"] == payment_hash
+    decoded = l2.rpc.call("decode", {"string": invoice["bolt11"]})
+    assert decoded["payment_hash"] == payment_hash
+    assert decoded["amount_msat"] == 10_000_000
+    assert decoded["payee"] == l2.info["id"]
+    assert (
+        l2.rpc.call("listinvoices", {"payment_hash": payment_hash})["invoices"] == []
+    )
+
+    result_lookup = l2.rpc.call("holdinvoicelookup", {"payment_hash": payment_hash})
+    assert result_lookup["state"] == "OPEN"
+
+    threading.Thread(target=pay_with_thread, args=(l1, invoice["bolt11"])).start()
+
+    wait_for(
+        lambda: l2.rpc.call("holdinvoicelookup", {"payment_hash": payment_hash})[
+            "state"
+        ]
+        == "ACCEPTED"
+    )
+
+    result_settle = l2.rpc.call("holdinvoicesettle", {"payment_hash": payment_hash})
+    assert result_settle["message"] == "missing required parameter: preimage"
+
+    wrong_preimage = secrets.token_hex(32)
+    result_settle = l2.rpc.call(
+        "holdinvoicesettle",
+        {"payment_hash": payment_hash, "preimage": wrong_preimage},
+    )
+    expected_message = (
+        f"preimage '{wrong_preimage}' does not match payment_hash '{payment_hash}'"
+    )
+    assert result_settle["message"] == expected_message
+
+    result_settle = l2.rpc.call(
+        "holdinvoicesettle", {"payment_hash": payment_hash, "preimage": preimage}
+    )
+    assert result_settle["state"] == "SETTLED"
+
+    result_lookup = l2.rpc.call("holdinvoicelookup", {"payment_hash": payment_hash})
+    assert result_lookup["state"] == "SETTLED"
+
+    wait_for(
+        lambda: only_one(
+            l1.rpc.call("listpays", {"payment_hash": payment_hash})["pays"]
+        )["status"]
+        == "complete"
+    )
+    payres = only_one(
+        l1.rpc.call("listpays", {"payment_hash": payment_hash})["pays"]
+    )
+    assert payres["preimage"] == preimage
+
+
+def test_webhook(node_factory, get_plugin):  # noqa: F811
+    received = []
+    failures = [1]
+
+    class Handler(BaseHTTPRequestHandler):
+        def do_POST(self):
+            body = self.rfile.read(int(self.headers["Content-Length"]))
+            if failures[0] > 0:
+                failures[0] -= 1
+                self.send_response(500)
+            else:
+                received.append((self.headers["X-Holdinvoice-Signature"], body))
+                self.send_response(200)
+            self.end_headers()
+
+        def log_message(self, format, *args):
+            pass
+
+    server = HTTPServer(("localhost", 0), Handler)
+    threading.Thread(target=server.serve_forever, daemon=True).start()
+    url = f"http://localhost:{server.server_address[1]}/hook"
+    secret = "webhooksecret"
+
+    node = node_factory.get_node(
+        options={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+            "holdinvoice-webhook-secret": secret,
+        }
+    )
+
+    result = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "webhook",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "webhook_url": "ftp://localhost/hook",
+        },
+    )
+    assert result["message"] == (
+        "webhook_url: should be an http or https url: invalid token "
+        "'ftp://localhost/hook'"
+    )
+
+    label = generate_random_label()
+    invoice = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "webhook",
+            "label": label,
+            "cltv": 144,
+            "webhook_url": url,
+        },
+    )
+    node.rpc.call("holdinvoicecancel", {"payment_hash": invoice["payment_hash"]})
+
+    # first delivery fails and is retried from the outbox
+    wait_for(lambda: len(received) == 2, timeout=60)
+    events = []
+    for signature, body in received:
+        expected = hmac.new(secret.encode(), body, hashlib.sha256).hexdigest()
+        assert signature == f"sha256={expected}"
+        events.append(json.loads(body))
+
+    assert [e["state"] for e in events] == ["OPEN", "CANCELED"]
+    assert [e["reason"] for e in events] == ["created", "cancel_requested"]
+    assert all(e["payment_hash"] == invoice["payment_hash"] for e in events)
+    assert all(e["label"] == label for e in events)
+    assert all(e["amount_msat"] == 1_000_000 for e in events)
+    wait_for(
+        lambda: node.rpc.call(
+            "listdatastore", {"key": ["holdinvoice", "webhook_outbox"]}
+        )["datastore"]
+        == []
+    )
+    server.shutdown()
+
+
+def test_wait(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        }
+    )
+    invoice = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "wait",
+            "label": generate_random_label(),
+            "cltv": 144,
+        },
+    )
+    payment_hash = invoice["payment_hash"]
+
+    result = node.rpc.call(
+        "holdinvoicewait", {"payment_hash": payment_hash, "states": "OPEN"}
+    )
+    assert result["state"] == "OPEN"
+
+    result = node.rpc.call(
+        "holdinvoicewait",
+        {"payment_hash": payment_hash, "states": ["ACCEPTED"], "timeout": 1},
+    )
+    assert result["code"] == 904
+
+    result = node.rpc.call(
+        "holdinvoicewait", {"payment_hash": payment_hash, "states": ["PAID"]}
+    )
+    assert result["code"] == -32602
+
+    waited = []
+
+    def wait_thread():
+        waited.append(
+            node.rpc.call(
+                "holdinvoicewait",
+                {
+                    "payment_hash": payment_hash,
+                    "states": ["SETTLED", "CANCELED"],
+                    "timeout": 30,
+                },
+            )
+        )
+
+    waiter = threading.Thread(target=wait_thread)
+    waiter.start()
+    time.sleep(1)
+    assert waited == []
+
+    node.rpc.call("holdinvoicecancel", {"payment_hash": payment_hash})
+    waiter.join(timeout=30)
+    assert waited == [{"state": "CANCELED"}]
+
+
+def test_cancel_many(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        }
+    )
+    prefix = generate_random_label()
+    payment_hashes = []
+    for i in range(3):
+        invoice = node.rpc.call(
+            "holdinvoice",
+            {
+                "amount_msat": 1_000_000,
+                "description": "cancel many",
+                "label": f"{prefix}-{i}",
+                "cltv": 144,
+            },
+        )
+        payment_hashes.append(invoice["payment_hash"])
+    other = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "cancel many other",
+            "label": generate_random_label(),
+            "cltv": 144,
+        },
+    )
+
+    result = node.rpc.call("holdinvoicecancelmany", {})
+    assert result["message"] == "Must specify either payment_hashes or label_prefix"
+
+    result = node.rpc.call(
+        "holdinvoicecancelmany", {"payment_hashes": [payment_hashes[0]]}
+    )
+    assert result["results"] == [
+        {"payment_hash": payment_hashes[0], "state": "CANCELED"}
+    ]
+
+    result = node.rpc.call("holdinvoicesettlemany", {"label_prefix": prefix})
+    assert len(result["results"]) == 3
+    for res in result["results"]:
+        assert "state" not in res
+        assert res["error"]["message"].startswith("Holdinvoice is in wrong state")
+
+    result = node.rpc.call("holdinvoicecancelmany", {"label_prefix": prefix})
+    assert sorted(r["payment_hash"] for r in result["results"]) == sorted(
+        payment_hashes
+    )
+    assert all(r["state"] == "CANCELED" for r in result["results"])
+
+    result_lookup = node.rpc.call(
+        "holdinvoicelookup", {"payment_hash": other["payment_hash"]}
+    )
+    assert result_lookup["state"] == "OPEN"
+
+
+def test_any_amount_hold_then_settle(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    result = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "fixed amount with min",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "min_amount_msat": 1_000,
+        },
+    )
+    assert result["message"] == "min_amount_msat: only allowed with amount_msat 'any'"
+
+    small = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": "any",
+            "description": "any amount too small",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "min_amount_msat": 5_000_000,
+        },
+    )
+    with pytest.raises(RpcError):
+        l1.rpc.call(
+            "pay",
+            {
+                "bolt11": small["bolt11"],
+                "amount_msat": 1_000_000,
+                "dev_use_shadow": False,
+                "retry_for": 10,
+            },
+        )
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": small["payment_hash"]}
+    )
+    assert result_lookup["state"] == "OPEN"
+
+    invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": "any",
+            "description": "any amount",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "min_amount_msat": 5_000_000,
+        },
+    )
+
+    def pay_any():
+        try:
+            l1.rpc.call(
+                "pay",
+                {
+                    "bolt11": invoice["bolt11"],
+                    "amount_msat": 10_000_000,
+                    "dev_use_shadow": False,
+                    "retry_for": 20,
+                },
+            )
+        except RpcError:
+            pass
+
+    threading.Thread(target=pay_any).start()
+
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_lookup["amount_accepted_msat"] == 10_000_000
+
+    result_settle = l2.rpc.call(
+        "holdinvoicesettle", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_settle["state"] == "SETTLED"
+    wait_for(
+        lambda: l2.rpc.call(
+            "listinvoices", {"payment_hash": invoice["payment_hash"]}
+        )["invoices"][0]["status"]
+        == "paid"
+    )
+
+
+def test_overpay(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "overpay",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "max_overpay_msat": 1_000,
+            "max_overpay_percent": 50,
+        },
+    )
+
+    route = l1.rpc.getroute(l2.info["id"], 1_500_000, 1)["route"]
+    l1.rpc.sendpay(
+        route,
+        invoice["payment_hash"],
+        payment_secret=invoice["payment_secret"],
+        amount_msat=1_500_000,
+    )
+    with pytest.raises(RpcError):
+        l1.rpc.waitsendpay(invoice["payment_hash"], 30)
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_lookup["state"] == "OPEN"
+    assert l2.daemon.is_in_log("would exceed the overpay limit")
+
+    route = l1.rpc.getroute(l2.info["id"], 1_000_500, 1)["route"]
+    l1.rpc.sendpay(
+        route,
+        invoice["payment_hash"],
+        payment_secret=invoice["payment_secret"],
+        amount_msat=1_000_500,
+        groupid=2,
+    )
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+    result_settle = l2.rpc.call(
+        "holdinvoicesettle", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_settle["state"] == "SETTLED"
+    l1.rpc.waitsendpay(invoice["payment_hash"], 30)
+
+
+def test_hold_offer(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    result = l2.rpc.call("holdoffer", {"offer_id": "00" * 32})
+    assert result["message"] == f"offer_id '{'00' * 32}' not found"
+
+    offer = l2.rpc.call(
+        "offer", {"amount": "10000msat", "description": "hold offer"}
+    )
+    result = l2.rpc.call("holdoffer", {"offer_id": offer["offer_id"]})
+    assert result["offer_id"] == offer["offer_id"]
+    assert result["bolt12"] == offer["bolt12"]
+
+    invoice = l1.rpc.call(
+        "fetchinvoice", {"offer": offer["bolt12"], "payer_note": "for the hold"}
+    )["invoice"]
+    payment_hash = l1.rpc.call("decode", {"string": invoice})["invoice_payment_hash"]
+
+    threading.Thread(target=pay_with_thread, args=(l1, invoice)).start()
+
+    wait_for(
+        lambda: l2.rpc.call("holdinvoicelookup", {"payment_hash": payment_hash})[
+            "state"
+        ]
+        == "ACCEPTED"
+    )
+    result_lookup = l2.rpc.call("holdinvoicelookup", {"payment_hash": payment_hash})
+    assert result_lookup["offer_id"] == offer["offer_id"]
+    assert result_lookup["payer_note"] == "for the hold"
+
+    result_list = l2.rpc.call("holdinvoicelist", {"state": "ACCEPTED"})
+    entry = only_one(result_list["holdinvoices"])
+    assert entry["payment_hash"] == payment_hash
+    assert entry["offer_id"] == offer["offer_id"]
+
+    result_settle = l2.rpc.call("holdinvoicesettle", {"payment_hash": payment_hash})
+    assert result_settle["state"] == "SETTLED"
+    wait_for(
+        lambda: l2.rpc.call("listinvoices", {"payment_hash": payment_hash})[
+            "invoices"
+        ][0]["status"]
+        == "paid"
+    )
+
+
+def test_safety_margins(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    result = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "margin too wide",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "cancel_before_htlc_expiry": 144,
+        },
+    )
+    assert result["message"] == "cltv: needs to be greater than '144' requested: '144'"
+
+    result = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "margin too wide",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "expiry": 3_600,
+            "cancel_before_invoice_expiry": 3_600,
+        },
+    )
+    assert (
+        result["message"] == "expiry: needs to be greater than '3600' requested: '3600'"
+    )
+
+    invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "wide margin",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "cancel_before_htlc_expiry": 140,
+        },
+    )
+
+    threading.Thread(target=pay_with_thread, args=(l1, invoice["bolt11"])).start()
+
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+
+    bitcoind.generate_block(10)
+    sync_blockheight(bitcoind, [l1, l2])
+
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "SETTLED"
+    )
+    assert l1.is_local_channel_active(cl1) is True
+
+
+def test_on_expiry_cancel(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    result = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "bad policy",
+            "label": generate_random_label(),
+            "cltv": 14,
+            "on_expiry": "refund",
+        },
+    )
+    assert (
+        result["message"] == "on_expiry: should be settle or cancel: invalid token 'refund'"
+    )
+
+    invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "escrow",
+            "label": generate_random_label(),
+            "cltv": 14,
+            "on_expiry": "cancel",
+        },
+    )
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_lookup["state"] == "OPEN"
+    assert result_lookup["on_expiry"] == "cancel"
+
+    threading.Thread(target=pay_with_thread, args=(l1, invoice["bolt11"])).start()
+
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+
+    bitcoind.generate_block(10)
+    sync_blockheight(bitcoind, [l1, l2])
+
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "CANCELED"
+    )
+    wait_for(
+        lambda: l2.rpc.call(
+            "listinvoices", {"payment_hash": invoice["payment_hash"]}
+        )["invoices"][0]["status"]
+        == "unpaid"
+    )
+    assert l1.is_local_channel_active(cl1) is True
+
+
+def test_metadata(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={"important-plugin": get_plugin, "log-level": "debug"}
+    )
+
+    result = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "metadata",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "metadata": ["not", "an", "object"],
+        },
+    )
+    assert result["message"] == "metadata: should be a JSON object"
+
+    result = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "metadata",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "metadata": {"blob": "x" * 5_000},
+        },
+    )
+    assert result["message"].startswith("metadata: too large")
+
+    metadata = {"order_id": 42, "customer": "alice"}
+    label = generate_random_label()
+    invoice = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "metadata",
+            "label": label,
+            "cltv": 144,
+            "metadata": metadata,
+        },
+    )
+
+    result_lookup = node.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_lookup["metadata"] == metadata
+
+    result_list = node.rpc.call("holdinvoicelist", {"label_prefix": label})
+    assert only_one(result_list["holdinvoices"])["metadata"] == metadata
+
+    node.rpc.call("holdinvoicecancel", {"payment_hash": invoice["payment_hash"]})
+    node.daemon.wait_for_log(r'"metadata":\{"customer":"alice","order_id":42\}')
+
+
+def test_notifications(node_factory, bitcoind, get_plugin):  # noqa: F811
+    listener = Path(__file__).parent / "plugins" / "notification_listener.py"
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "plugin": listener,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+    bitcoind.generate_block(6)
+    l1.wait_channel_active(cl1)
+
+    settle_label = generate_random_label()
+    settle_invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "notified settle",
+            "label": settle_label,
+            "cltv": 144,
+            "metadata": {"order": 1},
+        },
+    )
+    threading.Thread(
+        target=pay_with_thread, args=(l1, settle_invoice["bolt11"])
+    ).start()
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": settle_invoice["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+    l2.rpc.call("holdinvoicesettle", {"payment_hash": settle_invoice["payment_hash"]})
+
+    cancel_label = generate_random_label()
+    cancel_invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 2_000_000,
+            "description": "notified cancel",
+            "label": cancel_label,
+            "cltv": 144,
+        },
+    )
+    l2.rpc.call("holdinvoicecancel", {"payment_hash": cancel_invoice["payment_hash"]})
+
+    wait_for(lambda: len(l2.rpc.call("holdnotifications")["notifications"]) == 3)
+    notifications = l2.rpc.call("holdnotifications")["notifications"]
+    assert [n["topic"] for n in notifications] == [
+        "holdinvoice_accepted",
+        "holdinvoice_settled",
+        "holdinvoice_canceled",
+    ]
+    assert all(n["origin"] == "holdinvoice" for n in notifications)
+    accepted, settled, canceled = [n["payload"] for n in notifications]
+    for payload, state, reason in [
+        (accepted, "ACCEPTED", "htlcs_complete"),
+        (settled, "SETTLED", "settle_requested"),
+    ]:
+        assert payload["payment_hash"] == settle_invoice["payment_hash"]
+        assert payload["label"] == settle_label
+        assert payload["amount_msat"] == 1_000_000
+        assert payload["state"] == state
+        assert payload["reason"] == reason
+        assert payload["metadata"] == {"order": 1}
+    assert canceled["payment_hash"] == cancel_invoice["payment_hash"]
+    assert canceled["label"] == cancel_label
+    assert canceled["amount_msat"] == 2_000_000
+    assert canceled["state"] == "CANCELED"
+    assert canceled["reason"] == "cancel_requested"
+    assert canceled["metadata"] is None
+
+
+def test_hash_only_bolt11_roundtrip(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={"important-plugin": get_plugin, "log-level": "debug"}
+    )
+
+    # sub-satoshi, odd, whole satoshi and whole BTC amounts use every multiplier
+    max_description = "d" * 639
+    cases = [
+        (1, "sub-satoshi", 3_600, 18),
+        (1_001, "odd msat", 60, 144),
+        (10_000_000, "whole satoshi", 604_800, 9),
+        (100_000_000_000, "whole btc", 86_400, 2016),
+        (21_000, max_description, 1, 144),
+    ]
+    for amount_msat, description, expiry, cltv in cases:
+        payment_hash = hashlib.sha256(secrets.token_bytes(32)).hexdigest()
+        invoice = node.rpc.call(
+            "holdinvoice",
+            {
+                "amount_msat": amount_msat,
+                "description": description,
+                "label": generate_random_label(),
+                "expiry": expiry,
+                "cltv": cltv,
+                "payment_hash": payment_hash,
+            },
+        )
+        decoded = node.rpc.call("decode", {"string": invoice["bolt11"]})
+        assert decoded["valid"] is True
+        assert decoded["amount_msat"] == amount_msat
+        assert decoded["payment_hash"] == payment_hash
+        assert decoded["payment_secret"] == invoice["payment_secret"]
+        assert decoded["description"] == description
+        assert decoded["expiry"] == expiry
+        assert decoded["min_final_cltv_expiry"] == cltv
+        assert decoded["created_at"] + expiry == invoice["expires_at"]
+
+    result = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000,
+            "description": max_description + "d",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "payment_hash": hashlib.sha256(secrets.token_bytes(32)).hexdigest(),
+        },
+    )
+    expected_message = "Invalid input: 'bolt11 field d too long: 1024 > 1023'"
+    assert result["message"] == expected_message
+
+    for payment_hash in [None, hashlib.sha256(secrets.token_bytes(32)).hexdigest()]:
+        params = {
+            "amount_msat": 0,
+            "description": "zero",
+            "label": generate_random_label(),
+            "cltv": 144,
+        }
+        if payment_hash is not None:
+            params["payment_hash"] = payment_hash
+        result = node.rpc.call("holdinvoice", params)
+        expected_message = "amount_msat|msatoshi: should be positive msat or 'any'"
+        assert result["message"] == expected_message
+
+    label = generate_random_label()
+    node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000,
+            "description": "first",
+            "label": label,
+            "cltv": 144,
+            "payment_hash": hashlib.sha256(secrets.token_bytes(32)).hexdigest(),
+        },
+    )
+    for payment_hash in [hashlib.sha256(secrets.token_bytes(32)).hexdigest(), None]:
+        params = {
+            "amount_msat": 1_000,
+            "description": "same label",
+            "label": label,
+            "cltv": 144,
+        }
+        if payment_hash is not None:
+            params["payment_hash"] = payment_hash
+        result = node.rpc.call("holdinvoice", params)
+        assert result["code"] == 900
+        assert result["message"] == f"Duplicate label '{label}'"
+
+
+def test_label_and_bolt11(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={"important-plugin": get_plugin, "log-level": "debug"}
+    )
+
+    label = generate_random_label()
+    invoice = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "by label",
+            "label": label,
+            "cltv": 144,
+        },
+    )
+
+    result_lookup = node.rpc.call("holdinvoicelookup", {"label": label})
+    assert result_lookup["state"] == "OPEN"
+    result_lookup = node.rpc.call("holdinvoicelookup", {"bolt11": invoice["bolt11"]})
+    assert result_lookup["state"] == "OPEN"
+
+    result = node.rpc.call(
+        "holdinvoicelookup",
+        {"payment_hash": invoice["payment_hash"], "label": label},
+    )
+    assert (
+        result["message"] == "Must specify only one of payment_hash, label or bolt11"
+    )
+
+    result = node.rpc.call("holdinvoicelookup", {"label": "does-not-exist"})
+    assert result["message"] == "label 'does-not-exist' not found"
+
+    plain_label = generate_random_label()
+    node.rpc.invoice(1_000, plain_label, "not a holdinvoice")
+    result = node.rpc.call("holdinvoicecancel", {"label": plain_label})
+    assert result["message"] == f"label '{plain_label}' is not a holdinvoice"
+
+    result_cancel = node.rpc.call("holdinvoicecancel", {"label": label})
+    assert result_cancel["state"] == "CANCELED"
+
+    preimage = secrets.token_hex(32)
+    hash_only_label = generate_random_label()
+    hash_only = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "hash-only by bolt11",
+            "label": hash_only_label,
+            "cltv": 144,
+            "payment_hash": hashlib.sha256(bytes.fromhex(preimage)).hexdigest(),
+        },
+    )
+    result_lookup = node.rpc.call("holdinvoicelookup", {"label": hash_only_label})
+    assert result_lookup["state"] == "OPEN"
+
+    result_settle = node.rpc.call(
+        "holdinvoicesettle", {"bolt11": hash_only["bolt11"], "preimage": preimage}
+    )
+    assert result_settle["message"] == "Holdinvoice is in wrong state: 'OPEN'"
+
+
+def test_lookup_htlcs(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "lookup htlcs",
+            "label": generate_random_label(),
+            "cltv": 144,
+        },
+    )
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_lookup["htlcs"] == []
+
+    route = l1.rpc.getroute(l2.info["id"], 1_000_000, 1)["route"]
+    l1.rpc.sendpay(
+        route,
+        invoice["payment_hash"],
+        payment_secret=invoice["payment_secret"],
+        amount_msat=1_000_000,
+    )
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    htlc = only_one(result_lookup["htlcs"])
+    assert htlc["short_channel_id"] == cl1
+    assert htlc["amount_msat"] == 1_000_000
+    assert htlc["cltv_expiry"] == result_lookup["htlc_expiry"]
+    blockheight = l2.rpc.getinfo()["blockheight"]
+    assert htlc["blocks_until_expiry"] == htlc["cltv_expiry"] - blockheight
+    assert htlc["arrived_at"] <= time.time()
+    assert htlc["peer_id"] == l1.info["id"]
+
+    l2.rpc.call("holdinvoicecancel", {"payment_hash": invoice["payment_hash"]})
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_lookup["htlcs"] == []
+
+
+def test_history(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={"important-plugin": get_plugin, "log-level": "debug"}
+    )
+
+    label = generate_random_label()
+    invoice = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "history",
+            "label": label,
+            "cltv": 144,
+        },
+    )
+    blockheight = node.rpc.getinfo()["blockheight"]
+
+    result_history = node.rpc.call(
+        "holdinvoicehistory", {"payment_hash": invoice["payment_hash"]}
+    )
+    created = only_one(result_history["history"])
+    assert "from" not in created
+    assert created["to"] == "OPEN"
+    assert created["reason"] == "created"
+    assert created["initiator"] == "rpc"
+    assert created["blockheight"] == blockheight
+    assert created["amount_held_msat"] == 0
+
+    node.rpc.call("holdinvoicecancel", {"payment_hash": invoice["payment_hash"]})
+
+    result_history = node.rpc.call("holdinvoicehistory", {"label": label})
+    assert len(result_history["history"]) == 2
+    canceled = result_history["history"][1]
+    assert canceled["from"] == "OPEN"
+    assert canceled["to"] == "CANCELED"
+    assert canceled["reason"] == "cancel_requested"
+    assert canceled["initiator"] == "rpc"
+    assert canceled["timestamp"] >= created["timestamp"]
+
+    result_lookup = node.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_lookup["last_transition"] == canceled
+
+
+def test_restart_keeps_accepted(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "restart",
+            "label": generate_random_label(),
+            "cltv": 144,
+        },
+    )
+    route = l1.rpc.getroute(l2.info["id"], 1_000_000, 1)["route"]
+    l1.rpc.sendpay(
+        route,
+        invoice["payment_hash"],
+        payment_secret=invoice["payment_secret"],
+        amount_msat=1_000_000,
+    )
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+
+    l2.restart()
+    l2.daemon.wait_for_log(r"restored 1 held htlcs in \d+ms, 0 did not come back")
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_lookup["state"] == "ACCEPTED"
+    assert only_one(result_lookup["htlcs"])["short_channel_id"] == cl1
+    assert all(
+        t["initiator"] != "restart"
+        for t in l2.rpc.call(
+            "holdinvoicehistory", {"payment_hash": invoice["payment_hash"]}
+        )["history"]
+    )
+
+    l2.rpc.call("holdinvoicesettle", {"payment_hash": invoice["payment_hash"]})
+    wait_for(
+        lambda: only_one(
+            l1.rpc.listsendpays(payment_hash=invoice["payment_hash"])["payments"]
+        )["status"]
+        == "complete"
+    )
+
+
+def test_restart_reopens_missing_htlcs(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={"important-plugin": get_plugin, "log-level": "debug"}
+    )
+
+    invoice = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "missing htlcs",
+            "label": generate_random_label(),
+            "cltv": 144,
+        },
+    )
+    # pretend an htlc was held that cln no longer has
+    node.rpc.datastore(
+        key=["holdinvoice", invoice["payment_hash"], "state"],
+        string="ACCEPTED",
+        mode="must-replace",
+    )
+    node.rpc.datastore(
+        key=["holdinvoice", invoice["payment_hash"], "htlcs"],
+        string=json.dumps(
+            {
+                "htlcs": [
+                    {
+                        "short_channel_id": "1x1x1",
+                        "htlc_id": 0,
+                        "amount_msat": 1_000_000,
+                        "cltv_expiry": 1000,
+                        "arrived_at": 0,
+                    }
+                ]
+            }
+        ),
+    )
+
+    node.restart()
+    node.daemon.wait_for_log(
+        r"restored 0 held htlcs in \d+ms, 1 did not come back, "
+        r"1 holdinvoices back to OPEN"
+    )
+
+    result_lookup = node.rpc.call(
+        "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_lookup["state"] == "OPEN"
+    reopened = result_lookup["last_transition"]
+    assert reopened["from"] == "ACCEPTED"
+    assert reopened["to"] == "OPEN"
+    assert reopened["reason"] == "htlcs_missing"
+    assert reopened["initiator"] == "restart"
+    assert reopened["amount_held_msat"] == 0
+
+    result_settle = node.rpc.call(
+        "holdinvoicesettle", {"payment_hash": invoice["payment_hash"]}
+    )
+    assert result_settle["message"] == "Holdinvoice is in wrong state: 'OPEN'"
+
+
+def test_metrics(node_factory, get_plugin):  # noqa: F811
+    port = find_unused_port()
+    node = node_factory.get_node(
+        options={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+            "metrics-hold-port": port,
+        }
+    )
+
+    def scrape():
+        with urllib.request.urlopen(f"http://127.0.0.1:{port}/metrics") as response:
+            return response.read().decode()
+
+    invoice = node.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "metrics",
+            "label": generate_random_label(),
+            "cltv": 144,
+        },
+    )
+    wait_for(lambda: 'holdinvoice_invoices{state="OPEN"} 1' in scrape())
+
+    node.rpc.call("holdinvoicecancel", {"payment_hash": invoice["payment_hash"]})
+    metrics = scrape()
+    assert 'holdinvoice_invoices{state="OPEN"} 0' in metrics
+    assert 'holdinvoice_invoices{state="CANCELED"} 1' in metrics
+    assert "holdinvoice_held_msat 0" in metrics
+    assert "holdinvoice_held_htlcs 0" in metrics
+    assert "holdinvoice_auto_settled_total 0" in metrics
+    assert "# TYPE holdinvoice_htlc_hook_seconds histogram" in metrics
+
+
+def test_settle_resolves_immediately(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+
+    bitcoind.generate_block(6)
+
+    l1.wait_channel_active(cl1)
+
+    for action in ["holdinvoicesettle", "holdinvoicecancel"]:
+        invoice = l2.rpc.call(
+            "holdinvoice",
+            {
+                "amount_msat": 1_000_000,
+                "description": "wakeup",
+                "label": generate_random_label(),
+                "cltv": 144,
+            },
+        )
+        route = l1.rpc.getroute(l2.info["id"], 1_000_000, 1)["route"]
+        l1.rpc.sendpay(
+            route,
+            invoice["payment_hash"],
+            payment_secret=invoice["payment_secret"],
+            amount_msat=1_000_000,
+        )
+        wait_for(
+            lambda: l2.rpc.call(
+                "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+            )["state"]
+            == "ACCEPTED"
+        )
+
+        # the hold loop is woken up instead of polling every few seconds
+        start = time.time()
+        l2.rpc.call(action, {"payment_hash": invoice["payment_hash"]})
+        wait_for(
+            lambda: only_one(
+                l1.rpc.listsendpays(payment_hash=invoice["payment_hash"])["payments"]
+            )["status"]
+            != "pending"
+        )
+        assert time.time() - start < 1.5
+
+
+def test_rpc_pool(node_factory, get_plugin):  # noqa: F811
+    port = find_unused_port()
+    node = node_factory.get_node(
+        options={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+            "metrics-hold-port": port,
+            "holdinvoice-rpc-pool-size": 1,
+        }
+    )
+
+    results = []
+
+    def create():
+        results.append(
+            node.rpc.call(
+                "holdinvoice",
+                {
+                    "amount_msat": 1_000_000,
+                    "description": "pool",
+                    "label": generate_random_label(),
+                    "cltv": 144,
+                },
+            )
+        )
+
+    threads = [threading.Thread(target=create) for _ in range(10)]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join()
+    assert len(results) == 10
+    for result in results:
+        lookup = node.rpc.call(
+            "holdinvoicelookup", {"payment_hash": result["payment_hash"]}
+        )
+        assert lookup["state"] == "OPEN"
+
+    with urllib.request.urlopen(f"http://127.0.0.1:{port}/metrics") as response:
+        metrics = response.read().decode()
+    assert "holdinvoice_rpc_pool_size 1" in metrics
+    assert "holdinvoice_rpc_pool_open 1" in metrics
+    assert "# TYPE holdinvoice_rpc_pool_wait_seconds histogram" in metrics
+
+
+def test_autoclean(node_factory, get_plugin):  # noqa: F811
+    node = node_factory.get_node(
+        options={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+            "holdinvoice-autoclean-cycle": 0,
+            "holdinvoice-autoclean-canceled-age": 0,
+            "holdinvoice-autoclean-delinvoice": True,
+        }
+    )
+
+    invoices = [
+        node.rpc.call(
+            "holdinvoice",
+            {
+                "amount_msat": 1_000_000,
+                "description": "autoclean",
+                "label": generate_random_label(),
+                "cltv": 144,
+            },
+        )
+        for _ in range(3)
+    ]
+    open_hash = invoices[0]["payment_hash"]
+    canceled_hash = invoices[1]["payment_hash"]
+    orphaned_hash = invoices[2]["payment_hash"]
+
+    node.rpc.call("holdinvoicecancel", {"payment_hash": canceled_hash})
+    orphaned_label = node.rpc.listinvoices(payment_hash=orphaned_hash)["invoices"][0][
+        "label"
+    ]
+    node.rpc.delinvoice(orphaned_label, "unpaid")
+
+    with pytest.raises(RpcError, match="Invalid argument: 'dry_run'"):
+        node.rpc.call("holdinvoiceautoclean", {"dry_run": True})
+
+    result = node.rpc.call("holdinvoiceautoclean", {})
+    removed = {r["payment_hash"]: r for r in result["removed"]}
+    assert len(removed) == 2
+    assert removed[canceled_hash]["state"] == "CANCELED"
+    assert removed[canceled_hash]["reason"] == "retention"
+    assert removed[canceled_hash]["invoice_deleted"] is True
+    assert removed[orphaned_hash]["reason"] == "orphaned"
+    assert removed[orphaned_hash]["invoice_deleted"] is False
+
+    assert node.rpc.listinvoices(payment_hash=canceled_hash)["invoices"] == []
+    with pytest.raises(RpcError, match="not found"):
+        node.rpc.call("holdinvoicelookup", {"payment_hash": canceled_hash})
+    result_lookup = node.rpc.call("holdinvoicelookup", {"payment_hash": open_hash})
+    assert result_lookup["state"] == "OPEN"
+
+    assert node.rpc.call("holdinvoiceautoclean", {})["removed"] == []
+
+
+def test_status(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts={
+            "important-plugin": get_plugin,
+            "log-level": "debug",
+        },
+    )
+    status = l2.rpc.call("holdinvoicestatus", {})
+    assert status["ready"] is True
+    assert status["replays_counted"] is True
+    assert status["timed_out"] is False
+    assert status["expected_htlcs"] == 0
+    assert status["pending_htlcs"] == 0
+    assert status["pending_payment_hashes"] == []
+
+    with pytest.raises(RpcError, match="too many parameters: got 1, expected 0"):
+        l2.rpc.call("holdinvoicestatus", [True])
+
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+    bitcoind.generate_block(6)
+    l1.wait_channel_active(cl1)
+
+    invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "status",
+            "label": generate_random_label(),
+            "cltv": 144,
+        },
+    )
+    threading.Thread(target=pay_with_thread, args=(l1, invoice["bolt11"])).start()
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+
+    l2.restart()
+    l2.daemon.wait_for_log(r"waiting for 1 replayed htlcs of holdinvoices")
+    l2.daemon.wait_for_log(r"all replayed htlcs arrived, plugin is ready")
+    status = l2.rpc.call("holdinvoicestatus", {})
+    assert status["ready"] is True
+    assert status["timed_out"] is False
+    assert status["expected_htlcs"] == 1
+    assert status["pending_htlcs"] == 0
+
+    l2.rpc.call("holdinvoicesettle", {"payment_hash": invoice["payment_hash"]})
+
+
+def test_channel_health(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {"may_reconnect": True},
+            {
+                "important-plugin": get_plugin,
+                "log-level": "debug",
+                "may_reconnect": True,
+                "holdinvoice-channel-health-policy": "cancel",
+            },
+        ],
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+    bitcoind.generate_block(6)
+    l1.wait_channel_active(cl1)
+
+    accepted = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "health accepted",
+            "label": generate_random_label(),
+            "cltv": 144,
+        },
+    )
+    threading.Thread(target=pay_with_thread, args=(l1, accepted["bolt11"])).start()
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": accepted["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+
+    # only half of the amount arrives, so this one stays OPEN
+    partial = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 2_000_000,
+            "description": "health partial",
+            "label": generate_random_label(),
+            "cltv": 144,
+        },
+    )
+    route = l1.rpc.getroute(l2.info["id"], 1_000_000, 1)["route"]
+    l1.rpc.sendpay(
+        route,
+        partial["payment_hash"],
+        payment_secret=partial["payment_secret"],
+        amount_msat=2_000_000,
+        partid=1,
+    )
+    wait_for(
+        lambda: len(
+            l2.rpc.call("holdinvoicelookup", {"payment_hash": partial["payment_hash"]})[
+                "htlcs"
+            ]
+        )
+        == 1
+    )
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": accepted["payment_hash"]}
+    )
+    assert result_lookup["channel_unhealthy"] is False
+    assert "channel_issue" not in only_one(result_lookup["htlcs"])
+
+    l2.rpc.disconnect(l1.info["id"], force=True)
+    l2.daemon.wait_for_log(r"Holding htlc on unhealthy channel: peer_disconnected")
+
+    # ACCEPTED holdinvoices are only flagged
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": accepted["payment_hash"]}
+    )
+    assert result_lookup["state"] == "ACCEPTED"
+    assert result_lookup["channel_unhealthy"] is True
+    assert only_one(result_lookup["htlcs"])["channel_issue"] == "peer_disconnected"
+
+    # OPEN ones are canceled, the htlc is failed once the peer is back
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicehistory", {"payment_hash": partial["payment_hash"]}
+        )["history"][-1]["to"]
+        == "CANCELED"
+    )
+    last = l2.rpc.call("holdinvoicehistory", {"payment_hash": partial["payment_hash"]})[
+        "history"
+    ][-1]
+    assert last["reason"] == "channel_unhealthy"
+    assert last["initiator"] == "channel_monitor"
+
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": accepted["payment_hash"]}
+        )["channel_unhealthy"]
+        is False
+    )
+    result_lookup = l2.rpc.call(
+        "holdinvoicelookup", {"payment_hash": partial["payment_hash"]}
+    )
+    assert result_lookup["state"] == "CANCELED"
+    with pytest.raises(RpcError):
+        l1.rpc.waitsendpay(partial["payment_hash"], 60, partid=1)
+
+    result_settle = l2.rpc.call(
+        "holdinvoicesettle", {"payment_hash": accepted["payment_hash"]}
+    )
+    assert result_settle["state"] == "SETTLED"
+
+
+def test_channel_health_tighten_settle(node_factory, bitcoind, get_plugin):  # noqa: F811
+    l1, l2 = node_factory.get_nodes(
+        2,
+        opts=[
+            {"may_reconnect": True},
+            {
+                "important-plugin": get_plugin,
+                "log-level": "debug",
+                "may_reconnect": True,
+                "holdinvoice-channel-health-policy": "tighten",
+                "holdinvoice-unhealthy-cancel-before-htlc-expiry": 1_000,
+            },
+        ],
+    )
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    cl1, _ = l1.fundchannel(l2, 1_000_000)
+    bitcoind.generate_block(6)
+    l1.wait_channel_active(cl1)
+
+    invoice = l2.rpc.call(
+        "holdinvoice",
+        {
+            "amount_msat": 1_000_000,
+            "description": "tighten with on_expiry settle",
+            "label": generate_random_label(),
+            "cltv": 144,
+            "on_expiry": "settle",
+        },
+    )
+    threading.Thread(target=pay_with_thread, args=(l1, invoice["bolt11"])).start()
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicelookup", {"payment_hash": invoice["payment_hash"]}
+        )["state"]
+        == "ACCEPTED"
+    )
+
+    # the raised margin is crossed right away, but only a cancel may follow
+    l2.rpc.disconnect(l1.info["id"], force=True)
+    wait_for(
+        lambda: l2.rpc.call(
+            "holdinvoicehistory", {"payment_hash": invoice["payment_hash"]}
+        )["history"][-1]["to"]
+        == "CANCELED"
+    )
+    history = l2.rpc.call(
+        "holdinvoicehistory", {"payment_hash": invoice["payment_hash"]}
+    )["history"]
+    assert "SETTLED" not in [t["to"] for t in history]
+    assert history[-1]["initiator"] == "auto_soft_expiry"
+
+    l1.rpc.connect(l2.info["id"], "localhost", l2.port)
+    with pytest.raises(RpcError):
+        l1.rpc.waitsendpay(invoice["payment_hash"], 60)